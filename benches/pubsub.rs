@@ -30,7 +30,7 @@ async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
 }
 
 async fn start_subscriber(topic: &'static str) -> Result<()> {
-    let mut ctrl = connect().await?;
+    let ctrl = connect().await?;
     let stream = ctrl.open_stream().await?;
     info!("C(subscriber): stream opened");
     let cmd = CommandRequest::new_subscribe(topic.to_string());
@@ -46,7 +46,7 @@ async fn start_subscriber(topic: &'static str) -> Result<()> {
 async fn start_publisher(topic: &'static str, values: &'static [&'static str]) -> Result<()> {
     let mut rng = rand::thread_rng();
     let v = values.choose(&mut rng).unwrap();
-    let mut ctrl = connect().await.unwrap();
+    let ctrl = connect().await.unwrap();
     let mut stream = ctrl.open_stream().await.unwrap();
     info!("C(publisher): stream opened");
     let cmd = CommandRequest::new_publish(topic.to_string(), vec![(*v).into()]);
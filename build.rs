@@ -18,6 +18,12 @@ fn main() {
         "Unsubscribe",
         "PSubscribe",
         "PUnsubscribe",
+        "Cancel",
+        "Upload",
+        "Download",
+        "Ping",
+        "Track",
+        "Auth",
     ] {
         config.type_attribute(item, "#[derive(Eq)]");
     }
@@ -1,7 +1,8 @@
 use anyhow::Result;
 use simple_kv::{
-    ClientConfig, ClientTlsConfig, GeneralConfig, LevelConfig, LogConfig, RotationConfig,
-    ServerConfig, ServerTlsConfig, StorageConfig,
+    AclConfig, AuthConfig, ClientConfig, ClientTlsConfig, CompressionConfig, ConnectionConfig,
+    GeneralConfig, LevelConfig, LimitConfig, LogConfig, PoolConfig, RotationConfig, ServerConfig,
+    ServerMode, ServerTlsConfig, StorageConfig, YamuxConfig, MAX_FRAME,
 };
 use std::fs;
 
@@ -12,6 +13,8 @@ fn main() -> Result<()> {
 
     let general_config = GeneralConfig {
         addr: "127.0.0.1:9527".into(),
+        max_frame_size: MAX_FRAME,
+        grace_period: 30,
     };
 
     let server_config = ServerConfig {
@@ -21,6 +24,8 @@ fn main() -> Result<()> {
             cert: SERVER_CERT.into(),
             key: SERVER_KEY.into(),
             ca: None,
+            crls: Vec::new(),
+            sni: Vec::new(),
         },
         log: LogConfig {
             path: "/tmp/kv-log".into(),
@@ -29,6 +34,13 @@ fn main() -> Result<()> {
             enable_log_file: true,
             enable_jager: false,
         },
+        compression: CompressionConfig::default(),
+        connection: ConnectionConfig::default(),
+        limit: LimitConfig::default(),
+        mode: ServerMode::Yamux,
+        yamux: YamuxConfig::default(),
+        acl: AclConfig::default(),
+        auth: AuthConfig::default(),
     };
 
     fs::write(
@@ -43,6 +55,12 @@ fn main() -> Result<()> {
             identity: None,
             ca: Some(CA_CERT.into()),
         },
+        compression: CompressionConfig::default(),
+        keepalive_interval: 30,
+        yamux: YamuxConfig::default(),
+        pool: PoolConfig::default(),
+        cache_capacity: 0,
+        credentials: None,
     };
 
     fs::write(
@@ -0,0 +1,11 @@
+use anyhow::{anyhow, Result};
+use simple_kv::hash_password;
+
+/// 生成可以填入 server.conf 中 [[auth.users]] 的 password 的哈希
+fn main() -> Result<()> {
+    let password = std::env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Usage: hash_password <password>"))?;
+    println!("{}", hash_password(&password));
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use crate::{ClientConfig, KvClient, KvError, Kvpair, Subscription, Value};
+use futures::{Future, StreamExt};
+use std::{
+    io::{self, Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    runtime::{self, Runtime},
+};
+use tokio_rustls::client;
+
+/// 同步的 kv 客户端，内部使用单独的 tokio 运行时驱动 KvClient，不需要调用者提供异步运行时。
+/// 不能在异步上下文中使用
+pub struct BlockingClient<S> {
+    client: KvClient<S>,
+    runtime: Runtime,
+}
+
+/// 同步的订阅，以迭代器的方式依次返回 publish 发布的数据
+pub struct BlockingSubscription<'a> {
+    /// 订阅 id，用于退订
+    pub id: u32,
+    inner: Subscription,
+    runtime: &'a Runtime,
+}
+
+impl BlockingClient<client::TlsStream<TcpStream>> {
+    /// 通过配置创建同步的 kv 客户端
+    pub fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        Self::new(crate::connect_kv(config))
+    }
+}
+
+impl<S> BlockingClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// 在内部的运行时中执行 connect 创建 KvClient
+    pub fn new(
+        connect: impl Future<Output = Result<KvClient<S>, KvError>>,
+    ) -> Result<Self, KvError> {
+        // 使用单独的工作线程驱动连接，这样在两次调用之间订阅的数据也能被及时接收
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let client = runtime.block_on(connect)?;
+        Ok(Self { client, runtime })
+    }
+
+    /// 设置请求的默认超时时间
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = self.client.with_timeout(timeout);
+        self
+    }
+
+    /// 获取 key 对应的 value，key 不存在时返回 None
+    pub fn hget(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.runtime.block_on(self.client.hget(table, key))
+    }
+
+    /// 获取多个 key 对应的 value，不存在的 key 对应 None
+    pub fn hmget(&self, table: &str, keys: Vec<String>) -> Result<Vec<Option<Value>>, KvError> {
+        self.runtime.block_on(self.client.hmget(table, keys))
+    }
+
+    /// 获取 table 中所有的 kv pair
+    pub fn hgetall(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.runtime.block_on(self.client.hgetall(table))
+    }
+
+    /// 设置 key 对应的 value，返回之前的 value
+    pub fn hset(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        self.runtime.block_on(self.client.hset(table, key, value))
+    }
+
+    /// 设置多个 kv pair，返回之前的 value
+    pub fn hmset(&self, table: &str, pairs: Vec<Kvpair>) -> Result<Vec<Option<Value>>, KvError> {
+        self.runtime.block_on(self.client.hmset(table, pairs))
+    }
+
+    /// 删除 key，返回被删除的 value
+    pub fn hdel(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.runtime.block_on(self.client.hdel(table, key))
+    }
+
+    /// 删除多个 key，返回被删除的 value
+    pub fn hmdel(&self, table: &str, keys: Vec<String>) -> Result<Vec<Option<Value>>, KvError> {
+        self.runtime.block_on(self.client.hmdel(table, keys))
+    }
+
+    /// key 是否存在
+    pub fn exists(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.runtime.block_on(self.client.exists(table, key))
+    }
+
+    /// 多个 key 是否存在
+    pub fn hmexist(&self, table: &str, keys: Vec<String>) -> Result<Vec<bool>, KvError> {
+        self.runtime.block_on(self.client.hmexist(table, keys))
+    }
+
+    /// 发布数据到 topic
+    pub fn publish(&self, topic: &str, values: Vec<Value>) -> Result<(), KvError> {
+        self.runtime.block_on(self.client.publish(topic, values))
+    }
+
+    /// 订阅 topic
+    pub fn subscribe(&self, topic: &str) -> Result<BlockingSubscription<'_>, KvError> {
+        let inner = self.runtime.block_on(self.client.subscribe(topic))?;
+        Ok(self.subscription(inner))
+    }
+
+    /// 订阅所有匹配 pattern 的 topic
+    pub fn psubscribe(&self, pattern: &str) -> Result<BlockingSubscription<'_>, KvError> {
+        let inner = self.runtime.block_on(self.client.psubscribe(pattern))?;
+        Ok(self.subscription(inner))
+    }
+
+    /// 退订 topic
+    pub fn unsubscribe(&self, topic: &str, id: u32) -> Result<(), KvError> {
+        self.runtime.block_on(self.client.unsubscribe(topic, id))
+    }
+
+    /// 退订 pattern
+    pub fn punsubscribe(&self, pattern: &str, id: u32) -> Result<(), KvError> {
+        self.runtime.block_on(self.client.punsubscribe(pattern, id))
+    }
+
+    /// 检查服务端是否可用
+    pub fn ping(&self) -> Result<(), KvError> {
+        self.runtime.block_on(self.client.ping())
+    }
+
+    /// 分块上传 reader 中的数据，返回上传的字节数
+    pub fn upload(
+        &self,
+        table: &str,
+        key: &str,
+        reader: impl Read + Unpin,
+    ) -> Result<u64, KvError> {
+        let reader = SyncIo(reader);
+        self.runtime
+            .block_on(self.client.upload(table, key, reader))
+    }
+
+    /// 分块下载 value 到 writer 中，返回下载的字节数
+    pub fn download(
+        &self,
+        table: &str,
+        key: &str,
+        writer: impl Write + Unpin,
+    ) -> Result<u64, KvError> {
+        let writer = SyncIo(writer);
+        self.runtime
+            .block_on(self.client.download(table, key, writer))
+    }
+
+    fn subscription(&self, inner: Subscription) -> BlockingSubscription<'_> {
+        BlockingSubscription {
+            id: inner.id,
+            inner,
+            runtime: &self.runtime,
+        }
+    }
+}
+
+impl Iterator for BlockingSubscription<'_> {
+    type Item = Result<Vec<Value>, KvError>;
+
+    /// 阻塞直到收到下一次发布的数据，订阅结束时返回 None
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.inner.next())
+    }
+}
+
+/// 把同步的 Read/Write 包装成 AsyncRead/AsyncWrite。
+/// 读写会阻塞当前线程，只能在 block_on 中使用
+struct SyncIo<T>(T);
+
+impl<T: Read + Unpin> AsyncRead for SyncIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = self.get_mut().0.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Write + Unpin> AsyncWrite for SyncIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().0.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner, YamuxCtrl};
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    fn client() -> BlockingClient<DuplexStream> {
+        BlockingClient::new(async {
+            let (client, server) = duplex(64 * 1024);
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            YamuxCtrl::new_server(server, None, move |stream| {
+                let service = service.clone();
+                async move {
+                    let _ = ProstServerStream::new(stream.compat(), service)
+                        .process()
+                        .await;
+                    Ok(())
+                }
+            });
+            KvClient::new(YamuxCtrl::new_client(client, None)).await
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn blocking_client_should_work() -> Result<(), KvError> {
+        let client = client();
+
+        assert_eq!(client.hset("t1", "k1", "v1")?, None);
+        assert_eq!(client.hget("t1", "k1")?, Some("v1".into()));
+        assert!(client.exists("t1", "k1")?);
+        assert_eq!(client.hgetall("t1")?.len(), 1);
+        assert_eq!(client.hdel("t1", "k1")?, Some("v1".into()));
+        assert_eq!(client.hget("t1", "k1")?, None);
+        client.ping()
+    }
+
+    #[test]
+    fn blocking_subscription_should_iterate_published_data() -> Result<(), KvError> {
+        let client = client();
+        let mut sub = client.subscribe("lobby")?;
+
+        client.publish("lobby", vec!["hello".into()])?;
+        client.publish("lobby", vec!["world".into()])?;
+        assert_eq!(sub.next().unwrap()?, vec!["hello".into()]);
+        assert_eq!(sub.next().unwrap()?, vec!["world".into()]);
+
+        client.unsubscribe("lobby", sub.id)
+    }
+
+    #[test]
+    fn blocking_upload_and_download_should_work() -> Result<(), KvError> {
+        let client = client();
+        let data = vec![42u8; 1024 * 1024 + 1];
+
+        assert_eq!(client.upload("t1", "file", &data[..])?, data.len() as u64);
+        let mut buf = Vec::new();
+        assert_eq!(client.download("t1", "file", &mut buf)?, data.len() as u64);
+        assert_eq!(buf, data);
+        Ok(())
+    }
+}
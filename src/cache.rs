@@ -0,0 +1,133 @@
+use crate::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+/// 客户端缓存，保存 hget 的结果，依靠服务端推送的失效通知保持和服务端一致。
+/// 容量满了之后按照写入的先后顺序淘汰
+pub(crate) struct Cache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    /// Track 订阅 id，0 表示失效通知已经中断，缓存不再可用
+    tracking: u32,
+    /// 缓存的 value 以及写入时的序号，None 表示 key 不存在
+    entries: HashMap<(String, String), (Option<Value>, u64)>,
+    /// 按照写入顺序排列的 key，序号和 entries 中不一致的记录已经被删除或者覆盖
+    order: VecDeque<((String, String), u64)>,
+    /// 写入的序号
+    seq: u64,
+    /// 失效的次数，读请求发出之后发生过失效时，它的结果可能已经过时，不能放入缓存
+    generation: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, tracking: u32) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                tracking,
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                seq: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// 查找缓存，命中时返回 Some
+    pub fn get(&self, table: &str, key: &str) -> Option<Option<Value>> {
+        let inner = self.inner.lock().unwrap();
+        let entry = (table.to_owned(), key.to_owned());
+        inner.entries.get(&entry).map(|(value, _)| value.clone())
+    }
+
+    /// 读请求发出之前调用，返回 Track 订阅 id 和当前的失效次数，缓存不可用时返回 None
+    pub fn begin(&self) -> Option<(u32, u64)> {
+        let inner = self.inner.lock().unwrap();
+        match inner.tracking {
+            0 => None,
+            id => Some((id, inner.generation)),
+        }
+    }
+
+    /// 保存读请求的结果，generation 是 begin 返回的失效次数
+    pub fn insert(&self, generation: u64, table: &str, key: &str, value: Option<Value>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.tracking == 0 || inner.generation != generation || self.capacity == 0 {
+            return;
+        }
+
+        while inner.entries.len() >= self.capacity {
+            let (entry, seq) = match inner.order.pop_front() {
+                Some(v) => v,
+                None => break,
+            };
+            if matches!(inner.entries.get(&entry), Some((_, s)) if *s == seq) {
+                inner.entries.remove(&entry);
+            }
+        }
+
+        inner.seq += 1;
+        let (entry, seq) = ((table.to_owned(), key.to_owned()), inner.seq);
+        inner.entries.insert(entry.clone(), (value, seq));
+        inner.order.push_back((entry, seq));
+        // 失效和覆盖会在 order 中留下过时的记录，太多时清理掉
+        if inner.order.len() > self.capacity * 2 {
+            let CacheInner { entries, order, .. } = &mut *inner;
+            order.retain(|(entry, seq)| matches!(entries.get(entry), Some((_, s)) if s == seq));
+        }
+    }
+
+    /// 删除被修改的 key
+    pub fn invalidate(&self, table: &str, keys: &[&str]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        for key in keys {
+            inner.entries.remove(&(table.to_owned(), (*key).to_owned()));
+        }
+    }
+
+    /// 失效通知中断，清空并停用缓存
+    pub fn disable(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tracking = 0;
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_should_evict_oldest_entries() {
+        let cache = Cache::new(2, 1);
+        let (_, generation) = cache.begin().unwrap();
+        cache.insert(generation, "t1", "k1", Some("v1".into()));
+        cache.insert(generation, "t1", "k2", None);
+        cache.insert(generation, "t1", "k3", Some("v3".into()));
+
+        assert_eq!(cache.get("t1", "k1"), None);
+        assert_eq!(cache.get("t1", "k2"), Some(None));
+        assert_eq!(cache.get("t1", "k3"), Some(Some("v3".into())));
+    }
+
+    #[test]
+    fn stale_read_should_not_be_cached() {
+        let cache = Cache::new(16, 1);
+        let (_, generation) = cache.begin().unwrap();
+        // 读请求返回之前 key 被修改了
+        cache.invalidate("t1", &["k1"]);
+        cache.insert(generation, "t1", "k1", Some("v1".into()));
+        assert_eq!(cache.get("t1", "k1"), None);
+
+        cache.disable();
+        assert!(cache.begin().is_none());
+    }
+}
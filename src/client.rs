@@ -15,7 +15,7 @@ async fn main() -> Result<()> {
     let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
 
     // 打开一个 yamux ctrl
-    let mut ctrl = start_client_with_config(&config).await?;
+    let ctrl = start_client_with_config(&config).await?;
 
     let channel = "lobby";
     start_publishing(ctrl.open_stream().await?, channel)?;
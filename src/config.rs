@@ -1,24 +1,141 @@
-use crate::KvError;
-use serde::{Deserialize, Serialize};
+use crate::{
+    KvError, COMPRESSION_LIMIT, DEFAULT_LEVEL, GZIP, LZ4, MAX_FRAME, MAX_FRAME_LIMIT, NONE, ZSTD,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::fs;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ServerConfig {
+    // toml 要求普通的值写在表之前
+    #[serde(default)]
+    pub mode: ServerMode,
     pub general: GeneralConfig,
     pub storage: StorageConfig,
     pub tls: ServerTlsConfig,
     pub log: LogConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
+    #[serde(default)]
+    pub limit: LimitConfig,
+    #[serde(default)]
+    pub yamux: YamuxConfig,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ClientConfig {
+    // toml 要求普通的值写在表之前
+    /// 发送 Ping 的间隔（秒），需要小于服务端的 idle_timeout，0 表示不发送
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    /// 客户端缓存最多保存的 key 的数量，0 表示不使用缓存
+    #[serde(default)]
+    pub cache_capacity: usize,
     pub general: GeneralConfig,
     pub tls: ClientTlsConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub yamux: YamuxConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// 服务端开启认证时使用的用户名和密码
+    #[serde(default)]
+    pub credentials: Option<CredentialConfig>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct GeneralConfig {
     pub addr: String,
+    /// 能够接收的最大 frame（字节），超过该值的 frame 会导致所在的 stream 被关闭。
+    /// frame 的长度只有 30 bit，因此不能超过 MAX_FRAME_LIMIT
+    #[serde(
+        default = "default_max_frame_size",
+        deserialize_with = "deserialize_max_frame_size"
+    )]
+    pub max_frame_size: usize,
+    /// 关闭服务器时等待正在执行的请求结束的最长时间（秒）
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+}
+
+fn default_max_frame_size() -> usize {
+    MAX_FRAME
+}
+
+fn deserialize_max_frame_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<usize, D::Error> {
+    let size = usize::deserialize(deserializer)?;
+    if size > MAX_FRAME_LIMIT {
+        return Err(de::Error::custom(format!(
+            "max_frame_size {size} exceeds {MAX_FRAME_LIMIT}"
+        )));
+    }
+    Ok(size)
+}
+
+fn default_grace_period() -> u64 {
+    30
+}
+
+fn default_keepalive_interval() -> u64 {
+    30
+}
+
+/// 服务端对连接的限制
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// 最大连接数，超过后新的连接在握手之后收到 Overloaded 错误并被关闭
+    pub max_connections: usize,
+    /// 每个连接上最多同时打开的 yamux stream 数量
+    pub max_streams: usize,
+    /// 连接上超过该时间（秒）没有收到任何数据时关闭连接，0 表示不限制
+    pub idle_timeout: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_streams: 256,
+            idle_timeout: 90,
+        }
+    }
+}
+
+/// 客户端连接池的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// 连接池中的连接数
+    pub size: usize,
+    /// 重连失败后第一次等待的时间（毫秒），之后每次翻倍
+    pub min_backoff: u64,
+    /// 重连失败后最长的等待时间（毫秒）
+    pub max_backoff: u64,
+    /// 每次取用连接时最多尝试重连的次数，全部失败后返回错误
+    pub reconnect_attempts: u32,
+    /// 幂等的读请求因为连接断开而失败时，最多重试的次数
+    pub max_retries: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            min_backoff: 100,
+            max_backoff: 10_000,
+            reconnect_attempts: 8,
+            max_retries: 3,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -58,6 +175,25 @@ pub struct ServerTlsConfig {
     pub cert: String,
     pub key: String,
     pub ca: Option<String>,
+    /// PEM 格式的证书吊销列表，只在设置了 ca 时生效，必须由 ca 签发
+    #[serde(default)]
+    pub crls: Vec<String>,
+    /// 根据 SNI 选择的证书，没有匹配的 SNI 时使用 cert 和 key
+    #[serde(default)]
+    pub sni: Vec<SniConfig>,
+}
+
+/// 某个 SNI 使用的证书，以及它对应的 namespace
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SniConfig {
+    /// 客户端在 SNI 中发送的域名，不区分大小写
+    pub name: String,
+    pub cert: String,
+    pub key: String,
+    /// 通过这个域名访问的连接只能看到 namespace 下的 table 和 topic，不同的 namespace 之间互相隔离。
+    /// namespace 不能为空，也不能包含 ':'；没有 namespace 的连接不能访问名字中带有 ':' 的 table
+    #[serde(default)]
+    pub namespace: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -67,6 +203,203 @@ pub struct ClientTlsConfig {
     pub ca: Option<String>,
 }
 
+/// 服务端接受的连接类型
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ServerMode {
+    /// 只接受 yamux 连接
+    #[default]
+    Yamux,
+    /// 只接受直接收发 frame 的单 stream 连接
+    Plain,
+    /// 根据连接上的第一个 frame 自动判断
+    Auto,
+}
+
+/// yamux 的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct YamuxConfig {
+    /// 每个 stream 的接收窗口（字节），小于 256 KiB 时按 256 KiB 处理
+    pub receive_window: u32,
+    /// 每个 stream 缓存的尚未读取的数据上限（字节）
+    pub max_buffer_size: usize,
+    /// 发送时每个 data frame 的最大长度（字节）
+    pub split_send_size: usize,
+}
+
+impl Default for YamuxConfig {
+    fn default() -> Self {
+        Self {
+            receive_window: YAMUX_MIN_WINDOW,
+            max_buffer_size: 1024 * 1024,
+            split_send_size: 16 * 1024,
+        }
+    }
+}
+
+/// yamux 允许的最小接收窗口
+const YAMUX_MIN_WINDOW: u32 = 256 * 1024;
+
+impl From<&YamuxConfig> for yamux::Config {
+    fn from(config: &YamuxConfig) -> Self {
+        let mut yamux = yamux::Config::default();
+        yamux
+            .set_receive_window(config.receive_window.max(YAMUX_MIN_WINDOW))
+            .set_max_buffer_size(config.max_buffer_size)
+            .set_split_send_size(config.split_send_size);
+        yamux
+    }
+}
+
+/// 限流和过载保护的配置，限流按照客户端（证书的 CN 或者 IP 地址）和命令分类分别计算
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// 等待第一个响应的请求数超过该值时拒绝新的请求，0 表示不限制
+    pub max_inflight: usize,
+    /// 请求的平均延迟（毫秒）超过该值时拒绝新的请求，0 表示不限制
+    pub max_latency: u64,
+    /// hget、hgetall、download 等读命令
+    pub read: Option<RateConfig>,
+    /// hset、hmset、hdel、upload 等写命令
+    pub write: Option<RateConfig>,
+    /// publish、subscribe 等命令
+    pub pubsub: Option<RateConfig>,
+    /// auth 命令，没有配置时每个客户端每秒一次，允许连续尝试 5 次
+    pub auth: Option<RateConfig>,
+}
+
+/// 访问控制的配置，没有任何规则时不做限制。
+/// 请求只要被任意一条与客户端身份匹配的规则允许即可执行，否则返回 403。
+/// ping、cancel 以及空的请求是控制命令，不受规则限制；auth 由用户认证负责，只需要匹配命令名
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AclConfig {
+    pub rules: Vec<AclRule>,
+}
+
+/// 一条访问控制规则，各个字段都使用 glob 模式
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AclRule {
+    /// 匹配客户端证书的 CN、subject 或者任意一个 SAN，"*" 同时匹配没有证书的客户端
+    pub identity: String,
+    /// 允许的命令，比如 hget、publish，"*" 表示除了自定义命令之外的所有命令。
+    /// 自定义命令需要显式地以 custom:<name> 的形式允许，比如 custom:reserve 或者 custom:*。
+    /// track 只会收到带有 tracking 的读请求读取过的 key 的失效通知，这些读请求需要同一条规则同时允许 track
+    pub commands: Vec<String>,
+    /// 允许访问的 table，upload、download 以及带有 tracking 的读请求同样按照 table 检查，
+    /// 自定义命令读写的 table 需要允许该命令的规则同时允许
+    pub tables: Vec<String>,
+    /// 允许访问的 topic，psubscribe 的 pattern 本身需要匹配这里的模式，
+    /// 自定义命令发布消息的 topic 同 tables
+    pub topics: Vec<String>,
+}
+
+/// 用户认证的配置，没有任何用户时不需要认证
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+}
+
+/// 一个用户以及它的权限，各个权限字段都使用 glob 模式
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct UserConfig {
+    pub name: String,
+    /// 加盐的密码哈希，使用 hash_password 生成
+    pub password: String,
+    /// 允许读取的 table
+    pub read: Vec<String>,
+    /// 允许修改的 table
+    pub write: Vec<String>,
+    /// 允许 publish 的 topic
+    pub publish: Vec<String>,
+    /// 允许订阅的 topic，psubscribe 的 pattern 本身需要匹配这里的模式
+    pub subscribe: Vec<String>,
+    /// 管理员可以执行所有的命令
+    pub admin: bool,
+}
+
+/// 客户端认证使用的用户名和密码
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CredentialConfig {
+    pub username: String,
+    pub password: String,
+}
+
+/// 令牌桶的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RateConfig {
+    /// 每秒补充的令牌数
+    pub rate: u32,
+    /// 桶的容量，即允许的突发请求数
+    pub burst: u32,
+}
+
+/// 压缩相关的配置，连接建立时客户端和服务端会据此协商出双方共同使用的算法
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// 压缩算法
+    pub codec: CompressionCodec,
+    /// 压缩级别，0 表示使用算法自身的缺省级别
+    pub level: u32,
+    /// payload 超过该值时才进行压缩
+    pub threshold: usize,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CompressionCodec {
+    None,
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: CompressionCodec::Gzip,
+            level: DEFAULT_LEVEL,
+            threshold: COMPRESSION_LIMIT,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// 不进行任何压缩的配置
+    pub fn none() -> Self {
+        Self {
+            codec: CompressionCodec::None,
+            ..Default::default()
+        }
+    }
+}
+
+impl CompressionCodec {
+    /// 在 frame header 中表示该算法的 bit
+    pub fn bits(&self) -> usize {
+        match self {
+            CompressionCodec::None => NONE,
+            CompressionCodec::Gzip => GZIP,
+            CompressionCodec::Lz4 => LZ4,
+            CompressionCodec::Zstd => ZSTD,
+        }
+    }
+
+    /// 从 frame header 中的 bit 得到对应的算法
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            NONE => Some(CompressionCodec::None),
+            GZIP => Some(CompressionCodec::Gzip),
+            LZ4 => Some(CompressionCodec::Lz4),
+            ZSTD => Some(CompressionCodec::Zstd),
+            _ => None,
+        }
+    }
+}
+
 impl ServerConfig {
     pub fn load(path: &str) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
@@ -100,4 +433,103 @@ mod tests {
             toml::from_str(include_str!("../fixtures/client.conf"));
         assert!(result.is_ok())
     }
+
+    #[test]
+    fn config_without_compression_should_use_default() {
+        let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        assert_eq!(config.compression, CompressionConfig::default());
+    }
+
+    #[test]
+    fn config_without_max_frame_size_should_use_default() {
+        let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        assert_eq!(config.general.max_frame_size, MAX_FRAME);
+        assert_eq!(config.general.grace_period, 30);
+
+        let config: GeneralConfig =
+            toml::from_str("addr = \"127.0.0.1:9527\"\nmax_frame_size = 4096").unwrap();
+        assert_eq!(config.max_frame_size, 4096);
+
+        // 超过 30 bit 的长度无法在 frame 中表示
+        let config = format!(
+            "addr = \"127.0.0.1:9527\"\nmax_frame_size = {}",
+            MAX_FRAME_LIMIT + 1
+        );
+        let err = toml::from_str::<GeneralConfig>(&config).unwrap_err();
+        assert!(err.to_string().contains("max_frame_size"));
+    }
+
+    #[test]
+    fn connection_config_should_fill_missing_fields() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.connection, ConnectionConfig::default());
+
+        let config: ConnectionConfig = toml::from_str("max_connections = 10").unwrap();
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.idle_timeout, 90);
+    }
+
+    #[test]
+    fn limit_config_should_be_loaded() {
+        let config: LimitConfig =
+            toml::from_str("max_inflight = 100\n[write]\nrate = 10\nburst = 20").unwrap();
+        assert_eq!(
+            config.write,
+            Some(RateConfig {
+                rate: 10,
+                burst: 20
+            })
+        );
+        assert_eq!(config.read, None);
+        assert_eq!(config.max_inflight, 100);
+    }
+
+    #[test]
+    fn yamux_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.mode, ServerMode::Yamux);
+        assert_eq!(config.yamux, YamuxConfig::default());
+
+        let config: YamuxConfig =
+            toml::from_str("receive_window = 1024\nmax_buffer_size = 4194304").unwrap();
+        assert_eq!(config.max_buffer_size, 4 * 1024 * 1024);
+        // 过小的窗口不会导致 yamux panic
+        let _ = yamux::Config::from(&config);
+    }
+
+    #[test]
+    fn acl_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert!(config.acl.rules.is_empty());
+
+        let config: AclConfig = toml::from_str(
+            "[[rules]]\nidentity = \"*.acme.inc\"\ncommands = [\"hget\"]\ntables = [\"t1\"]",
+        )
+        .unwrap();
+        assert_eq!(config.rules[0].identity, "*.acme.inc");
+        assert_eq!(config.rules[0].commands, vec!["hget".to_string()]);
+        assert!(config.rules[0].topics.is_empty());
+    }
+
+    #[test]
+    fn auth_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert!(config.auth.users.is_empty());
+
+        let config: AuthConfig =
+            toml::from_str("[[users]]\nname = \"alice\"\npassword = \"hash\"\nread = [\"t*\"]")
+                .unwrap();
+        assert_eq!(config.users[0].name, "alice");
+        assert_eq!(config.users[0].read, vec!["t*".to_string()]);
+        assert!(!config.users[0].admin);
+    }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config: CompressionConfig =
+            toml::from_str("codec = \"Zstd\"\nlevel = 3\nthreshold = 4096").unwrap();
+        assert_eq!(config.codec, CompressionCodec::Zstd);
+        assert_eq!(config.level, 3);
+        assert_eq!(config.threshold, 4096);
+    }
 }
@@ -8,6 +8,33 @@ pub enum KvError {
     #[error("Cannot parse command: {0}")]
     InvalidCommand(String),
 
+    #[error("Unsupported command: {0}")]
+    UnsupportedCommand(String),
+
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+
+    #[error("Server is overloaded")]
+    Overloaded,
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(String),
+
+    #[error("Connection is closed: {0}")]
+    ConnectionClosed(String),
+
+    #[error("Handshake failed: {0}")]
+    HandshakeError(String),
+
     #[error("Cannot convert value {0} to {1}")]
     ConvertError(String, &'static str),
 
@@ -29,12 +56,18 @@ pub enum KvError {
     #[error("Frame is too large")]
     FrameError,
 
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+
     #[error("I/O error")]
     IOError(#[from] std::io::Error),
 
     #[error("Certificate parse error: error to load {0} {1}")]
     CertificateParseError(&'static str, &'static str),
 
+    #[error("Invalid certificate revocation list: {0}")]
+    InvalidCrl(String),
+
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
 
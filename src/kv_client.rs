@@ -0,0 +1,466 @@
+use crate::{
+    cache::Cache, value, CommandRequest, CommandResponse, Hello, KvError, Kvpair, PipelineClient,
+    ProstClientStream, ProstServerStream, Service, Storage, StreamResult, Value, YamuxCtrl,
+};
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncWrite, DuplexStream},
+    task::JoinHandle,
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use tracing::{instrument, warn};
+
+/// 进程内连接每个方向上缓存的数据量
+const LOCAL_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 带有类型的 kv 客户端，普通命令在同一个 stream 上并发执行，每个订阅使用单独的 stream。
+/// 服务端返回的错误会还原成对应的 KvError
+pub struct KvClient<S> {
+    ctrl: YamuxCtrl<S>,
+    pipeline: PipelineClient,
+    tracking: Option<Tracking>,
+}
+
+/// 客户端缓存，以及接收失效通知的任务
+struct Tracking {
+    cache: Arc<Cache>,
+    task: JoinHandle<()>,
+}
+
+/// 订阅返回的数据流，每一项是一次 publish 发布的数据
+pub struct Subscription {
+    /// 订阅 id，用于退订
+    pub id: u32,
+    inner: StreamResult,
+}
+
+impl<S> KvClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub async fn new(ctrl: YamuxCtrl<S>) -> Result<Self, KvError> {
+        let pipeline = open(&ctrl).await?.into_pipeline().await?;
+        Ok(Self {
+            ctrl,
+            pipeline,
+            tracking: None,
+        })
+    }
+
+    /// 开启客户端缓存，最多缓存 capacity 个 hget 的结果。
+    /// 服务端会记住读取过的 key，并在 key 被修改时推送失效通知
+    pub async fn with_cache(mut self, capacity: usize) -> Result<Self, KvError> {
+        // 失效通知需要一直保持，因此不使用默认的超时时间
+        let stream = open(&self.ctrl)
+            .await?
+            .execute_streaming(&CommandRequest::new_track())
+            .await?;
+        let cache = Arc::new(Cache::new(capacity, stream.id));
+        let task = tokio::spawn(receive_invalidations(cache.clone(), stream));
+        self.tracking = Some(Tracking { cache, task });
+        Ok(self)
+    }
+
+    /// 设置请求的默认超时时间，订阅会一直保持，不使用这个超时时间
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.pipeline = self.pipeline.with_timeout(timeout);
+        self
+    }
+
+    /// 获取 key 对应的 value，key 不存在时返回 None
+    pub async fn hget(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let cache = self.tracking.as_ref().map(|t| &t.cache);
+        if let Some(value) = cache.and_then(|c| c.get(table, key)) {
+            return Ok(value);
+        }
+        // 缓存未命中时带上 Track 订阅 id，让服务端记住这个 key
+        let begin = cache.and_then(|c| c.begin());
+        let cmd = match begin {
+            Some((id, _)) => CommandRequest::new_hget(table, key).with_tracking(id),
+            None => CommandRequest::new_hget(table, key),
+        };
+
+        let value = match self.call(cmd).await {
+            Ok(res) => res.values.into_iter().next().and_then(optional),
+            Err(KvError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if let (Some(cache), Some((_, generation))) = (cache, begin) {
+            cache.insert(generation, table, key, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// 获取多个 key 对应的 value，不存在的 key 对应 None
+    pub async fn hmget(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.call(CommandRequest::new_hmget(table, keys)).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// 获取 table 中所有的 kv pair
+    pub async fn hgetall(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let res = self.call(CommandRequest::new_hgetall(table)).await?;
+        Ok(res.kvpairs)
+    }
+
+    /// 设置 key 对应的 value，返回之前的 value
+    pub async fn hset(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        let res = self.write(cmd).await?;
+        Ok(res.values.into_iter().next().and_then(optional))
+    }
+
+    /// 设置多个 kv pair，返回之前的 value
+    pub async fn hmset(
+        &self,
+        table: &str,
+        pairs: Vec<Kvpair>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.write(CommandRequest::new_hmset(table, pairs)).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// 删除 key，返回被删除的 value
+    pub async fn hdel(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let res = self.write(CommandRequest::new_hdel(table, key)).await?;
+        Ok(res.values.into_iter().next().and_then(optional))
+    }
+
+    /// 删除多个 key，返回被删除的 value
+    pub async fn hmdel(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.write(CommandRequest::new_hmdel(table, keys)).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// key 是否存在
+    pub async fn exists(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let res = self.call(CommandRequest::new_hexist(table, key)).await?;
+        match res.values.first() {
+            Some(v) => v.try_into(),
+            None => Err(KvError::Internal("Didn't get any value".into())),
+        }
+    }
+
+    /// 多个 key 是否存在
+    pub async fn hmexist(&self, table: &str, keys: Vec<String>) -> Result<Vec<bool>, KvError> {
+        let res = self.call(CommandRequest::new_hmexist(table, keys)).await?;
+        res.values.iter().map(|v| v.try_into()).collect()
+    }
+
+    /// 发布数据到 topic
+    pub async fn publish(&self, topic: &str, values: Vec<Value>) -> Result<(), KvError> {
+        self.call(CommandRequest::new_publish(topic, values))
+            .await?;
+        Ok(())
+    }
+
+    /// 订阅 topic
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription, KvError> {
+        self.stream(CommandRequest::new_subscribe(topic)).await
+    }
+
+    /// 订阅所有匹配 pattern 的 topic
+    pub async fn psubscribe(&self, pattern: &str) -> Result<Subscription, KvError> {
+        self.stream(CommandRequest::new_psubscribe(pattern)).await
+    }
+
+    /// 退订 topic
+    pub async fn unsubscribe(&self, topic: &str, id: u32) -> Result<(), KvError> {
+        self.call(CommandRequest::new_unsubscribe(topic, id))
+            .await?;
+        Ok(())
+    }
+
+    /// 退订 pattern
+    pub async fn punsubscribe(&self, pattern: &str, id: u32) -> Result<(), KvError> {
+        self.call(CommandRequest::new_punsubscribe(pattern, id))
+            .await?;
+        Ok(())
+    }
+
+    /// 检查服务端是否可用
+    pub async fn ping(&self) -> Result<(), KvError> {
+        self.call(CommandRequest::new_ping()).await?;
+        Ok(())
+    }
+
+    /// 分块上传 value，返回上传的字节数
+    pub async fn upload<R>(&self, table: &str, key: &str, reader: R) -> Result<u64, KvError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let res = open(&self.ctrl).await?.upload(table, key, reader).await;
+        self.invalidate(table, &[key]);
+        res
+    }
+
+    /// 分块下载 value，返回下载的字节数
+    pub async fn download<W>(&self, table: &str, key: &str, writer: W) -> Result<u64, KvError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        open(&self.ctrl).await?.download(table, key, writer).await
+    }
+
+    /// 服务端在握手时的回复
+    pub fn server(&self) -> &Hello {
+        self.pipeline.server()
+    }
+
+    /// 底层连接是否已经关闭
+    pub fn is_closed(&self) -> bool {
+        self.ctrl.is_closed()
+    }
+
+    #[instrument(name = "kv_client_call", skip_all)]
+    async fn call(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.pipeline.execute(&cmd).await?.into_result()
+    }
+
+    /// 执行修改数据的请求，并删除本地缓存中对应的 key
+    async fn write(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        if self.tracking.is_none() {
+            return self.call(cmd).await;
+        }
+        let res = self.call(cmd.clone()).await;
+        if let Some((table, keys)) = cmd.keys() {
+            self.invalidate(table, &keys);
+        }
+        res
+    }
+
+    fn invalidate(&self, table: &str, keys: &[&str]) {
+        if let Some(tracking) = &self.tracking {
+            tracking.cache.invalidate(table, keys);
+        }
+    }
+
+    /// 在单独的 stream 上订阅，和 with_cache 一样不使用默认的超时时间，否则订阅会被服务端结束
+    async fn stream(&self, cmd: CommandRequest) -> Result<Subscription, KvError> {
+        let inner = open(&self.ctrl).await?.execute_streaming(&cmd).await?;
+        Ok(Subscription {
+            id: inner.id,
+            inner,
+        })
+    }
+}
+
+impl KvClient<DuplexStream> {
+    /// 创建直接访问进程内 service 的客户端，不需要 TLS 和 socket。
+    /// 请求通过内存中的 duplex 按照和网络连接相同的协议收发，订阅和客户端缓存都可以正常使用
+    pub async fn local<Store: Storage>(service: Service<Store>) -> Result<Self, KvError> {
+        let (client, server) = duplex(LOCAL_BUFFER_SIZE);
+        YamuxCtrl::new_server(server, None, move |stream| {
+            let service = service.clone();
+            async move {
+                let stream = ProstServerStream::new(stream.compat(), service).with_client("local");
+                if let Err(e) = stream.process().await {
+                    warn!("Local stream is closed: {}", e);
+                }
+                Ok(())
+            }
+        });
+        Self::new(YamuxCtrl::new_client(client, None)).await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Vec<Value>, KvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut()
+            .inner
+            .poll_next_unpin(cx)
+            .map(|res| res.map(|res| Ok(res?.into_result()?.values)))
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// 根据服务端推送的失效通知删除缓存中的 key，通知中断后缓存不再可信，直接停用
+async fn receive_invalidations(cache: Arc<Cache>, mut stream: StreamResult) {
+    while let Some(Ok(res)) = stream.next().await {
+        if res.status != 200 {
+            break;
+        }
+        let mut values = res.values.iter().filter_map(|v| match &v.value {
+            Some(value::Value::String(s)) => Some(s.as_str()),
+            _ => None,
+        });
+        if let Some(table) = values.next() {
+            cache.invalidate(table, &values.collect::<Vec<_>>());
+        }
+    }
+    warn!("Tracking is interrupted, client cache is disabled");
+    cache.disable();
+}
+
+/// 打开一个新的 stream
+async fn open<S>(ctrl: &YamuxCtrl<S>) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    ctrl.open_stream()
+        .await
+        .map_err(|e| KvError::ConnectionClosed(e.to_string()))
+}
+
+/// 服务端用 Value::default() 表示不存在的值
+fn optional(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+    use anyhow::Result;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time;
+
+    async fn client() -> Result<KvClient<DuplexStream>> {
+        connect(ServiceInner::new(MemTable::new()).into()).await
+    }
+
+    async fn connect(service: Service) -> Result<KvClient<DuplexStream>> {
+        Ok(KvClient::local(service).await?)
+    }
+
+    #[tokio::test]
+    async fn kv_client_basic_commands_should_work() -> Result<()> {
+        let client = client().await?;
+
+        assert_eq!(client.hget("t1", "k1").await?, None);
+        assert_eq!(client.hset("t1", "k1", "v1").await?, None);
+        assert_eq!(client.hset("t1", "k1", "v2").await?, Some("v1".into()));
+        assert_eq!(client.hget("t1", "k1").await?, Some("v2".into()));
+        assert!(client.exists("t1", "k1").await?);
+
+        let pairs = vec![Kvpair::new("k2", 2.into()), Kvpair::new("k3", 3.into())];
+        assert_eq!(client.hmset("t1", pairs).await?, vec![None, None]);
+        let keys = vec!["k2".into(), "k4".into()];
+        assert_eq!(client.hmget("t1", keys).await?, vec![Some(2.into()), None]);
+        assert_eq!(client.hgetall("t1").await?.len(), 3);
+
+        assert_eq!(client.hdel("t1", "k1").await?, Some("v2".into()));
+        assert!(!client.exists("t1", "k1").await?);
+        client.ping().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kv_client_subscription_should_work() -> Result<()> {
+        let client = client().await?;
+        let mut sub = client.subscribe("lobby").await?;
+
+        client.publish("lobby", vec!["hello".into()]).await?;
+        let data = sub.next().await.unwrap()?;
+        assert_eq!(data, vec!["hello".into()]);
+
+        client.unsubscribe("lobby", sub.id).await?;
+        assert!(client.unsubscribe("lobby", sub.id).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_outlive_default_timeout() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let client = KvClient::local(service)
+            .await?
+            .with_timeout(Duration::from_millis(50));
+        let mut sub = client.subscribe("lobby").await?;
+        let mut psub = client.psubscribe("lob*").await?;
+
+        time::sleep(Duration::from_millis(150)).await;
+        client.publish("lobby", vec!["hello".into()]).await?;
+        assert_eq!(sub.next().await.unwrap()?, vec!["hello".into()]);
+        assert_eq!(psub.next().await.unwrap()?, vec!["hello".into()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_clients_should_share_service() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let subscriber = KvClient::local(service.clone()).await?;
+        let publisher = KvClient::local(service).await?;
+        let mut sub = subscriber.psubscribe("news.*").await?;
+
+        publisher.hset("t1", "k1", "v1").await?;
+        assert_eq!(subscriber.hget("t1", "k1").await?, Some("v1".into()));
+        publisher.publish("news.rust", vec!["hello".into()]).await?;
+        let data = sub.next().await.unwrap()?;
+        assert_eq!(data, vec!["hello".into()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_errors_should_map_to_kv_error() -> Result<()> {
+        let client = client().await?;
+        let res = client.unsubscribe("lobby", 42).await;
+        assert!(matches!(res, Err(KvError::NotFound(_))));
+
+        let res = client.download("t1", "k1", Vec::new()).await;
+        match res {
+            Err(KvError::NotFound(msg)) => assert_eq!(msg, "t1:k1"),
+            res => panic!("unexpected result: {res:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cached_value_should_be_invalidated_by_other_clients() -> Result<()> {
+        let hgets = Arc::new(AtomicUsize::new(0));
+        let counter = hgets.clone();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_received(move |cmd| {
+                if cmd.name() == "hget" {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            })
+            .into();
+        let reader = connect(service.clone()).await?.with_cache(16).await?;
+        let writer = connect(service).await?;
+
+        writer.hset("t1", "k1", "v1").await?;
+        for _ in 0..3 {
+            assert_eq!(reader.hget("t1", "k1").await?, Some("v1".into()));
+        }
+        assert_eq!(hgets.load(Ordering::Relaxed), 1);
+
+        // 失效通知是异步推送的，等待缓存被清理
+        writer.hset("t1", "k1", "v2").await?;
+        time::timeout(Duration::from_secs(1), async {
+            while reader.hget("t1", "k1").await? != Some("v2".into()) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, KvError>(())
+        })
+        .await??;
+        assert_eq!(hgets.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,712 @@
+use crate::{
+    detect, peer_identity, Acl, AclConfig, AuthConfig, CompressionConfig, ConnectionConfig,
+    IdleStream, KvError, LimitConfig, MemTable, PeerIdentity, ProstServerStream, Protocol,
+    ServerConfig, ServerMode, Service, ServiceInner, Storage, TlsServerAcceptor, Users,
+    YamuxConfig, YamuxCtrl, MAX_FRAME,
+};
+use futures::{future::BoxFuture, Future};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncWrite, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch, Semaphore},
+    task::JoinHandle,
+    time,
+};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{info, span, warn, Instrument};
+
+/// accept 出错后重试之前等待的时间
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// 内存连接每个方向上缓存的数据量
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 连接数超过上限时，最多同时告知多少个连接服务器过载，更多的连接直接关闭
+const MAX_REJECTING: usize = 64;
+
+/// 告知连接服务器过载最多花费的时间
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 服务器接受连接的来源，比如 TCP、Unix socket 或者内存中的 duplex
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// 接受一个新的连接，返回连接以及对端的地址，不是 TCP 连接时地址为 None
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, Option<SocketAddr>)>>;
+
+    /// 监听的地址，不是 TCP 的 listener 返回 None
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(TcpStream, Option<SocketAddr>)>> {
+        Box::pin(async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            Ok((stream, Some(addr)))
+        })
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpListener::local_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(Self::Stream, Option<SocketAddr>)>> {
+        Box::pin(async move {
+            let (stream, _) = tokio::net::UnixListener::accept(self).await?;
+            Ok((stream, None))
+        })
+    }
+}
+
+/// 在内存中接受连接的 listener，连接由对应的 MemoryConnector 创建
+pub struct MemoryListener {
+    rx: mpsc::Receiver<DuplexStream>,
+}
+
+/// 创建连接到 MemoryListener 的内存连接，可以 clone 之后在多处使用
+#[derive(Clone)]
+pub struct MemoryConnector {
+    tx: mpsc::Sender<DuplexStream>,
+}
+
+/// 创建一对内存中的 listener 和 connector
+pub fn memory_listener() -> (MemoryListener, MemoryConnector) {
+    let (tx, rx) = mpsc::channel(16);
+    (MemoryListener { rx }, MemoryConnector { tx })
+}
+
+impl Listener for MemoryListener {
+    type Stream = DuplexStream;
+
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(DuplexStream, Option<SocketAddr>)>> {
+        Box::pin(async move {
+            match self.rx.recv().await {
+                Some(stream) => Ok((stream, None)),
+                // 所有的 connector 都被 drop 之后不会再有新的连接，等待服务器关闭
+                None => futures::future::pending().await,
+            }
+        })
+    }
+}
+
+impl MemoryConnector {
+    /// 建立一个新的内存连接，服务器已经停止时返回错误
+    pub async fn connect(&self) -> Result<DuplexStream, KvError> {
+        let (client, server) = duplex(MEMORY_BUFFER_SIZE);
+        self.tx
+            .send(server)
+            .await
+            .map_err(|_| KvError::ConnectionClosed("memory listener is closed".into()))?;
+        Ok(client)
+    }
+}
+
+/// 可以嵌入到应用中的 kv 服务器
+pub struct KvServer;
+
+impl KvServer {
+    /// 创建服务器的 builder，默认使用 MemTable 存储、不使用 TLS
+    pub fn builder() -> KvServerBuilder<MemTable> {
+        KvServerBuilder::new(BuilderService::Inner(Box::new(ServiceInner::new(
+            MemTable::new(),
+        ))))
+    }
+}
+
+/// builder 使用的 service。由 builder 创建的 service 在启动时才构建，因此还可以应用配置中的
+/// 限流、访问控制和用户认证；通过 with_service 传入的 service 需要调用者自己配置
+enum BuilderService<Store> {
+    Inner(Box<ServiceInner<Store>>),
+    Built(Service<Store>),
+}
+
+/// 配置并启动 KvServer
+pub struct KvServerBuilder<Store = MemTable> {
+    service: BuilderService<Store>,
+    tls: Option<TlsServerAcceptor>,
+    mode: ServerMode,
+    yamux: YamuxConfig,
+    compression: CompressionConfig,
+    max_frame: usize,
+    connection: ConnectionConfig,
+    grace_period: Duration,
+}
+
+/// 运行中的 KvServer，drop 之后服务器继续在后台运行
+pub struct KvServerHandle {
+    addr: Option<SocketAddr>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), KvError>>,
+}
+
+impl<Store: Storage> KvServerBuilder<Store> {
+    fn new(service: BuilderService<Store>) -> Self {
+        Self {
+            service,
+            tls: None,
+            mode: ServerMode::default(),
+            yamux: YamuxConfig::default(),
+            compression: CompressionConfig::default(),
+            max_frame: MAX_FRAME,
+            connection: ConnectionConfig::default(),
+            grace_period: Duration::from_secs(30),
+        }
+    }
+
+    /// 使用 store 作为存储，会替换掉之前设置的 store 或者 service
+    pub fn with_store<S: Storage>(self, store: S) -> KvServerBuilder<S> {
+        self.with(BuilderService::Inner(Box::new(ServiceInner::new(store))))
+    }
+
+    /// 使用已经配置好 hook、限流、鉴权等的 service
+    pub fn with_service<S: Storage>(self, service: Service<S>) -> KvServerBuilder<S> {
+        self.with(BuilderService::Built(service))
+    }
+
+    fn with<S: Storage>(self, service: BuilderService<S>) -> KvServerBuilder<S> {
+        KvServerBuilder {
+            service,
+            tls: self.tls,
+            mode: self.mode,
+            yamux: self.yamux,
+            compression: self.compression,
+            max_frame: self.max_frame,
+            connection: self.connection,
+            grace_period: self.grace_period,
+        }
+    }
+
+    /// 使用配置中除了存储和 TLS 之外的部分，限流、访问控制和用户认证会应用到 builder 创建的 service 上。
+    /// 通过 with_service 传入的 service 已经无法修改，此时配置中设置了这些部分会返回错误
+    pub fn with_config(mut self, config: &ServerConfig) -> Result<Self, KvError> {
+        self.service = match self.service {
+            BuilderService::Inner(inner) => BuilderService::Inner(Box::new(
+                inner
+                    .with_limit(config.limit.clone())
+                    .with_acl(Acl::new(&config.acl)?)
+                    .with_users(Users::new(&config.auth)?),
+            )),
+            BuilderService::Built(_)
+                if config.limit != LimitConfig::default()
+                    || config.acl != AclConfig::default()
+                    || config.auth != AuthConfig::default() =>
+            {
+                return Err(KvError::Internal(
+                    "limit, acl and auth in the config cannot be applied to a prebuilt service"
+                        .into(),
+                ));
+            }
+            service => service,
+        };
+        self.mode = config.mode;
+        self.yamux = config.yamux.clone();
+        self.compression = config.compression.clone();
+        self.max_frame = config.general.max_frame_size;
+        self.connection = config.connection.clone();
+        self.grace_period = Duration::from_secs(config.general.grace_period);
+        Ok(self)
+    }
+
+    /// 使用 TLS 加密连接，没有设置时直接在连接上收发数据
+    pub fn with_tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// 设置接受的连接类型
+    pub fn with_mode(mut self, mode: ServerMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 设置 yamux 的配置
+    pub fn with_yamux(mut self, yamux: YamuxConfig) -> Self {
+        self.yamux = yamux;
+        self
+    }
+
+    /// 设置服务端期望的压缩配置
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置能够接收的最大 frame
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// 设置连接数、stream 数以及空闲超时的限制
+    pub fn with_connection(mut self, connection: ConnectionConfig) -> Self {
+        self.connection = connection;
+        self
+    }
+
+    /// 设置关闭时等待正在执行的请求结束的最长时间
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// 在后台开始接受 listener 上的连接
+    pub fn start<L: Listener>(self, listener: L) -> KvServerHandle {
+        let addr = listener.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        let task = tokio::spawn(self.serve_with_shutdown(listener, async move {
+            // handle 被 drop 时不关闭服务器
+            if rx.await.is_err() {
+                futures::future::pending().await
+            }
+        }));
+        KvServerHandle {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// 接受 listener 上的连接，shutdown 完成时停止接受新的连接，
+    /// 等待正在执行的请求结束（最多等待 grace period）并把数据写入磁盘后返回
+    pub async fn serve_with_shutdown<L: Listener>(
+        self,
+        mut listener: L,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), KvError> {
+        let addr = listener.local_addr();
+        info!("Start listening on {:?}", addr);
+
+        let limits = &self.connection;
+        let connections = Arc::new(Semaphore::new(limits.max_connections));
+        let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
+        let mut yamux = yamux::Config::from(&self.yamux);
+        yamux.set_max_num_streams(limits.max_streams);
+
+        let service = match self.service {
+            BuilderService::Inner(inner) => (*inner).into(),
+            BuilderService::Built(service) => service,
+        };
+        let (notify, signal) = watch::channel(false);
+        let (drain, mut drained) = mpsc::channel::<()>(1);
+        let ctx = Arc::new(ServerContext {
+            service: service.clone(),
+            compression: self.compression,
+            max_frame: self.max_frame,
+            mode: self.mode,
+            yamux,
+            idle_timeout: match limits.idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            signal,
+            drain: Mutex::new(Some(drain)),
+        });
+
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(v) => v,
+                    // 比如文件描述符耗尽，稍等片刻再继续 accept，避免空转
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let (ctx, tls) = (ctx.clone(), self.tls.clone());
+            let root = span!(tracing::Level::INFO, "server_process");
+            // 连接数达到上限时告知客户端服务器过载之后关闭连接，避免耗尽文件描述符。
+            // 告知的过程同样占用文件描述符，因此只允许少量的连接同时进行并且限制时间，更多的连接直接关闭
+            let permit = match connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let permit = match rejecting.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            warn!("Too many connections, drop client {:?}", addr);
+                            continue;
+                        }
+                    };
+                    warn!("Too many connections, reject client {:?}", addr);
+                    let conn = async move {
+                        let conn = ctx.accept(tls, stream, addr, true);
+                        let _ = time::timeout(REJECT_TIMEOUT, conn).await;
+                        drop(permit);
+                    };
+                    tokio::spawn(conn.instrument(root));
+                    continue;
+                }
+            };
+            info!("Client {:?} connected", addr);
+
+            let conn = async move {
+                ctx.accept(tls, stream, addr, false).await;
+                // 连接关闭后才释放 permit
+                info!("Client {:?} disconnected", addr);
+                drop(permit);
+            };
+            tokio::spawn(conn.instrument(root));
+        }
+
+        // 停止 accept 之后，通知所有的 stream 和订阅者，并在 grace period 内等待正在执行的请求结束
+        info!("Shutting down server on {:?}", addr);
+        let _ = notify.send(true);
+        service.shutdown();
+        ctx.drain.lock().unwrap().take();
+        if time::timeout(self.grace_period, drained.recv())
+            .await
+            .is_err()
+        {
+            warn!("Grace period elapsed, unfinished requests are dropped");
+        }
+        service.flush()?;
+        info!("Server on {:?} is stopped", addr);
+        Ok(())
+    }
+}
+
+impl KvServerHandle {
+    /// 服务器监听的地址，不是 TCP 的 listener 返回 None
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// 关闭服务器，等待正在执行的请求结束并把数据写入磁盘后返回
+    pub async fn shutdown(self) -> Result<(), KvError> {
+        let _ = self.shutdown.send(());
+        self.task
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?
+    }
+}
+
+/// 连接另一端的客户端
+struct Peer {
+    /// 客户端的标识，有客户端证书时使用证书的 CN，否则使用 IP 地址
+    client: String,
+    /// 客户端证书中的身份，没有证书时为 None
+    identity: Option<Arc<PeerIdentity>>,
+    /// 客户端通过 SNI 访问的 namespace
+    namespace: Option<String>,
+    addr: Option<SocketAddr>,
+    /// 连接数超过了上限，只告知客户端服务器过载，不处理请求
+    overloaded: bool,
+}
+
+impl Peer {
+    fn new(identity: Option<PeerIdentity>, addr: Option<SocketAddr>) -> Self {
+        let client = identity.as_ref().and_then(|id| id.common_name.clone());
+        let client = client.or_else(|| addr.map(|addr| addr.ip().to_string()));
+        Self {
+            // 内存连接和 Unix socket 上的客户端共享同一个标识
+            client: client.unwrap_or_else(|| "local".into()),
+            identity: identity.map(Arc::new),
+            namespace: None,
+            addr,
+            overloaded: false,
+        }
+    }
+}
+
+/// 服务端处理连接时共享的上下文
+struct ServerContext<Store> {
+    service: Service<Store>,
+    compression: CompressionConfig,
+    max_frame: usize,
+    mode: ServerMode,
+    yamux: yamux::Config,
+    idle_timeout: Option<Duration>,
+    /// 通知所有 stream 服务器正在关闭
+    signal: watch::Receiver<bool>,
+    /// 每个正在处理的 stream 都持有一个 sender，服务器关闭时被清空
+    drain: Mutex<Option<mpsc::Sender<()>>>,
+}
+
+impl<Store: Storage> ServerContext<Store> {
+    /// 处理一个新的连接，有 TLS 时先完成握手，连接关闭后返回
+    async fn accept<S>(
+        self: Arc<Self>,
+        tls: Option<TlsServerAcceptor>,
+        stream: S,
+        addr: Option<SocketAddr>,
+        overloaded: bool,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match tls {
+            Some(tls) => self.serve_tls(tls, stream, addr, overloaded).await,
+            None => {
+                let peer = Peer {
+                    overloaded,
+                    ..Peer::new(None, addr)
+                };
+                self.serve(stream, Arc::new(peer)).await
+            }
+        }
+    }
+
+    /// 完成 TLS 握手之后处理连接
+    async fn serve_tls<S>(
+        self: Arc<Self>,
+        tls: TlsServerAcceptor,
+        stream: S,
+        addr: Option<SocketAddr>,
+        overloaded: bool,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // 握手迟迟不能完成的连接同样按照空闲连接处理
+        let stream =
+            match with_timeout(self.idle_timeout, "TLS handshake", tls.accept(stream)).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {:?} failed: {}", addr, e);
+                    return;
+                }
+            };
+        // 有客户端证书时使用证书的 CN 标识客户端，否则使用 IP 地址
+        let peer = Peer {
+            namespace: tls.namespace(&stream),
+            overloaded,
+            ..Peer::new(peer_identity(&stream), addr)
+        };
+        self.serve(stream, Arc::new(peer)).await;
+    }
+
+    /// 处理一个已经完成 TLS 握手的连接，连接关闭后返回
+    async fn serve<S>(self: Arc<Self>, stream: S, peer: Arc<Peer>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self.mode {
+            ServerMode::Yamux => self.serve_yamux(stream, peer).await,
+            ServerMode::Plain => self.serve_plain(stream, peer).await,
+            ServerMode::Auto => {
+                match with_timeout(self.idle_timeout, "detect", detect(stream)).await {
+                    Ok((Protocol::Yamux, stream)) => self.serve_yamux(stream, peer).await,
+                    Ok((Protocol::Plain, stream)) => self.serve_plain(stream, peer).await,
+                    Err(e) => warn!("Failed to detect protocol of {:?}: {}", peer.addr, e),
+                }
+            }
+        }
+    }
+
+    /// 在 yamux 的每个 stream 上分别处理请求
+    async fn serve_yamux<S>(self: Arc<Self>, stream: S, peer: Arc<Peer>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let ctx = self.clone();
+        let ctrl = YamuxCtrl::new_server(stream, Some(self.yamux.clone()), move |stream| {
+            let ctx = ctx.clone();
+            let peer = peer.clone();
+            async move {
+                ctx.serve_stream(stream.compat(), &peer).await;
+                Ok(())
+            }
+        });
+        let ctrl = match self.idle_timeout {
+            Some(timeout) => ctrl.with_idle_timeout(timeout),
+            None => ctrl,
+        };
+        ctrl.closed().await;
+    }
+
+    /// 直接在连接上处理请求
+    async fn serve_plain<S>(&self, stream: S, peer: Arc<Peer>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (stream, activity) = IdleStream::new(stream);
+        match self.idle_timeout {
+            Some(timeout) => tokio::select! {
+                _ = self.serve_stream(stream, &peer) => {}
+                _ = activity.wait_idle(timeout) => {
+                    info!("Connection from {:?} is idle for {:?}, closing it", peer.addr, timeout);
+                }
+            },
+            None => self.serve_stream(stream, &peer).await,
+        }
+    }
+
+    async fn serve_stream<S>(&self, stream: S, peer: &Peer)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        // 服务器已经开始关闭时，不再处理新的 stream
+        let _guard = match self.drain.lock().unwrap().clone() {
+            Some(guard) => guard,
+            None => return,
+        };
+        let stream = ProstServerStream::new(stream, self.service.clone())
+            .with_compression(self.compression.clone())
+            .with_max_frame(self.max_frame)
+            .with_shutdown(self.signal.clone())
+            .with_client(peer.client.clone())
+            .with_identity(peer.identity.clone())
+            .with_namespace(peer.namespace.as_deref());
+        let stream = match peer.addr {
+            Some(addr) => stream.with_addr(addr),
+            None => stream,
+        };
+        if peer.overloaded {
+            if let Err(e) = stream.reject(KvError::Overloaded).await {
+                warn!("Failed to reject stream from {:?}: {}", peer.addr, e);
+            }
+            return;
+        }
+        // 出错时只关闭这个 stream，不影响同一个连接上的其它 stream
+        if let Err(e) = stream.process().await {
+            warn!("Stream from {:?} is closed: {}", peer.addr, e);
+        }
+    }
+}
+
+/// 超过 timeout 时返回 KvError::Timeout
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    name: &str,
+    fut: impl Future<Output = Result<T, KvError>>,
+) -> Result<T, KvError> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, fut)
+            .await
+            .unwrap_or_else(|_| Err(KvError::Timeout(name.into()))),
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AclRule, CommandRequest, KvClient, ProstClientStream, Value};
+    use anyhow::Result;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn tcp_server_should_work() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = KvServer::builder()
+            .with_store(MemTable::new())
+            .start(listener);
+        let addr = server.local_addr().unwrap();
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = KvClient::new(YamuxCtrl::new_client(stream, None)).await?;
+        client.hset("t1", "k1", "v1").await?;
+        assert_eq!(client.hget("t1", "k1").await?, Some("v1".into()));
+
+        server.shutdown().await?;
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_server_should_work() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_before_send(|res| {
+                res.message = "hooked".into();
+                Ok(())
+            })
+            .into();
+        let (listener, connector) = memory_listener();
+        let server = KvServer::builder()
+            .with_service(service)
+            .with_mode(ServerMode::Plain)
+            .start(listener);
+        assert!(server.local_addr().is_none());
+
+        let mut client = ProstClientStream::new(connector.connect().await?);
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", Value::from("v1")))
+            .await?;
+        assert_eq!(res.message, "hooked");
+
+        server.shutdown().await?;
+        assert!(connector.connect().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn config_should_apply_to_builder_service() -> Result<()> {
+        let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+        config.mode = ServerMode::Plain;
+        config.acl.rules = vec![AclRule {
+            identity: "*".into(),
+            commands: vec!["hget".into()],
+            tables: vec!["*".into()],
+            ..Default::default()
+        }];
+
+        // 已经构建好的 service 无法再应用配置中的访问控制
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = KvServer::builder()
+            .with_service(service)
+            .with_config(&config);
+        assert!(matches!(res, Err(KvError::Internal(_))));
+
+        let (listener, connector) = memory_listener();
+        let server = KvServer::builder().with_config(&config)?.start(listener);
+        let mut client = ProstClientStream::new(connector.connect().await?);
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(res.status, StatusCode::FORBIDDEN.as_u16() as u32);
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_over_limit_should_be_told_overloaded() -> Result<()> {
+        let (listener, connector) = memory_listener();
+        let server = KvServer::builder()
+            .with_mode(ServerMode::Plain)
+            .with_connection(ConnectionConfig {
+                max_connections: 1,
+                ..Default::default()
+            })
+            .start(listener);
+        let mut first = ProstClientStream::new(connector.connect().await?);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        first.execute(&cmd).await?;
+
+        let mut second = ProstClientStream::new(connector.connect().await?);
+        let res = second.execute(&cmd).await?;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE.as_u16() as u32);
+        assert!(second.execute(&cmd).await.is_err());
+
+        // 已有的连接不受影响，关闭之后新的连接可以正常使用
+        first.execute(&cmd).await?;
+        drop(first);
+        time::sleep(Duration::from_millis(50)).await;
+        let mut third = ProstClientStream::new(connector.connect().await?);
+        let res = third.execute(&cmd).await?;
+        assert_eq!(res.status, StatusCode::OK.as_u16() as u32);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
@@ -1,62 +1,86 @@
+mod blocking;
+mod cache;
 mod config;
 mod error;
+mod kv_client;
+mod kv_server;
 mod network;
 mod pb;
+mod pool;
 mod service;
 mod storage;
+pub use blocking::{BlockingClient, BlockingSubscription};
 pub use config::*;
 pub use error::KvError;
+pub use kv_client::{KvClient, Subscription};
+pub use kv_server::{
+    memory_listener, KvServer, KvServerBuilder, KvServerHandle, Listener, MemoryConnector,
+    MemoryListener,
+};
 pub use network::*;
 pub use pb::abi::*;
+pub use pb::{COMMANDS, PROTOCOL_VERSION};
+pub use pool::{KvPool, PoolEvent, PoolSubscription};
 pub use service::*;
 pub use storage::*;
 
 use anyhow::Result;
+use futures::{future, Future};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::client;
-use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, instrument, span};
+use tracing::instrument;
 
 async fn start_tls_server<Store: Storage>(
-    addr: &str,
+    config: &ServerConfig,
     store: Store,
     acceptor: TlsServerAcceptor,
+    shutdown: impl Future<Output = ()>,
 ) -> Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    let service: Service<Store> = ServiceInner::new(store).into();
-    info!("Start listening on {}", addr);
-    loop {
-        let root = span!(tracing::Level::INFO, "server_process");
-        let _enter = root.enter();
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
-            YamuxCtrl::new_server(stream, None, move |stream| {
-                let svc1 = svc.clone();
-                async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-                    stream.process().await.unwrap();
-                    Ok(())
-                }
-            });
-        });
-    }
+    let listener = TcpListener::bind(&config.general.addr).await?;
+    KvServer::builder()
+        .with_store(store)
+        .with_config(config)?
+        .with_tls(acceptor)
+        .serve_with_shutdown(listener, shutdown)
+        .await?;
+    Ok(())
 }
 
 /// 通过配置创建 kv 服务器
 #[instrument(skip_all)]
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
+    start_server_with_shutdown(config, future::pending()).await
+}
+
+/// 通过配置创建 kv 服务器，shutdown 完成时停止接受新的连接，
+/// 等待正在执行的请求结束（最多等待 grace_period 秒）并把数据写入磁盘后返回
+#[instrument(skip_all)]
+pub async fn start_server_with_shutdown(
+    config: &ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     let acceptor =
-        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?;
+        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?
+            .with_crls(&config.tls.crls)?
+            .with_sni(&config.tls.sni)?;
+    start_server_with_acceptor(config, acceptor, shutdown).await
+}
 
-    let addr = &config.general.addr;
+/// 使用给定的 acceptor 创建 kv 服务器，调用 acceptor 的 reload 可以在运行时更换证书
+#[instrument(skip_all)]
+pub async fn start_server_with_acceptor(
+    config: &ServerConfig,
+    acceptor: TlsServerAcceptor,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
+        StorageConfig::MemTable => {
+            start_tls_server(config, MemTable::new(), acceptor, shutdown).await?
+        }
+        StorageConfig::SledDb(path) => {
+            start_tls_server(config, SledDb::new(path), acceptor, shutdown).await?
+        }
     };
     Ok(())
 }
@@ -66,6 +90,12 @@ pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
 pub async fn start_client_with_config(
     config: &ClientConfig,
 ) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
+    Ok(connect(config).await?)
+}
+
+async fn connect(
+    config: &ClientConfig,
+) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>, KvError> {
     let addr = &config.general.addr;
     let tls = &config.tls;
 
@@ -74,5 +104,69 @@ pub async fn start_client_with_config(
     let stream = TcpStream::connect(addr).await?;
     let stream = connector.connect(stream).await?;
     // 打开一个 stream
-    Ok(YamuxCtrl::new_client(stream, None))
+    let ctrl = YamuxCtrl::new_client(stream, Some((&config.yamux).into()))
+        .with_compression(config.compression.clone())
+        .with_max_frame(config.general.max_frame_size);
+    let ctrl = match &config.credentials {
+        Some(c) => ctrl.with_credentials(&c.username, &c.password),
+        None => ctrl,
+    };
+    Ok(match config.keepalive_interval {
+        0 => ctrl,
+        secs => ctrl.with_keepalive(Duration::from_secs(secs)),
+    })
+}
+
+/// 通过配置创建不使用 yamux 的 kv 客户端，直接在连接上收发 frame，服务端需要使用 Plain 或者 Auto 模式
+#[instrument(skip_all)]
+pub async fn start_plain_client_with_config(
+    config: &ClientConfig,
+) -> Result<ProstClientStream<client::TlsStream<TcpStream>>> {
+    let addr = &config.general.addr;
+    let tls = &config.tls;
+
+    let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+    let stream = TcpStream::connect(addr).await?;
+    let stream = connector.connect(stream).await?;
+    let stream = ProstClientStream::new(stream)
+        .with_compression(config.compression.clone())
+        .with_max_frame(config.general.max_frame_size);
+    Ok(match &config.credentials {
+        Some(c) => stream.with_credentials(&c.username, &c.password),
+        None => stream,
+    })
+}
+
+/// 通过配置创建带有类型的 kv 客户端
+#[instrument(skip_all)]
+pub async fn start_kv_client_with_config(
+    config: &ClientConfig,
+) -> Result<KvClient<client::TlsStream<TcpStream>>> {
+    Ok(connect_kv(config).await?)
+}
+
+async fn connect_kv(
+    config: &ClientConfig,
+) -> Result<KvClient<client::TlsStream<TcpStream>>, KvError> {
+    let client = KvClient::new(connect(config).await?).await?;
+    match config.cache_capacity {
+        0 => Ok(client),
+        capacity => client.with_cache(capacity).await,
+    }
+}
+
+/// 通过配置创建 kv 客户端的连接池，连接断开后会自动重连
+#[instrument(skip_all)]
+pub async fn start_kv_pool_with_config(
+    config: &ClientConfig,
+) -> Result<KvPool<client::TlsStream<TcpStream>>> {
+    let connect_config = config.clone();
+    let pool = KvPool::new(config.pool.clone(), move || {
+        let config = connect_config.clone();
+        async move { connect_kv(&config).await }
+    });
+    // 先建立一个连接，尽早发现配置上的错误
+    pool.client().await?;
+    Ok(pool)
 }
@@ -1,22 +1,31 @@
-use std::io::{Read, Write};
+use std::io::Write;
 
-use crate::{Compressor, KvError};
+use super::read_to_limit;
+use crate::{Compressor, KvError, DEFAULT_LEVEL};
 use bytes::{BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 #[derive(Debug)]
 pub struct Gzip {}
 
 impl Compressor for Gzip {
-    fn compress(src: &[u8], dst: &mut BytesMut) -> Result<usize, KvError> {
-        let mut encoder = GzEncoder::new(dst.writer(), Compression::default());
+    fn compress_with_level(src: &[u8], dst: &mut BytesMut, level: u32) -> Result<usize, KvError> {
+        let level = match level {
+            DEFAULT_LEVEL => Compression::default(),
+            // gzip 的压缩级别为 0~9
+            v => Compression::new(v.min(9)),
+        };
+        let mut encoder = GzEncoder::new(dst.writer(), level);
         encoder.write_all(src)?;
         encoder.finish()?;
         Ok(dst.len())
     }
 
-    fn decompress(src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError> {
-        let mut decoder = GzDecoder::new(&src[..]);
-        decoder.read_to_end(dst)?;
-        Ok(())
+    fn decompress_with_limit(
+        src: &BytesMut,
+        dst: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), KvError> {
+        let decoder = GzDecoder::new(&src[..]);
+        read_to_limit(decoder, dst, limit)
     }
 }
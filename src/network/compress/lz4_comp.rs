@@ -1,21 +1,29 @@
-use crate::{Compressor, KvError};
+use super::read_to_limit;
+use crate::{Compressor, KvError, DEFAULT_LEVEL};
 use bytes::{BufMut, BytesMut};
 use lz4::{Decoder, EncoderBuilder};
-use std::io::{Read, Write};
+use std::io::Write;
 #[derive(Debug)]
 pub struct Lz4 {}
 
 impl Compressor for Lz4 {
-    fn compress(src: &[u8], dst: &mut bytes::BytesMut) -> Result<usize, KvError> {
-        let mut encoder = EncoderBuilder::new().level(4).build(dst.writer())?;
+    fn compress_with_level(src: &[u8], dst: &mut BytesMut, level: u32) -> Result<usize, KvError> {
+        let level = match level {
+            DEFAULT_LEVEL => 4,
+            v => v,
+        };
+        let mut encoder = EncoderBuilder::new().level(level).build(dst.writer())?;
         encoder.write_all(src)?;
         let (_output, result) = encoder.finish();
         result.map_or_else(|e| Err(e.into()), |_| Ok(dst.len()))
     }
 
-    fn decompress(src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError> {
-        let mut decoder = Decoder::new(&src[..])?;
-        decoder.read_to_end(dst)?;
-        Ok(())
+    fn decompress_with_limit(
+        src: &BytesMut,
+        dst: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), KvError> {
+        let decoder = Decoder::new(&src[..])?;
+        read_to_limit(decoder, dst, limit)
     }
 }
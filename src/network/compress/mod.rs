@@ -1,42 +1,110 @@
 mod gzip;
 mod lz4_comp;
 mod zstd_comp;
-use crate::KvError;
+use crate::{CompressionCodec, KvError};
 use bytes::BytesMut;
 pub use gzip::Gzip;
 pub use lz4_comp::Lz4;
+use std::io::Read;
 pub use zstd_comp::Zstd;
 
+pub const NONE: usize = 0;
 pub const GZIP: usize = 1;
 pub const LZ4: usize = 2;
 pub const ZSTD: usize = 3;
 
+/// 当前支持的所有压缩算法
+pub const SUPPORTED_CODECS: [CompressionCodec; 4] = [
+    CompressionCodec::None,
+    CompressionCodec::Gzip,
+    CompressionCodec::Lz4,
+    CompressionCodec::Zstd,
+];
+
+/// 缺省压缩级别，由各个算法自行决定具体数值
+pub const DEFAULT_LEVEL: u32 = 0;
+
 pub trait Compressor {
-    fn compress(src: &[u8], dst: &mut BytesMut) -> Result<usize, KvError>;
-    fn decompress(src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError>;
+    /// 使用缺省级别进行压缩
+    fn compress(src: &[u8], dst: &mut BytesMut) -> Result<usize, KvError> {
+        Self::compress_with_level(src, dst, DEFAULT_LEVEL)
+    }
+    /// 使用指定级别进行压缩，level 为 DEFAULT_LEVEL 时使用算法自身的缺省级别
+    fn compress_with_level(src: &[u8], dst: &mut BytesMut, level: u32) -> Result<usize, KvError>;
+    /// 不限制解压后的大小
+    fn decompress(src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError> {
+        Self::decompress_with_limit(src, dst, usize::MAX)
+    }
+    /// 解压后的数据超过 limit 时返回 KvError::FrameError，避免被压缩炸弹耗尽内存
+    fn decompress_with_limit(
+        src: &BytesMut,
+        dst: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), KvError>;
+}
+
+/// 从 decoder 中最多读出 limit 个字节，超出时返回错误
+fn read_to_limit(decoder: impl Read, dst: &mut Vec<u8>, limit: usize) -> Result<(), KvError> {
+    let start = dst.len();
+    decoder
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(dst)?;
+    if dst.len() - start > limit {
+        dst.truncate(start);
+        return Err(KvError::FrameError);
+    }
+    Ok(())
 }
 
-pub fn compress(comp: usize, src: &Vec<u8>, dst: &mut BytesMut) -> Result<usize, KvError> {
+/// 判断压缩算法是否被支持
+pub fn is_supported(comp: usize) -> bool {
+    matches!(comp, NONE | GZIP | LZ4 | ZSTD)
+}
+
+pub fn compress(comp: usize, src: &[u8], dst: &mut BytesMut) -> Result<usize, KvError> {
+    compress_with_level(comp, DEFAULT_LEVEL, src, dst)
+}
+
+pub fn compress_with_level(
+    comp: usize,
+    level: u32,
+    src: &[u8],
+    dst: &mut BytesMut,
+) -> Result<usize, KvError> {
     match comp {
-        GZIP => Gzip::compress(src, dst),
-        LZ4 => Lz4::compress(src, dst),
-        ZSTD => Zstd::compress(src, dst),
+        GZIP => Gzip::compress_with_level(src, dst, level),
+        LZ4 => Lz4::compress_with_level(src, dst, level),
+        ZSTD => Zstd::compress_with_level(src, dst, level),
         _ => {
-            dst.extend_from_slice(&src[..]);
+            dst.extend_from_slice(src);
             Ok(src.len())
         }
     }
 }
 
 pub fn decompress(comp: usize, src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError> {
+    decompress_with_limit(comp, usize::MAX, src, dst)
+}
+
+/// 解压后的数据最多为 limit 个字节，不认识的压缩算法会返回错误
+pub fn decompress_with_limit(
+    comp: usize,
+    limit: usize,
+    src: &BytesMut,
+    dst: &mut Vec<u8>,
+) -> Result<(), KvError> {
     match comp {
-        GZIP => Gzip::decompress(src, dst),
-        LZ4 => Lz4::decompress(src, dst),
-        ZSTD => Zstd::decompress(src, dst),
-        _ => {
-            dst.extend_from_slice(&src[..]);
+        NONE if src.len() > limit => Err(KvError::FrameError),
+        NONE => {
+            dst.extend_from_slice(src);
             Ok(())
         }
+        GZIP => Gzip::decompress_with_limit(src, dst, limit),
+        LZ4 => Lz4::decompress_with_limit(src, dst, limit),
+        ZSTD => Zstd::decompress_with_limit(src, dst, limit),
+        _ => Err(KvError::InvalidFrame(format!(
+            "unknown compression code {comp}"
+        ))),
     }
 }
 
@@ -104,4 +172,20 @@ mod tests {
         assert_eq!(src, dst_0);
         assert_eq!(len0, src.len());
     }
+
+    #[test]
+    fn compress_with_level_should_work() {
+        let src: Vec<u8> = "Hello World".repeat(64).into();
+        for comp in [GZIP, LZ4, ZSTD] {
+            let mut fast = BytesMut::new();
+            let mut best = BytesMut::new();
+            compress_with_level(comp, 1, &src, &mut fast).unwrap();
+            compress_with_level(comp, 9, &src, &mut best).unwrap();
+
+            let mut dst = Vec::new();
+            decompress(comp, &best, &mut dst).unwrap();
+            assert_eq!(src, dst);
+            assert!(best.len() <= fast.len());
+        }
+    }
 }
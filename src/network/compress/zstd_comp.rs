@@ -1,21 +1,30 @@
-use crate::{Compressor, KvError};
+use super::read_to_limit;
+use crate::{Compressor, KvError, DEFAULT_LEVEL};
 use bytes::{BufMut, BytesMut};
-use std::io::{Read, Write};
+use std::io::Write;
 use zstd::{Decoder, Encoder};
 #[derive(Debug)]
 pub struct Zstd {}
 
 impl Compressor for Zstd {
-    fn compress(src: &[u8], dst: &mut BytesMut) -> Result<usize, KvError> {
-        let mut encoder = Encoder::new(dst.writer(), 1)?;
+    fn compress_with_level(src: &[u8], dst: &mut BytesMut, level: u32) -> Result<usize, KvError> {
+        let level = match level {
+            DEFAULT_LEVEL => 1,
+            // zstd 的压缩级别为 1~22
+            v => v.min(22) as i32,
+        };
+        let mut encoder = Encoder::new(dst.writer(), level)?;
         encoder.write_all(src)?;
         encoder.finish()?;
         Ok(dst.len())
     }
 
-    fn decompress(src: &BytesMut, dst: &mut Vec<u8>) -> Result<(), KvError> {
-        let mut decoder = Decoder::new(&src[..])?;
-        decoder.read_to_end(dst)?;
-        Ok(())
+    fn decompress_with_limit(
+        src: &BytesMut,
+        dst: &mut Vec<u8>,
+        limit: usize,
+    ) -> Result<(), KvError> {
+        let decoder = Decoder::new(&src[..])?;
+        read_to_limit(decoder, dst, limit)
     }
 }
@@ -0,0 +1,341 @@
+use crate::KvError;
+use ring::signature::{self, VerificationAlgorithm};
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::{
+    rustls::{Certificate, ClientCertVerified, ClientCertVerifier, DistinguishedNames, TLSError},
+    webpki::DNSName,
+};
+use tracing::warn;
+use x509_parser::{
+    certificate::X509Certificate, oid_registry::*, pem::Pem,
+    revocation_list::CertificateRevocationList, time::ASN1Time, traits::FromDer,
+};
+
+/// 从 CRL 中加载的已经被吊销的证书，用 (issuer, serial) 标识一个证书
+#[derive(Debug, Default)]
+pub struct Revocations {
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
+}
+
+/// 在 CA 校验的基础上拒绝已经被吊销的客户端证书
+pub(super) struct CrlVerifier {
+    pub inner: Arc<dyn ClientCertVerifier>,
+    pub revocations: Arc<RwLock<Revocations>>,
+}
+
+impl Revocations {
+    /// 加载若干个 PEM 格式的 CRL，每个字符串中可以包含多个 CRL。
+    /// CRL 必须由 issuers 中的某个 CA 签发，thisUpdate 还没有到的 CRL 会被拒绝，
+    /// 过了 nextUpdate 的 CRL 依然生效，但是会打印警告
+    pub fn new(crls: &[impl AsRef<str>], issuers: &[Certificate]) -> Result<Self, KvError> {
+        let issuers = issuers
+            .iter()
+            .map(|cert| match X509Certificate::from_der(&cert.0) {
+                Ok((_, cert)) => Ok(cert),
+                Err(_) => Err(KvError::CertificateParseError("CA", "cert")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut revoked = HashSet::new();
+        for pem in crls {
+            for der in pem_blocks(pem.as_ref())? {
+                let (_, crl) = CertificateRevocationList::from_der(&der)
+                    .map_err(|_| KvError::CertificateParseError("revocation", "list"))?;
+                verify_crl(&crl, &issuers)?;
+                let issuer = crl.issuer().as_raw();
+                revoked.extend(
+                    crl.iter_revoked_certificates()
+                        .map(|revoked| (issuer.to_vec(), revoked.raw_serial().to_vec())),
+                );
+            }
+        }
+        Ok(Self { revoked })
+    }
+
+    /// DER 编码的证书是否已经被吊销，无法解析的证书按照被吊销处理
+    pub fn is_revoked(&self, cert: &[u8]) -> bool {
+        match issuer_and_serial(cert) {
+            Some((issuer, serial)) => self.revoked.contains(&(issuer.to_vec(), serial.to_vec())),
+            None => true,
+        }
+    }
+
+    /// 被吊销的证书数量
+    pub fn len(&self) -> usize {
+        self.revoked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revoked.is_empty()
+    }
+}
+
+impl ClientCertVerifier for CrlVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&DNSName>) -> Option<bool> {
+        self.inner.client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs, sni)?;
+        let revocations = self.revocations.read().unwrap();
+        if let Some(cert) = presented_certs.first() {
+            if !revocations.is_empty() && revocations.is_revoked(&cert.0) {
+                let serial = issuer_and_serial(&cert.0).map(|(_, serial)| to_hex(serial));
+                warn!(
+                    "Rejected revoked client certificate, serial: {}",
+                    serial.as_deref().unwrap_or("unknown")
+                );
+                return Err(TLSError::General("client certificate is revoked".into()));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+/// 取出 PEM 中所有的 CRL 并解码成 DER
+fn pem_blocks(pem: &str) -> Result<Vec<Vec<u8>>, KvError> {
+    let invalid = || KvError::CertificateParseError("revocation", "list");
+    let mut blocks = Vec::new();
+    for block in Pem::iter_from_buffer(pem.as_bytes()) {
+        blocks.push(block.map_err(|_| invalid())?.contents);
+    }
+    if blocks.is_empty() {
+        return Err(invalid());
+    }
+    Ok(blocks)
+}
+
+/// 校验 CRL 的签名是否来自签发它的 CA，以及它是否已经生效
+fn verify_crl(crl: &CertificateRevocationList, issuers: &[X509Certificate]) -> Result<(), KvError> {
+    let issuer = issuers
+        .iter()
+        .find(|ca| ca.subject().as_raw() == crl.issuer().as_raw())
+        .ok_or_else(|| KvError::InvalidCrl(format!("{} is not a trusted CA", crl.issuer())))?;
+    let oid = &crl.signature_algorithm.algorithm;
+    let algorithm = verification_algorithm(oid)
+        .ok_or_else(|| KvError::InvalidCrl(format!("unsupported signature algorithm {oid}")))?;
+    signature::UnparsedPublicKey::new(algorithm, issuer.public_key().subject_public_key.data)
+        .verify(crl.tbs_cert_list.as_ref(), crl.signature_value.data)
+        .map_err(|_| KvError::InvalidCrl(format!("bad signature from {}", crl.issuer())))?;
+
+    let now = ASN1Time::now();
+    if crl.last_update() > now {
+        return Err(KvError::InvalidCrl(format!(
+            "CRL from {} is not valid until {}",
+            crl.issuer(),
+            crl.last_update().to_rfc2822()
+        )));
+    }
+    // 过期的 CRL 中吊销的证书依然不可信，因此继续使用它，等待管理员更新
+    if let Some(next_update) = crl.next_update().filter(|t| *t < now) {
+        warn!(
+            "CRL from {} is expired since {}",
+            crl.issuer(),
+            next_update.to_rfc2822()
+        );
+    }
+    Ok(())
+}
+
+/// 签名算法对应的 ring 校验算法，不接受 SHA1 之类不安全的算法
+fn verification_algorithm(oid: &Oid) -> Option<&'static dyn VerificationAlgorithm> {
+    let algorithm: &'static dyn VerificationAlgorithm = match oid {
+        oid if *oid == OID_PKCS1_SHA256WITHRSA => &signature::RSA_PKCS1_2048_8192_SHA256,
+        oid if *oid == OID_PKCS1_SHA384WITHRSA => &signature::RSA_PKCS1_2048_8192_SHA384,
+        oid if *oid == OID_PKCS1_SHA512WITHRSA => &signature::RSA_PKCS1_2048_8192_SHA512,
+        oid if *oid == OID_SIG_ECDSA_WITH_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        oid if *oid == OID_SIG_ECDSA_WITH_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        oid if *oid == OID_SIG_ED25519 => &signature::ED25519,
+        _ => return None,
+    };
+    Some(algorithm)
+}
+
+/// 从 DER 编码的证书中取出 issuer 和 serial
+fn issuer_and_serial(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let tbs = cert.tbs_certificate;
+    Some((tbs.issuer.as_raw(), tbs.raw_serial()))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tls::{
+        load_certs,
+        tls_utils::{tls_acceptor, tls_connector},
+    };
+    use anyhow::Result;
+    use ring::signature::Ed25519KeyPair;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::rustls::internal::pemfile;
+
+    /// 编码一个 DER 元素
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut buf = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => buf.push(len as u8),
+            len => {
+                buf.extend([0x82, (len >> 8) as u8, len as u8]);
+            }
+        }
+        buf.extend_from_slice(content);
+        buf
+    }
+
+    /// 生成一个吊销了 serials 的 CRL，用测试 CA 的私钥签名
+    fn crl(issuer: &[u8], serials: &[&[u8]]) -> String {
+        signed_crl(issuer, serials, b"221019000000Z", sign)
+    }
+
+    /// 用测试 CA 的 ed25519 私钥签名
+    fn sign(data: &[u8]) -> Vec<u8> {
+        let mut key = include_str!("../../fixtures/ca.key").as_bytes();
+        let key = pemfile::pkcs8_private_keys(&mut key).unwrap().remove(0);
+        let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&key.0).unwrap();
+        key.sign(data).as_ref().to_vec()
+    }
+
+    fn signed_crl(
+        issuer: &[u8],
+        serials: &[&[u8]],
+        this_update: &[u8],
+        sign: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> String {
+        // ed25519 的 OID 1.3.101.112
+        let algorithm = der(0x30, &der(0x06, &[0x2b, 0x65, 0x70]));
+        let time = der(0x17, this_update);
+        let entries: Vec<u8> = serials
+            .iter()
+            .flat_map(|serial| der(0x30, &[der(0x02, serial), time.clone()].concat()))
+            .collect();
+        let tbs = [
+            der(0x02, &[1]),
+            algorithm.clone(),
+            issuer.to_vec(),
+            time.clone(),
+            time,
+            der(0x30, &entries),
+        ]
+        .concat();
+        let tbs = der(0x30, &tbs);
+        let signature = der(0x03, &[&[0][..], &sign(&tbs)].concat());
+        let crl = der(0x30, &[tbs, algorithm, signature].concat());
+        format!(
+            "-----BEGIN X509 CRL-----\n{}\n-----END X509 CRL-----\n",
+            base64::encode(crl)
+        )
+    }
+
+    fn ca() -> Vec<Certificate> {
+        load_certs(include_str!("../../fixtures/ca.cert")).unwrap()
+    }
+
+    #[test]
+    fn revoked_certificate_should_be_detected() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        let cert = &certs[0].0;
+        let (issuer, serial) = issuer_and_serial(cert).unwrap();
+
+        let revocations = Revocations::new(&[crl(issuer, &[serial])], &ca()).unwrap();
+        assert_eq!(revocations.len(), 1);
+        assert!(revocations.is_revoked(cert));
+
+        let revocations = Revocations::new(&[crl(issuer, &[&[1, 2, 3]])], &ca()).unwrap();
+        assert!(!revocations.is_revoked(cert));
+    }
+
+    #[test]
+    fn invalid_crl_should_be_rejected() {
+        assert!(Revocations::new(&["not a crl"], &ca()).is_err());
+        let pem = "-----BEGIN X509 CRL-----\nAAAA\n-----END X509 CRL-----\n";
+        assert!(Revocations::new(&[pem], &ca()).is_err());
+    }
+
+    #[test]
+    fn untrusted_crl_should_be_rejected() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        let (issuer, serial) = issuer_and_serial(&certs[0].0).unwrap();
+
+        // 没有配置 CA，或者 issuer 不是配置的 CA
+        let res = Revocations::new(&[crl(issuer, &[serial])], &[]);
+        assert!(matches!(res, Err(KvError::InvalidCrl(_))));
+        let res = Revocations::new(&[crl(&der(0x30, &[]), &[serial])], &ca());
+        assert!(matches!(res, Err(KvError::InvalidCrl(_))));
+
+        // 签名不正确
+        let pem = signed_crl(issuer, &[serial], b"221019000000Z", |_| vec![0; 64]);
+        let res = Revocations::new(&[pem], &ca());
+        assert!(matches!(res, Err(KvError::InvalidCrl(msg)) if msg.contains("signature")));
+    }
+
+    #[test]
+    fn crl_should_be_valid_now() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        let (issuer, serial) = issuer_and_serial(&certs[0].0).unwrap();
+
+        // thisUpdate 在未来的 CRL 不能使用，过了 nextUpdate 的 CRL 只会打印警告
+        let pem = signed_crl(issuer, &[serial], b"491019000000Z", sign);
+        let res = Revocations::new(&[pem], &ca());
+        assert!(matches!(res, Err(KvError::InvalidCrl(msg)) if msg.contains("not valid until")));
+    }
+
+    #[tokio::test]
+    async fn revoked_client_should_be_rejected() -> Result<()> {
+        let certs = load_certs(include_str!("../../fixtures/client.cert"))?;
+        let (issuer, serial) = issuer_and_serial(&certs[0].0).unwrap();
+        let acceptor = tls_acceptor(true)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = acceptor.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = server.accept(stream).await {
+                    let mut buf = [0; 12];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&buf).await;
+                    }
+                }
+            }
+        });
+        let echo = || async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = tls_connector(true)?.connect(stream).await?;
+            stream.write_all(b"hello world!").await?;
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await?;
+            anyhow::Ok(buf)
+        };
+        assert_eq!(&echo().await?, b"hello world!");
+
+        acceptor.reload_crls(&[crl(issuer, &[serial])])?;
+        assert!(echo().await.is_err());
+
+        // CRL 在运行时更新之后，没有被吊销的证书可以重新连接
+        acceptor.reload_crls(&[crl(issuer, &[&[1, 2, 3]])])?;
+        assert_eq!(&echo().await?, b"hello world!");
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use crate::KvError;
+use bytes::{Buf, BytesMut};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// 判断协议时需要读取的字节数：frame header 的长度加上 payload 的第一个字节
+const PEEK_LEN: usize = 5;
+
+/// Hello 第一个字段 version 的 tag，version 不能为 0，因此 encode 后一定出现在 payload 的开头
+const HELLO_TAG: u8 = 0x08;
+
+/// 连接上使用的协议
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// 直接在连接上收发 frame
+    Plain,
+    /// 在连接上使用 yamux 多路复用
+    Yamux,
+}
+
+/// 根据连接上的第一个 frame 判断对端使用的协议。
+/// plain 连接的第一个 frame 是 Hello，payload 的第一个字节是 HELLO_TAG；
+/// yamux 的第一个 frame 的 4..8 字节是 stream id，客户端打开的第一个 stream id 很小，第一个字节为 0。
+/// 返回的 stream 会重新读到已经读取的数据
+pub async fn detect<S>(mut stream: S) -> Result<(Protocol, Peeked<S>), KvError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(PEEK_LEN);
+    while buf.len() < PEEK_LEN {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(KvError::InvalidFrame("connection closed".into()));
+        }
+    }
+    let protocol = match buf[4] {
+        HELLO_TAG => Protocol::Plain,
+        _ => Protocol::Yamux,
+    };
+    Ok((protocol, Peeked { buf, inner: stream }))
+}
+
+/// 先返回判断协议时读取的数据，再从底层的 stream 中读取
+pub struct Peeked<S> {
+    buf: BytesMut,
+    inner: S,
+}
+
+impl<S> AsyncRead for Peeked<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buf.has_remaining() {
+            let len = this.buf.len().min(buf.remaining());
+            buf.put_slice(&this.buf[..len]);
+            this.buf.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for Peeked<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client_handshake, CompressionConfig, YamuxCtrl};
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn plain_connection_should_be_detected() -> anyhow::Result<()> {
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(async move {
+            let _ = client_handshake(&mut client, &CompressionConfig::default()).await;
+        });
+
+        let (protocol, mut stream) = detect(server).await?;
+        assert_eq!(protocol, Protocol::Plain);
+
+        // 已经读取的数据不会丢失
+        let mut header = [0u8; PEEK_LEN];
+        stream.read_exact(&mut header).await?;
+        assert_eq!(header[4], HELLO_TAG);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_connection_should_be_detected() -> anyhow::Result<()> {
+        let (client, server) = duplex(4096);
+        let ctrl = YamuxCtrl::new_client(client, None);
+        tokio::spawn(async move {
+            if let Ok(mut stream) = ctrl.open_stream().await {
+                let _ = stream.hello().await;
+            }
+        });
+
+        let (protocol, _) = detect(server).await?;
+        assert_eq!(protocol, Protocol::Yamux);
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{compress, decompress, CommandRequest, CommandResponse, KvError, GZIP};
+use crate::{
+    compress_with_level, decompress_with_limit, CommandRequest, CommandResponse, CompressionConfig,
+    Hello, KvError, NONE,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use prost::Message;
 use tokio::io::{AsyncRead, AsyncReadExt};
@@ -8,46 +11,70 @@ use tracing::debug;
 pub const LEN_LEN: usize = 4;
 
 /// 最高 2 位 表示是否压缩，剩余 30 bit 表示长度，因此 frame 最大为 1G
-const MAX_FRAME: usize = 1024 * 1024;
+pub const MAX_FRAME: usize = 1024 * 1024;
+/// 30 bit 的长度能够表示的最大 frame，配置的 max_frame_size 不能超过该值
+pub const MAX_FRAME_LIMIT: usize = (1 << COMPRESSION_BIT) - 1;
 const COMPRESSION_BIT: usize = 30;
 const COMPRESSION_MASK: usize = 3 << 30;
 
 /// 如果 payload 超过了 1436，则进行压缩，这样可以避免分片
-const COMPRESSION_LIMIT: usize = 1436;
+pub const COMPRESSION_LIMIT: usize = 1436;
 
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
+    /// 按照 compression 中的算法、级别和阈值把 Message encode 成一个 frame
     fn encode_frame_with_compressor(
         &self,
         buf: &mut BytesMut,
-        compressor_type: usize,
+        compression: &CompressionConfig,
+    ) -> Result<(), KvError> {
+        self.encode_frame_with_limit(buf, compression, MAX_FRAME)
+    }
+
+    /// 同 encode_frame_with_compressor，frame 超过 limit（通常是对端在握手时给出的大小）时
+    /// 返回 KvError::FrameError，buf 保持不变
+    fn encode_frame_with_limit(
+        &self,
+        buf: &mut BytesMut,
+        compression: &CompressionConfig,
+        limit: usize,
     ) -> Result<(), KvError> {
         let size = self.encoded_len();
-        if size > MAX_FRAME {
+        // 对端解压后的数据同样受限，因此未压缩的大小也不能超过 limit
+        if size > limit.min(MAX_FRAME_LIMIT) {
             return Err(KvError::FrameError);
         }
 
+        let codec = compression.codec.bits();
+        // buf 中可能已经有尚未发送的 frame，因此需要记录下本 frame 的起始位置
+        let start = buf.len();
+
         // step 1: 将长度信息写入头部
         buf.put_u32(size as _);
         // step 2: 判断是否需要进行压缩
-        if size > COMPRESSION_LIMIT {
+        if codec != NONE && size > compression.threshold {
             // step 2.1: 现将数据 encode 到 buf_tmp 中暂存起来，等待压缩
             let mut buf_tmp = Vec::with_capacity(size);
             self.encode(&mut buf_tmp)?;
 
             // step 2.2: 跳过开头的长度信息
-            let mut payload = buf.split_off(LEN_LEN);
-            buf.clear();
+            let mut payload = buf.split_off(start + LEN_LEN);
 
             // step 2.3: 进行压缩
-            let len = compress(compressor_type, &buf_tmp, &mut payload)?;
+            let len = compress_with_level(codec, compression.level, &buf_tmp, &mut payload)?;
             debug!("Encode a frame size: {}({})", size, len);
-            // step 2.5: 写入压缩后的长度
-            buf.put_u32((len | (GZIP << COMPRESSION_BIT)) as _);
+            if len > limit {
+                buf.truncate(start);
+                return Err(KvError::FrameError);
+            }
+
+            // step 2.4: 写入压缩后的长度以及实际使用的压缩算法
+            let header = (len | (codec << COMPRESSION_BIT)) as u32;
+            buf[start..].copy_from_slice(&header.to_be_bytes());
 
-            // step 2.6: 把 BytesMut 合并回来
+            // step 2.5: 把 BytesMut 合并回来
             buf.unsplit(payload);
             Ok(())
         } else {
@@ -58,26 +85,50 @@ where
 
     /// 把一个 Message encode 成一个 frame, Message 可以是 CommandRequest
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
-        self.encode_frame_with_compressor(buf, GZIP)
+        self.encode_frame_with_compressor(buf, &CompressionConfig::default())
     }
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with_limit(buf, MAX_FRAME)
+    }
+
+    /// 把一个完整的 frame decode 成一个 Message，frame 以及解压后的数据都不能超过 limit
+    fn decode_frame_with_limit(buf: &mut BytesMut, limit: usize) -> Result<Self, KvError> {
+        if buf.len() < LEN_LEN {
+            return Err(KvError::InvalidFrame("incomplete frame header".into()));
+        }
         // step 1: 读取 header，并取出相关的值(注意：get_u32 后，position 会向前步进 4)
         let header = buf.get_u32() as usize;
         let (len, compressed) = decode_header(header);
         debug!("Got a frame: msg len {}, compressed {}", len, compressed);
 
-        let mut buf_tmp = Vec::with_capacity(len * 2);
+        // step 2: 对端给出的长度不可信，需要先检查再分配内存
+        if len > limit {
+            return Err(KvError::FrameError);
+        }
+        if buf.len() < len {
+            return Err(KvError::InvalidFrame(format!(
+                "expect {} bytes, got {}",
+                len,
+                buf.len()
+            )));
+        }
+
         let src = buf.split_to(len);
-        decompress(compressed, &src, &mut buf_tmp)?;
+        if compressed == NONE {
+            return Ok(Self::decode(src)?);
+        }
+        let mut buf_tmp = Vec::with_capacity((len * 2).min(limit));
+        decompress_with_limit(compressed, limit, &src, &mut buf_tmp)?;
 
-        Ok(Self::decode(&buf_tmp[..buf_tmp.len()])?)
+        Ok(Self::decode(&buf_tmp[..])?)
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for Hello {}
 
 fn decode_header(header: usize) -> (usize, usize) {
     let len = header & !COMPRESSION_MASK;
@@ -85,27 +136,51 @@ fn decode_header(header: usize) -> (usize, usize) {
     (len, compressed)
 }
 
+/// 根据 buf 中的 header 计算出整个 frame（包括 header）的长度，header 不完整时返回 None
+pub(crate) fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < LEN_LEN {
+        return None;
+    }
+    let (len, _compressed) = decode_header((&buf[..LEN_LEN]).get_u32() as usize);
+    Some(LEN_LEN + len)
+}
+
 /// 从 stream 中读出一个完整的 frame
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    read_frame_with_limit(stream, buf, MAX_FRAME).await
+}
+
+/// 从 stream 中读出一个完整的 frame，frame 超过 limit 时返回 KvError::FrameError
+pub async fn read_frame_with_limit<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    limit: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
     let (len, _compressed) = decode_header(header);
+    if len > limit {
+        return Err(KvError::FrameError);
+    }
 
-    // 先分配至少一个 frame 的内存
-    buf.reserve(LEN_LEN + len);
+    // 先分配一个 frame 的内存，并用 0 填充，再从 stream 中读满
+    let start = buf.len();
     buf.put_u32(header as _);
-    unsafe { buf.advance_mut(len) };
-    stream.read_exact(&mut buf[LEN_LEN..]).await?;
+    buf.resize(start + LEN_LEN + len, 0);
+    stream.read_exact(&mut buf[start + LEN_LEN..]).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Value;
     use crate::{utils::DummyStream, CommandRequest};
+    use crate::{CompressionCodec, Value, LZ4, ZSTD};
     use bytes::Bytes;
 
     #[tokio::test]
@@ -160,6 +235,137 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn frame_header_should_carry_codec_bits() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        for (codec, bits) in [(CompressionCodec::Lz4, LZ4), (CompressionCodec::Zstd, ZSTD)] {
+            let mut buf = BytesMut::new();
+            let compression = CompressionConfig {
+                codec,
+                ..Default::default()
+            };
+            res.encode_frame_with_compressor(&mut buf, &compression)
+                .unwrap();
+
+            let (_, compressed) = decode_header((&buf[..LEN_LEN]).get_u32() as usize);
+            assert_eq!(compressed, bits);
+
+            let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+            assert_eq!(res, res1);
+        }
+    }
+
+    #[test]
+    fn compression_threshold_should_be_respected() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        let compression = CompressionConfig {
+            threshold: 8192,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        res.encode_frame_with_compressor(&mut buf, &compression)
+            .unwrap();
+        assert!(!is_compressed(&buf));
+
+        let mut buf = BytesMut::new();
+        res.encode_frame_with_compressor(&mut buf, &CompressionConfig::none())
+            .unwrap();
+        assert!(!is_compressed(&buf));
+    }
+
+    #[test]
+    fn multiple_compressed_frames_in_one_buffer_should_work() {
+        let value: Value = Bytes::from(vec![0u8; 4096]).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame(&mut buf).unwrap();
+        res.encode_frame(&mut buf).unwrap();
+
+        let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+        let res2 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(res, res1);
+        assert_eq!(res, res2);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_frame() {
+        let mut buf = BytesMut::new();
+        // 声称有 1G 的数据，实际上什么都没有
+        buf.put_u32((MAX_FRAME * 1000) as _);
+        let mut stream = DummyStream { buf };
+        let mut data = BytesMut::new();
+        let res = read_frame(&mut stream, &mut data).await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+        assert!(data.capacity() < MAX_FRAME);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let mut buf = BytesMut::new();
+        cmd.encode_frame(&mut buf).unwrap();
+        let mut stream = DummyStream { buf };
+        let res = read_frame_with_limit(&mut stream, &mut data, 4).await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn encode_frame_should_respect_limit() {
+        let cmd = CommandRequest::new_hset("t1", "k1", Bytes::from(vec![0u8; 4096]).into());
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hget("t1", "k1")
+            .encode_frame(&mut buf)
+            .unwrap();
+        let len = buf.len();
+
+        // 压缩前后超过 limit 都会被拒绝，已经在 buf 中的 frame 不受影响
+        let none = CompressionConfig::none();
+        let res = cmd.encode_frame_with_limit(&mut buf, &none, 1024);
+        assert!(matches!(res, Err(KvError::FrameError)));
+        let res = cmd.encode_frame_with_limit(&mut buf, &CompressionConfig::default(), 1024);
+        assert!(matches!(res, Err(KvError::FrameError)));
+        assert_eq!(buf.len(), len);
+
+        cmd.encode_frame_with_limit(&mut buf, &none, 8192).unwrap();
+        let _ = CommandRequest::decode_frame(&mut buf).unwrap();
+        assert_eq!(CommandRequest::decode_frame(&mut buf).unwrap(), cmd);
+    }
+
+    #[test]
+    fn decode_frame_should_reject_malformed_frame() {
+        let mut buf = BytesMut::from(&[0u8, 0][..]);
+        let res = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(res, Err(KvError::InvalidFrame(_))));
+
+        // header 中的长度超过了实际的数据
+        let mut buf = BytesMut::new();
+        buf.put_u32(100);
+        buf.put_slice(b"short");
+        let res = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(res, Err(KvError::InvalidFrame(_))));
+
+        let mut buf = BytesMut::new();
+        buf.put_u32((MAX_FRAME + 1) as _);
+        let res = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(res, Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn decode_frame_should_reject_decompression_bomb() {
+        // 16M 的 0 压缩后只有十几 K，解压后远远超过 limit
+        let value: Value = Bytes::from(vec![0u8; 16 * 1024 * 1024]).into();
+        let res: CommandResponse = value.into();
+        let mut payload = BytesMut::new();
+        let len = compress_with_level(ZSTD, 0, &res.encode_to_vec(), &mut payload).unwrap();
+        assert!(len < MAX_FRAME);
+
+        let mut buf = BytesMut::new();
+        buf.put_u32((len | (ZSTD << COMPRESSION_BIT)) as _);
+        buf.unsplit(payload);
+        let res = CommandResponse::decode_frame(&mut buf);
+        assert!(matches!(res, Err(KvError::FrameError)));
+    }
+
     fn is_compressed(buf: &BytesMut) -> bool {
         if let &[v] = &buf[..1] {
             (v >> 6) != 0
@@ -0,0 +1,243 @@
+use crate::{
+    read_frame, CompressionCodec, CompressionConfig, FrameCoder, Hello, KvError, COMMANDS,
+    MAX_FRAME, PROTOCOL_VERSION,
+};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, instrument, warn};
+
+/// 服务端标识
+pub const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// 客户端发起握手：发送自己的版本、能力以及期望的压缩配置，返回服务端的回复
+pub async fn client_handshake<S>(
+    stream: &mut S,
+    compression: &CompressionConfig,
+) -> Result<Hello, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    client_handshake_with_limit(stream, compression, MAX_FRAME).await
+}
+
+/// 同 client_handshake，并告知服务端客户端能够接收的最大 frame
+#[instrument(name = "client_handshake", skip_all)]
+pub async fn client_handshake_with_limit<S>(
+    stream: &mut S,
+    compression: &CompressionConfig,
+    max_frame: usize,
+) -> Result<Hello, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let hello = Hello {
+        max_frame_size: max_frame as _,
+        ..Hello::new(compression)
+    };
+    write_message(stream, &hello).await?;
+    let reply: Hello = read_message(stream).await?;
+    if reply.version == 0 || reply.version > PROTOCOL_VERSION {
+        return Err(KvError::HandshakeError(format!(
+            "unsupported protocol version {}",
+            reply.version
+        )));
+    }
+    debug!("Got server hello: {:?}", reply);
+    Ok(reply)
+}
+
+/// 服务端响应握手：根据客户端的 Hello 以及自身的配置选出双方共同使用的版本和压缩配置，
+/// 返回发送给客户端的回复
+pub async fn server_handshake<S>(
+    stream: &mut S,
+    compression: &CompressionConfig,
+) -> Result<Hello, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (_offer, reply) = server_handshake_with_limit(stream, compression, MAX_FRAME).await?;
+    Ok(reply)
+}
+
+/// 同 server_handshake，并告知客户端服务端能够接收的最大 frame，返回客户端的 Hello 以及回复
+#[instrument(name = "server_handshake", skip_all)]
+pub async fn server_handshake_with_limit<S>(
+    stream: &mut S,
+    compression: &CompressionConfig,
+    max_frame: usize,
+) -> Result<(Hello, Hello), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let offer: Hello = read_message(stream).await?;
+    debug!("Got client hello: {:?}", offer);
+    if offer.version == 0 {
+        warn!("Client hello without protocol version: {:?}", offer);
+        return Err(KvError::HandshakeError("missing protocol version".into()));
+    }
+
+    let reply = Hello {
+        max_frame_size: max_frame as _,
+        ..negotiate(compression, &offer)
+    };
+    write_message(stream, &reply).await?;
+    Ok((offer, reply))
+}
+
+/// 版本取双方的较小值。压缩配置：任何一方不压缩（或客户端只给出了不认识的算法）时都不压缩；
+/// 否则客户端支持服务端的算法时以服务端的算法和级别为准，不支持时使用客户端的首选算法，
+/// 阈值取双方的较大值
+fn negotiate(local: &CompressionConfig, offer: &Hello) -> Hello {
+    let codecs: Vec<_> = offer
+        .codecs
+        .iter()
+        .filter_map(|c| CompressionCodec::from_bits(*c as _))
+        .collect();
+    let threshold = local.threshold.max(offer.threshold as _);
+
+    let compression = match codecs.first() {
+        None | Some(CompressionCodec::None) => CompressionConfig::none(),
+        Some(_) if local.codec == CompressionCodec::None => CompressionConfig::none(),
+        Some(_) if codecs.contains(&local.codec) => CompressionConfig {
+            threshold,
+            ..local.clone()
+        },
+        Some(preferred) => CompressionConfig {
+            codec: *preferred,
+            level: offer.level,
+            threshold,
+        },
+    };
+
+    Hello {
+        version: offer.version.min(PROTOCOL_VERSION),
+        commands: COMMANDS.iter().map(|c| c.to_string()).collect(),
+        codecs: vec![compression.codec.bits() as _],
+        level: compression.level,
+        threshold: compression.threshold as _,
+        max_frame_size: MAX_FRAME as _,
+        server: SERVER_NAME.into(),
+    }
+}
+
+/// 握手消息本身不做压缩
+async fn write_message<S, M>(stream: &mut S, msg: &M) -> Result<(), KvError>
+where
+    S: AsyncWrite + Unpin,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    msg.encode_frame_with_compressor(&mut buf, &CompressionConfig::none())?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_message<S, M>(stream: &mut S) -> Result<M, KvError>
+where
+    S: AsyncRead + Unpin + Send,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    read_frame(stream, &mut buf).await?;
+    M::decode_frame(&mut buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::convert::TryFrom;
+    use tokio::io::duplex;
+
+    fn config(codec: CompressionCodec, level: u32, threshold: usize) -> CompressionConfig {
+        CompressionConfig {
+            codec,
+            level,
+            threshold,
+        }
+    }
+
+    fn agreed(hello: &Hello) -> CompressionConfig {
+        CompressionConfig::try_from(hello).unwrap()
+    }
+
+    #[test]
+    fn negotiate_should_prefer_server_codec() {
+        let server = config(CompressionCodec::Zstd, 3, 1024);
+        let offer = Hello::new(&config(CompressionCodec::Lz4, 1, 4096));
+        let reply = negotiate(&server, &offer);
+        assert_eq!(agreed(&reply), config(CompressionCodec::Zstd, 3, 4096));
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert_eq!(reply.server, SERVER_NAME);
+    }
+
+    #[test]
+    fn negotiate_should_fallback_to_client_codec() {
+        let server = config(CompressionCodec::Zstd, 3, 1024);
+        let offer = Hello {
+            codecs: vec![CompressionCodec::Lz4.bits() as _],
+            ..Hello::new(&config(CompressionCodec::Lz4, 1, 512))
+        };
+        assert_eq!(
+            agreed(&negotiate(&server, &offer)),
+            config(CompressionCodec::Lz4, 1, 1024)
+        );
+    }
+
+    #[test]
+    fn negotiate_should_disable_compression_if_either_side_disabled() {
+        let server = config(CompressionCodec::Zstd, 3, 1024);
+        let offer = Hello::new(&CompressionConfig::none());
+        assert_eq!(
+            agreed(&negotiate(&server, &offer)).codec,
+            CompressionCodec::None
+        );
+
+        let offer = Hello::new(&config(CompressionCodec::Lz4, 1, 4096));
+        assert_eq!(
+            agreed(&negotiate(&CompressionConfig::none(), &offer)).codec,
+            CompressionCodec::None
+        );
+
+        let offer = Hello {
+            codecs: vec![42],
+            ..Hello::new(&CompressionConfig::default())
+        };
+        assert_eq!(
+            agreed(&negotiate(&server, &offer)).codec,
+            CompressionCodec::None
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_should_work() -> Result<()> {
+        let (mut client, mut server) = duplex(1024);
+        let server_config = config(CompressionCodec::Lz4, 0, 2048);
+        let handle =
+            tokio::spawn(async move { server_handshake(&mut server, &server_config).await });
+
+        let reply = client_handshake(&mut client, &CompressionConfig::default()).await?;
+        assert_eq!(agreed(&reply), config(CompressionCodec::Lz4, 0, 2048));
+        assert!(reply.supports("hget"));
+        assert!(!reply.supports("unknown"));
+        assert_eq!(handle.await??, reply);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_without_version_should_fail() -> Result<()> {
+        let (mut client, mut server) = duplex(1024);
+        let handle = tokio::spawn(async move {
+            server_handshake(&mut server, &CompressionConfig::default()).await
+        });
+
+        let hello = Hello {
+            version: 0,
+            ..Hello::new(&CompressionConfig::default())
+        };
+        write_message(&mut client, &hello).await?;
+        assert!(matches!(handle.await?, Err(KvError::HandshakeError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+
+/// 记录连接上最后一次读到数据的时间，用于发现已经失联的对端
+pub struct IdleStream<S> {
+    inner: S,
+    activity: Activity,
+}
+
+/// 连接的活跃情况，可以在其它的任务中查询
+#[derive(Clone, Debug)]
+pub struct Activity {
+    start: Instant,
+    /// 最后一次读到数据时距离 start 的毫秒数
+    last_read: Arc<AtomicU64>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_read: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_read.store(now, Ordering::Relaxed);
+    }
+
+    /// 距离最后一次读到数据经过的时间
+    pub fn idle(&self) -> Duration {
+        let last_read = Duration::from_millis(self.last_read.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last_read)
+    }
+
+    /// 等到连接空闲的时间达到 timeout 时返回
+    pub async fn wait_idle(&self, timeout: Duration) {
+        loop {
+            let idle = self.idle();
+            if idle >= timeout {
+                return;
+            }
+            time::sleep(timeout - idle).await;
+        }
+    }
+}
+
+impl<S> IdleStream<S> {
+    pub fn new(inner: S) -> (Self, Activity) {
+        let activity = Activity::new();
+        let stream = Self {
+            inner,
+            activity: activity.clone(),
+        };
+        (stream, activity)
+    }
+}
+
+impl<S> AsyncRead for IdleStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.activity.touch();
+        }
+        res
+    }
+}
+
+impl<S> AsyncWrite for IdleStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn read_should_reset_idle_time() {
+        let (client, mut server) = duplex(64);
+        let (mut stream, activity) = IdleStream::new(client);
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(activity.idle() >= Duration::from_millis(50));
+
+        // 写数据不算活跃
+        stream.write_all(b"ping").await.unwrap();
+        assert!(activity.idle() >= Duration::from_millis(50));
+
+        server.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(activity.idle() < Duration::from_millis(50));
+    }
+}
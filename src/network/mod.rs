@@ -1,29 +1,83 @@
 mod compress;
+mod crl;
+mod detect;
 mod frame;
+mod handshake;
+mod idle;
 mod multiplex;
+mod pipeline;
+mod sni;
 mod stream;
 mod stream_result;
 mod tls;
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
+use crate::{
+    command_request::RequestData, from_box_error, value, BoxCommandService, Cancel, CommandRequest,
+    CommandResponse, CompressionConfig, CredentialConfig, Hello, KvError, Pending, Service,
+    Session, SessionService, Storage, StreamingResponse, CHUNK_SIZE,
+};
+use bytes::Bytes;
 pub use compress::*;
-pub use frame::{read_frame, FrameCoder};
-use futures::{SinkExt, StreamExt};
+pub use crl::Revocations;
+pub use detect::{detect, Peeked, Protocol};
+pub use frame::{
+    read_frame, read_frame_with_limit, FrameCoder, COMPRESSION_LIMIT, MAX_FRAME, MAX_FRAME_LIMIT,
+};
+use futures::{
+    future::{self, AbortHandle, Abortable},
+    SinkExt, StreamExt,
+};
+pub use handshake::{
+    client_handshake, client_handshake_with_limit, server_handshake, server_handshake_with_limit,
+    SERVER_NAME,
+};
+pub(crate) use idle::IdleStream;
 pub use multiplex::YamuxCtrl;
+pub use pipeline::PipelineClient;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 pub use stream::ProstStream;
 pub use stream_result::StreamResult;
-pub use tls::{TlsClientConnector, TlsServerAcceptor};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+pub use tls::{
+    peer_common_name, peer_identity, PeerIdentity, TlsClientConnector, TlsServerAcceptor,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+    time,
+};
+use tower::{util::BoxCloneService, BoxError, Layer, ServiceExt};
+use tracing::{debug, info, warn};
+
+/// 每个 stream 上等待写回的最大响应数量
+const RESPONSE_CAPACITY: usize = 128;
 
 /// 处理服务端某个 accept 下的 socket 读写
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    compression: CompressionConfig,
+    max_frame: usize,
+    /// 服务器关闭的信号，收到后不再读取新的请求，等正在执行的请求结束后退出
+    shutdown: Option<watch::Receiver<bool>>,
+    /// 这个 stream 的上下文，包含客户端的标识和身份，用于限流、鉴权以及 Hook
+    session: Session,
+    /// 包装请求执行的 tower 中间件
+    layer: Option<Arc<WrapService<Store>>>,
 }
 
+type WrapService<Store> = dyn Fn(SessionService<Store>) -> BoxCommandService + Send + Sync;
+
 /// 处理 Client socket 的读写
 pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
+    compression: CompressionConfig,
+    max_frame: usize,
+    /// 握手时服务端的回复，包含了服务端的版本和能力
+    server: Option<Hello>,
+    /// 请求的默认超时时间
+    timeout: Option<Duration>,
+    /// 握手之后用于认证的用户名和密码
+    credentials: Option<CredentialConfig>,
+    next_id: u32,
 }
 
 impl<S, Store> ProstServerStream<S, Store>
@@ -35,23 +89,331 @@ where
         Self {
             inner: ProstStream::new(stream),
             service,
+            compression: CompressionConfig::default(),
+            max_frame: MAX_FRAME,
+            shutdown: None,
+            session: Session::default(),
+            layer: None,
         }
     }
 
+    /// 设置客户端的标识，比如证书的 CN 或者 IP 地址，没有设置时所有的客户端共享同一个限流配额
+    pub fn with_client(mut self, client: impl Into<String>) -> Self {
+        self.session = self.session.with_client(client);
+        self
+    }
+
+    /// 设置客户端证书中的身份，没有设置时按照没有证书的客户端鉴权
+    pub fn with_identity(mut self, identity: Option<Arc<PeerIdentity>>) -> Self {
+        self.session = self.session.with_identity(identity);
+        self
+    }
+
+    /// 设置客户端的地址，Hook 可以通过 session 拿到
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.session = self.session.with_addr(addr);
+        self
+    }
+
+    /// 设置 stream 的 namespace，stream 上的请求只能访问 namespace 下的 table
+    pub fn with_namespace(mut self, namespace: Option<&str>) -> Self {
+        if let Some(namespace) = namespace {
+            self.session = self.session.with_namespace(namespace);
+        }
+        self
+    }
+
+    /// 用 tower 的 Layer 包装请求的执行，比如超时、并发限制和 load shedding。
+    /// 认证、鉴权和限流在 layer 之前完成。每个 stream 会单独包装一次，
+    /// 需要在 stream 之间共享状态的中间件（比如全局的并发限制）要使用共享状态的版本
+    pub fn with_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<SessionService<Store>> + Send + Sync + 'static,
+        L::Service:
+            tower::Service<CommandRequest, Response = StreamingResponse> + Clone + Send + 'static,
+        <L::Service as tower::Service<CommandRequest>>::Error: Into<BoxError>,
+        <L::Service as tower::Service<CommandRequest>>::Future: Send + 'static,
+    {
+        self.layer = Some(Arc::new(move |svc| {
+            let svc = layer.layer(svc).map_err(|e| from_box_error(e.into()));
+            BoxCloneService::new(svc)
+        }));
+        self
+    }
+
+    /// 设置服务器关闭的信号，值变为 true 时开始关闭
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// 设置能够接收的最大 frame，超过该值的请求会导致 stream 被关闭
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        // 握手时告知对端的大小同样不能超过 30 bit 的长度
+        let max_frame = max_frame.min(MAX_FRAME_LIMIT);
+        self.inner.set_max_frame(max_frame);
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// 设置服务端期望的压缩配置，实际使用的配置由握手协商得出
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 完成握手之后对第一个请求返回 err 并关闭 stream，用于告知客户端服务器无法处理这个连接，
+    /// 比如连接数超过了上限
+    pub async fn reject(mut self, err: KvError) -> Result<(), KvError> {
+        let (compression, max_frame) = (&self.compression, self.max_frame);
+        let (offer, hello) =
+            server_handshake_with_limit(self.inner.get_mut(), compression, max_frame).await?;
+        self.inner.set_compression((&hello).try_into()?);
+        self.inner.set_peer_max_frame(offer.max_frame_size as _);
+
+        let id = match self.inner.next().await {
+            Some(Ok(cmd)) => cmd.id,
+            Some(Err(e)) => return Err(e),
+            None => return Ok(()),
+        };
+        let res = CommandResponse {
+            id,
+            ..(&err).into()
+        };
+        send_response(&mut self.inner, &res).await?;
+        self.inner.close().await
+    }
+
     // process 是对外的方法
     pub async fn process(mut self) -> Result<(), KvError> {
+        let (compression, max_frame) = (&self.compression, self.max_frame);
+        let (offer, hello) =
+            server_handshake_with_limit(self.inner.get_mut(), compression, max_frame).await?;
+        self.inner.set_compression((&hello).try_into()?);
+        self.inner.set_peer_max_frame(offer.max_frame_size as _);
+
+        let svc = self.service.with_session(self.session.clone());
+        let svc = match &self.layer {
+            Some(wrap) => wrap(svc),
+            None => BoxCloneService::new(svc),
+        };
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                stream.send(&data).await.unwrap();
+        // 同一个 stream 上的请求并发执行，响应汇总到 channel 中，按照完成的先后顺序写回。
+        // 关闭时 drop 掉 tx，所有请求执行完毕后 rx 会返回 None
+        let (tx, mut rx) = mpsc::channel(RESPONSE_CAPACITY);
+        let mut tx = Some(tx);
+        // 尚未执行完的请求，用 stream 内部的序号标识，出错关闭时全部取消
+        let mut tasks: HashMap<u64, (u32, AbortHandle)> = HashMap::new();
+        // 请求 id 对应的序号，可以通过 Cancel 取消，id 为 0 的请求无法取消
+        let mut ids: HashMap<u32, u64> = HashMap::new();
+        let mut next_key = 0u64;
+        // 订阅不会自己结束，对端不再发送请求之后通过它通知订阅结束
+        let (closing, closed) = watch::channel(false);
+        let result = loop {
+            tokio::select! {
+                cmd = stream.next(), if tx.is_some() => match (cmd, &tx) {
+                    (
+                        Some(Ok(CommandRequest {
+                            request_data: Some(RequestData::Cancel(Cancel { id })),
+                            ..
+                        })),
+                        _,
+                    ) => {
+                        if let Some((_, task)) = ids.remove(&id).and_then(|key| tasks.remove(&key)) {
+                            info!("Cancel request {}", id);
+                            task.abort();
+                        }
+                    }
+                    (Some(Ok(cmd)), Some(tx)) => {
+                        info!("Got a new command: {:?}", cmd);
+                        let id = cmd.id;
+                        // 相同 id 的请求无法区分响应，也无法取消之前的请求
+                        let duplicated = || match id != 0 && ids.contains_key(&id) {
+                            true => Err(KvError::InvalidCommand(format!(
+                                "request {id} is already in flight"
+                            ))),
+                            false => Ok(()),
+                        };
+                        let identity = self.session.identity();
+                        let pending = match duplicated()
+                            .and_then(|_| self.service.authorize(identity, &cmd))
+                            .and_then(|_| self.service.admit(self.session.client(), &cmd))
+                        {
+                            Ok(pending) => pending,
+                            Err(e) => {
+                                let res = CommandResponse { id, ..e.into() };
+                                if let Err(e) = send_response(stream, &res).await {
+                                    break Err(e);
+                                }
+                                continue;
+                            }
+                        };
+                        // Auth 在读取下一个请求之前完成，保证之后的请求都以认证后的身份执行
+                        if cmd.name() == "auth" {
+                            let res = self.service.execute_in(&self.session, cmd).next().await;
+                            pending.finish();
+                            if let Some(data) = res {
+                                let res = CommandResponse { id, ..(*data).clone() };
+                                if let Err(e) = send_response(stream, &res).await {
+                                    break Err(e);
+                                }
+                                self.service.after_send(&self.session, &res).await;
+                            }
+                            continue;
+                        }
+                        let (task, registration) = AbortHandle::new_pair();
+                        let streaming = matches!(cmd.name(), "subscribe" | "psubscribe" | "track");
+                        let closed = streaming.then(|| closed.clone());
+                        let key = next_key;
+                        next_key += 1;
+                        let fut = execute(svc.clone(), key, cmd, pending, closed, tx.clone());
+                        tokio::spawn(Abortable::new(fut, registration));
+                        tasks.insert(key, (id, task));
+                        if id != 0 {
+                            ids.insert(id, key);
+                        }
+                    }
+                    // frame 是完整的，只是内容无法解析，返回错误后可以继续处理后续的请求
+                    (Some(Err(e @ KvError::DecodeError(_))), _) => {
+                        warn!("Failed to decode request: {}", e);
+                        if let Err(e) = send_response(stream, &e.into()).await {
+                            break Err(e);
+                        }
+                    }
+                    // frame 损坏或者 I/O 出错，stream 中的数据已经无法继续读取，尽量告知对端后关闭
+                    (Some(Err(e)), _) => {
+                        warn!("Failed to read request: {}", e);
+                        if !matches!(e, KvError::IOError(_)) {
+                            let _ = send_response(stream, &(&e).into()).await;
+                        }
+                        break Err(e);
+                    }
+                    // 对端不再发送请求（比如只关闭了写的一端），写回正在执行的请求的响应之后再关闭。
+                    // 对端已经无法取消订阅，订阅在返回第一个响应之后结束
+                    (None, _) | (_, None) => {
+                        info!("Peer closed the stream, waiting for {} requests", tasks.len());
+                        tx = None;
+                        let _ = closing.send(true);
+                    }
+                },
+                _ = wait_for_shutdown(&mut self.shutdown), if tx.is_some() => {
+                    info!("Server is shutting down, waiting for {} requests", tasks.len());
+                    tx = None;
+                }
+                res = rx.recv() => match res {
+                    Some((_, Some(data))) => {
+                        if let Err(e) = send_response(stream, &data).await {
+                            warn!("Failed to send response: {}", e);
+                            break Err(e);
+                        }
+                        self.service.after_send(&self.session, &data).await;
+                    }
+                    Some((key, None)) => {
+                        if let Some((id, _)) = tasks.remove(&key) {
+                            ids.remove(&id);
+                        }
+                    }
+                    // 对端或者服务器关闭之后，所有的请求都已经执行完毕
+                    None => break Ok(()),
+                },
             }
+        };
+
+        // 只有 stream 出错时才会有尚未完成的请求
+        for (_, task) in tasks.into_values() {
+            task.abort();
         }
-        Ok(())
+        result
     }
 }
 
+/// 等待服务器关闭的信号，没有设置信号时永远不会返回
+async fn wait_for_shutdown(shutdown: &mut Option<watch::Receiver<bool>>) {
+    if let Some(shutdown) = shutdown {
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+    }
+    future::pending().await
+}
+
+/// 写回一个响应。无法 encode 的响应（比如超过了 frame 的大小限制）会被替换成对应的错误，
+/// 只有 I/O 出错时才返回错误
+async fn send_response<S>(
+    stream: &mut ProstStream<S, CommandRequest, CommandResponse>,
+    data: &CommandResponse,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match stream.send(data).await {
+        Err(KvError::IOError(e)) => Err(e.into()),
+        Err(e) => {
+            warn!("Failed to encode response {}: {}", data.id, e);
+            let res = CommandResponse {
+                id: data.id,
+                ..e.into()
+            };
+            stream.send(&res).await
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// 执行一个请求，把带有请求 id 的响应交给 process 写回，执行结束后发送 None 通知 process。
+/// key 是请求在 stream 内部的序号。订阅在 closed 变为 true 之后，返回了第一个响应就结束
+async fn execute(
+    svc: BoxCommandService,
+    key: u64,
+    cmd: CommandRequest,
+    pending: Pending,
+    mut closed: Option<watch::Receiver<bool>>,
+    tx: mpsc::Sender<(u64, Option<Arc<CommandResponse>>)>,
+) {
+    let id = cmd.id;
+    let mut res = match svc.oneshot(cmd).await {
+        Ok(res) => res,
+        Err(e) => {
+            let res = e.into();
+            Box::pin(futures::stream::once(async { Arc::new(res) }))
+        }
+    };
+    let mut pending = Some(pending);
+    loop {
+        let data = match &mut closed {
+            Some(closed) if pending.is_none() => tokio::select! {
+                data = res.next() => data,
+                _ = closed.wait_for(|closed| *closed) => None,
+            },
+            _ => res.next().await,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => break,
+        };
+        if let Some(pending) = pending.take() {
+            pending.finish();
+        }
+        // 订阅推送的数据是多个订阅者共享的，只有需要设置 id 时才复制
+        let data = match id {
+            0 => data,
+            id => Arc::new(CommandResponse {
+                id,
+                ..(*data).clone()
+            }),
+        };
+        if tx.send((key, Some(data))).await.is_err() {
+            return;
+        }
+    }
+    let _ = tx.send((key, None)).await;
+}
+
 impl<S> ProstClientStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -59,44 +421,259 @@ where
     pub fn new(stream: S) -> ProstClientStream<S> {
         Self {
             inner: ProstStream::new(stream),
+            compression: CompressionConfig::default(),
+            max_frame: MAX_FRAME,
+            server: None,
+            timeout: None,
+            credentials: None,
+            next_id: 0,
+        }
+    }
+
+    /// 设置能够接收的最大 frame，超过该值的响应会返回 KvError::FrameError
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        // 握手时告知对端的大小同样不能超过 30 bit 的长度
+        let max_frame = max_frame.min(MAX_FRAME_LIMIT);
+        self.inner.set_max_frame(max_frame);
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// 设置请求的默认超时时间，超时后返回 KvError::Timeout，服务端也不再等待该请求的结果，
+    /// 但是已经开始执行的写入依然可能生效
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 设置客户端期望的压缩配置，实际使用的配置由握手协商得出
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置用户名和密码，握手之后先发送 Auth 认证当前的 stream
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some(CredentialConfig {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// 获取服务端的版本和能力，如果还没有握手，则先完成握手和认证
+    pub async fn hello(&mut self) -> Result<&Hello, KvError> {
+        if self.server.is_none() {
+            let (compression, max_frame) = (&self.compression, self.max_frame);
+            let stream = self.inner.get_mut();
+            let hello = client_handshake_with_limit(stream, compression, max_frame).await?;
+            self.inner.set_compression((&hello).try_into()?);
+            self.inner.set_peer_max_frame(hello.max_frame_size as _);
+            if let Some(credentials) = self.credentials.clone() {
+                let cmd = CommandRequest::new_auth(credentials.username, credentials.password);
+                let cmd = self.prepare(&cmd);
+                self.request(&cmd).await?.into_result()?;
+            }
+            self.server = Some(hello);
         }
+        Ok(self.server.as_ref().unwrap())
+    }
+
+    /// 在发送命令之前确认服务端支持该命令，避免发送对方无法识别的请求
+    async fn check(&mut self, cmd: &CommandRequest) -> Result<(), KvError> {
+        let hello = self.hello().await?;
+        if !hello.supports(cmd.name()) {
+            return Err(KvError::UnsupportedCommand(cmd.name().into()));
+        }
+        // cancel 只用于取消 pipeline 中的请求，服务端不会返回响应
+        if cmd.name() == "cancel" {
+            return Err(KvError::InvalidCommand("cancel cannot be executed".into()));
+        }
+        Ok(())
     }
 
     pub async fn execute(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        self.check(cmd).await?;
+        let cmd = self.prepare(cmd);
+        match cmd.timeout() {
+            Some(timeout) => time::timeout(timeout, self.request(&cmd))
+                .await
+                .map_err(|_| KvError::Timeout(cmd.name().into()))?,
+            None => self.request(&cmd).await,
+        }
+    }
+
+    /// 发送请求并读取对应的响应
+    async fn request(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
         let stream = &mut self.inner;
         stream.send(cmd).await?;
-        match stream.next().await {
-            Some(v) => v,
-            None => Err(KvError::Internal("Didn't get any response".into())),
+        loop {
+            match stream.next().await {
+                Some(Ok(res)) if res.id == cmd.id => return Ok(res),
+                // 之前超时的请求，其响应可能会晚到，直接丢弃
+                Some(Ok(res)) => debug!("Drop a stale response {}", res.id),
+                Some(Err(e)) => return Err(e),
+                None => return Err(KvError::ConnectionClosed("didn't get any response".into())),
+            }
         }
     }
 
-    pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
-        let mut stream = self.inner;
+    /// 为请求分配 id，并在请求没有指定超时时间时使用客户端的默认值
+    fn prepare(&mut self, cmd: &CommandRequest) -> CommandRequest {
+        // 0 表示不需要匹配响应，因此跳过
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let mut cmd = cmd.clone().with_id(self.next_id);
+        if let (Some(timeout), None) = (self.timeout, cmd.timeout()) {
+            cmd = cmd.with_timeout(timeout);
+        }
+        cmd
+    }
+
+    /// 把 reader 中的数据分块上传到 table:key，返回上传的总长度
+    pub async fn upload<R>(&mut self, table: &str, key: &str, mut reader: R) -> Result<u64, KvError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let (mut upload_id, mut offset) = (0, 0);
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            // 尽量读满一个分块，读到 EOF 时发送最后一个分块
+            let (mut len, mut finish) = (0, false);
+            while len < CHUNK_SIZE {
+                match reader.read(&mut chunk[len..]).await? {
+                    0 => {
+                        finish = true;
+                        break;
+                    }
+                    n => len += n,
+                }
+            }
+
+            let data = Bytes::copy_from_slice(&chunk[..len]);
+            let cmd = CommandRequest::new_upload(upload_id, table, key, offset, data, finish);
+            let res = self.execute(&cmd).await?.into_result()?;
+            upload_id = i64::try_from(&res)? as _;
+            offset += len as u64;
+            if finish {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// 把 table:key 中的 value 分块下载到 writer 中，返回下载的总长度
+    pub async fn download<W>(
+        &mut self,
+        table: &str,
+        key: &str,
+        mut writer: W,
+    ) -> Result<u64, KvError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let cmd = CommandRequest::new_download(table, key);
+        self.check(&cmd).await?;
+        let cmd = self.prepare(&cmd);
+        let size = match cmd.timeout() {
+            Some(timeout) => time::timeout(timeout, self.receive_chunks(&cmd, &mut writer))
+                .await
+                .map_err(|_| KvError::Timeout(cmd.name().into()))?,
+            None => self.receive_chunks(&cmd, &mut writer).await,
+        }?;
+        writer.flush().await?;
+        Ok(size)
+    }
+
+    /// 发送 download 请求，第一个响应是 value 的总长度，之后依次把各个分块写入 writer
+    async fn receive_chunks<W>(
+        &mut self,
+        cmd: &CommandRequest,
+        writer: &mut W,
+    ) -> Result<u64, KvError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let stream = &mut self.inner;
         stream.send(cmd).await?;
-        stream.close().await?;
+        let (mut size, mut received) = (None, 0);
+        while size != Some(received) {
+            let res = match stream.next().await {
+                Some(Ok(res)) if res.id == cmd.id => res.into_result()?,
+                Some(Ok(res)) => {
+                    debug!("Drop a stale response {}", res.id);
+                    continue;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Err(KvError::ConnectionClosed("didn't get any response".into())),
+            };
+
+            if size.is_none() {
+                size = Some(i64::try_from(&res)? as u64);
+                continue;
+            }
+            match res.values.first().and_then(|v| v.value.as_ref()) {
+                Some(value::Value::Binary(chunk)) => {
+                    writer.write_all(chunk).await?;
+                    received += chunk.len() as u64;
+                }
+                _ => return Err(KvError::ConvertError(res.format(), "Binary")),
+            }
+        }
+        Ok(received)
+    }
+
+    /// 把当前 stream 转换成可以同时发送多个请求的 PipelineClient
+    pub async fn into_pipeline(mut self) -> Result<PipelineClient, KvError> {
+        let hello = self.hello().await?.clone();
+        Ok(PipelineClient::new(self.inner, hello, self.timeout))
+    }
+
+    /// 订阅的超时时间对整个订阅生效，到期后服务端会结束订阅
+    pub async fn execute_streaming(
+        mut self,
+        cmd: &CommandRequest,
+    ) -> Result<StreamResult, KvError> {
+        self.check(cmd).await?;
+        let cmd = self.prepare(cmd);
+        let mut stream = self.inner;
+        stream.send(&cmd).await?;
         StreamResult::new(stream).await
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+pub(crate) mod tests {
+    use crate::{
+        assert_res_ok, hash_password, Acl, AclConfig, AclRule, AuthConfig, CompressionCodec,
+        LimitConfig, MemTable, RateConfig, ServiceInner, UserConfig, Users, Value,
+    };
 
     use super::*;
     use anyhow::Result;
     use bytes::Bytes;
-    use std::net::SocketAddr;
+    use http::StatusCode;
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
     use tokio::net::{TcpListener, TcpStream};
 
-    async fn start_server() -> Result<SocketAddr> {
+    pub async fn start_server() -> Result<SocketAddr> {
+        start_server_with_compression(CompressionConfig::default()).await
+    }
+
+    async fn start_server_with_compression(compression: CompressionConfig) -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
         tokio::spawn(async move {
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
                 let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
+                let server =
+                    ProstServerStream::new(stream, service).with_compression(compression.clone());
                 tokio::spawn(server.process());
             }
         });
@@ -139,6 +716,493 @@ mod tests {
         assert_res_ok(&res, &[v], &[]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn client_server_negotiated_compression_should_work() -> anyhow::Result<()> {
+        for codec in [
+            CompressionCodec::None,
+            CompressionCodec::Gzip,
+            CompressionCodec::Lz4,
+            CompressionCodec::Zstd,
+        ] {
+            let compression = CompressionConfig {
+                codec,
+                level: 3,
+                threshold: 512,
+            };
+            let addr = start_server_with_compression(compression.clone()).await?;
+            let stream = TcpStream::connect(addr).await?;
+            let mut client = ProstClientStream::new(stream).with_compression(compression);
+
+            let v: Value = Bytes::from(vec![1u8; 16384]).into();
+            let cmd = CommandRequest::new_hset("t1", "k1", v.clone());
+            let res = client.execute(&cmd).await.unwrap();
+            assert_res_ok(&res, &[Value::default()], &[]);
+
+            let cmd = CommandRequest::new_hget("t1", "k1");
+            let res = client.execute(&cmd).await.unwrap();
+            assert_res_ok(&res, &[v], &[]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_get_server_hello() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let hello = client.hello().await?;
+        assert_eq!(hello.version, crate::PROTOCOL_VERSION);
+        assert_eq!(hello.server, SERVER_NAME);
+        assert!(hello.supports("hset"));
+
+        let res = client.execute(&CommandRequest::default()).await;
+        assert!(matches!(res, Err(KvError::UnsupportedCommand(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_and_download_large_value_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // 超过 MAX_FRAME 的数据无法通过 hset 写入
+        let data: Vec<u8> = (0..MAX_FRAME * 3).map(|i| (i % 251) as u8).collect();
+        let size = client.upload("t1", "k1", &data[..]).await?;
+        assert_eq!(size, data.len() as u64);
+
+        let mut buf = Vec::new();
+        let size = client.download("t1", "k1", &mut buf).await?;
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(buf, data);
+
+        // 空的 value 也可以上传和下载
+        client.upload("t1", "k2", &b""[..]).await?;
+        let mut buf = Vec::new();
+        assert_eq!(client.download("t1", "k2", &mut buf).await?, 0);
+
+        let res = client.download("t1", "k3", &mut buf).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undecodable_request_should_get_error_response() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        client_handshake(&mut stream, &CompressionConfig::none()).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        stream.set_compression(CompressionConfig::none());
+
+        // 一个完整的 frame，但内容不是合法的 protobuf
+        stream
+            .get_mut()
+            .write_all(&[0, 0, 0, 2, 0xff, 0xff])
+            .await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST.as_u16() as u32);
+
+        // stream 仍然可以继续使用
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_id(1);
+        stream.send(&cmd).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_request_should_close_stream() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        client_handshake(&mut stream, &CompressionConfig::none()).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+
+        stream
+            .get_mut()
+            .write_all(&((MAX_FRAME + 1) as u32).to_be_bytes())
+            .await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE.as_u16() as u32);
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frames_should_respect_peer_max_frame() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let server = ProstServerStream::new(stream, service).with_max_frame(4096);
+            server.process().await
+        });
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream)
+            .with_compression(CompressionConfig::none())
+            .with_max_frame(2048);
+
+        // 超过服务端给出的大小，请求不会被发送，stream 仍然可以继续使用
+        let value: Value = Bytes::from(vec![1u8; 8192]).into();
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", value))
+            .await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+
+        // 响应超过了客户端给出的大小，服务端返回错误而不是发送客户端无法接收的 frame
+        let value: Value = Bytes::from(vec![1u8; 3072]).into();
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k2", value))
+            .await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k2"))
+            .await?;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE.as_u16() as u32);
+
+        let res = client
+            .execute(&CommandRequest::new_hexist("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &[false.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unencodable_response_should_become_error() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream).with_compression(CompressionConfig::none());
+
+        let data = vec![1u8; MAX_FRAME * 2];
+        client.upload("t1", "k1", &data[..]).await?;
+
+        // value 超过了 frame 的限制，只能通过 download 获取
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE.as_u16() as u32);
+
+        let res = client
+            .execute(&CommandRequest::new_hexist("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &[true.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_time_out() -> anyhow::Result<()> {
+        let (client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            // 完成握手之后不再响应任何请求
+            server_handshake(&mut server, &CompressionConfig::default()).await?;
+            time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, KvError>(server)
+        });
+        let mut client = ProstClientStream::new(client).with_timeout(Duration::from_millis(50));
+
+        let res = client.execute(&CommandRequest::new_hget("t1", "k1")).await;
+        assert!(matches!(res, Err(KvError::Timeout(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_end_at_deadline() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream);
+
+        let cmd = CommandRequest::new_subscribe("lobby").with_timeout(Duration::from_millis(50));
+        let mut res = client.execute_streaming(&cmd).await?;
+        let data = res.next().await.unwrap()?;
+        assert_eq!(data.status, StatusCode::REQUEST_TIMEOUT.as_u16() as u32);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_should_stop_subscription() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        client_handshake(&mut stream, &CompressionConfig::none()).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        stream.set_compression(CompressionConfig::none());
+
+        stream
+            .send(&CommandRequest::new_subscribe("lobby").with_id(1))
+            .await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.id, 1);
+
+        stream.send(&CommandRequest::new_cancel(1)).await?;
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]).with_id(2);
+        stream.send(&cmd).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.id, 2);
+
+        // 订阅已经被取消，不会再收到推送的数据
+        let res = time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn half_closed_stream_should_get_pending_responses() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        client_handshake(&mut stream, &CompressionConfig::none()).await?;
+        let mut stream = ProstStream::<_, CommandResponse, CommandRequest>::new(stream);
+        stream.set_compression(CompressionConfig::none());
+
+        let cmds = [
+            CommandRequest::new_subscribe("lobby").with_id(1),
+            CommandRequest::new_psubscribe("lobby*").with_id(3),
+            CommandRequest::new_track().with_id(4),
+            // id 相同的请求在前一个请求结束之前会被拒绝
+            CommandRequest::new_subscribe("hall").with_id(1),
+            CommandRequest::new_hset("t1", "k1", "v1".into()).with_id(2),
+        ];
+        for cmd in &cmds {
+            stream.send(cmd).await?;
+        }
+        stream.get_mut().shutdown().await?;
+
+        // 关闭写的一端之后仍然能收到已经发出的请求的响应，之后服务端结束所有的订阅并关闭 stream
+        let mut responses = Vec::new();
+        let collect = async {
+            while let Some(res) = stream.next().await {
+                let res = res?;
+                responses.push((res.id, res.status));
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(5), collect).await??;
+        responses.sort_unstable();
+        let ok = StatusCode::OK.as_u16() as u32;
+        let bad = StatusCode::BAD_REQUEST.as_u16() as u32;
+        assert_eq!(responses, [(1, ok), (1, bad), (2, ok), (3, ok), (4, ok)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limited_request_should_return_429() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let limit = LimitConfig {
+            write: Some(RateConfig { rate: 1, burst: 1 }),
+            ..Default::default()
+        };
+        let service: Service = ServiceInner::new(MemTable::new()).with_limit(limit).into();
+        tokio::spawn(
+            ProstServerStream::new(server, service)
+                .with_client("alice")
+                .process(),
+        );
+        let mut client = ProstClientStream::new(client);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(&cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = client.execute(&cmd).await?;
+        assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS.as_u16() as u32);
+
+        // 读命令没有限流
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn denied_request_should_return_403() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let acl = AclConfig {
+            rules: vec![AclRule {
+                identity: "awesome-device-id".into(),
+                commands: vec!["hget".into()],
+                tables: vec!["t1".into()],
+                ..Default::default()
+            }],
+        };
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_acl(Acl::new(&acl)?)
+            .into();
+        let identity = PeerIdentity {
+            common_name: Some("awesome-device-id".into()),
+            ..Default::default()
+        };
+        tokio::spawn(
+            ProstServerStream::new(server, service)
+                .with_identity(Some(Arc::new(identity)))
+                .process(),
+        );
+        let mut client = ProstClientStream::new(client);
+
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(res.status, StatusCode::FORBIDDEN.as_u16() as u32);
+        assert!(matches!(KvError::from(&res), KvError::PermissionDenied(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_should_be_authenticated_before_executing() -> anyhow::Result<()> {
+        let auth = AuthConfig {
+            users: vec![UserConfig {
+                name: "alice".into(),
+                password: hash_password("secret"),
+                read: vec!["t1".into()],
+                ..Default::default()
+            }],
+        };
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_users(Users::new(&auth)?)
+            .into();
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+        let mut client = ProstClientStream::new(client);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(&cmd).await?;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED.as_u16() as u32);
+        let res = client
+            .execute(&CommandRequest::new_auth("alice", "wrong"))
+            .await?;
+        assert!(matches!(KvError::from(&res), KvError::Unauthenticated(_)));
+
+        // 认证之后按照用户的规则执行
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        let mut client = ProstClientStream::new(client).with_credentials("alice", "secret");
+        let res = client.execute(&cmd).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(res.status, StatusCode::FORBIDDEN.as_u16() as u32);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_should_be_rate_limited_and_hooked() -> anyhow::Result<()> {
+        let auth = AuthConfig {
+            users: vec![UserConfig {
+                name: "alice".into(),
+                password: hash_password("secret"),
+                ..Default::default()
+            }],
+        };
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_users(Users::new(&auth)?)
+            .with_limit(LimitConfig {
+                auth: Some(RateConfig { rate: 1, burst: 2 }),
+                ..Default::default()
+            })
+            .fn_received(move |cmd| {
+                if cmd.name() == "auth" {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            })
+            .into();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = ProstServerStream::new(server, service).with_client("10.0.0.1");
+        tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+        let cmd = CommandRequest::new_auth("alice", "wrong");
+        for _ in 0..2 {
+            let res = client.execute(&cmd).await?;
+            assert!(matches!(KvError::from(&res), KvError::Unauthenticated(_)));
+        }
+        // 超过限流之后不再校验密码
+        let res = client.execute(&cmd).await?;
+        assert!(matches!(KvError::from(&res), KvError::RateLimited(_)));
+        assert_eq!(received.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hook_should_be_notified_after_response_is_sent() -> anyhow::Result<()> {
+        /// 记录已经发送的响应以及对应的客户端地址
+        struct Sent(mpsc::UnboundedSender<(Option<SocketAddr>, u32)>);
+
+        impl crate::Hook for Sent {
+            fn on_after_send<'a>(
+                &'a self,
+                session: &'a Session,
+                res: &'a CommandResponse,
+            ) -> future::BoxFuture<'a, ()> {
+                let _ = self.0.send((session.addr(), res.status));
+                Box::pin(future::ready(()))
+            }
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .with_hook(Sent(tx))
+            .into();
+        let addr: SocketAddr = "10.0.0.1:4000".parse()?;
+        let (client, server) = tokio::io::duplex(4096);
+        let server = ProstServerStream::new(server, service).with_addr(addr);
+        tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+        let sent = rx.recv().await.unwrap();
+        assert_eq!(sent, (Some(addr), StatusCode::NOT_FOUND.as_u16() as u32));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tower_layer_should_wrap_execution() -> anyhow::Result<()> {
+        /// 让 t2 上的请求执行得很慢
+        struct Slow;
+
+        impl crate::Hook for Slow {
+            fn on_received<'a>(
+                &'a self,
+                _session: &'a Session,
+                cmd: &'a CommandRequest,
+            ) -> future::BoxFuture<'a, Result<(), CommandResponse>> {
+                Box::pin(async move {
+                    if cmd.table() == Some("t2") {
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                    Ok(())
+                })
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::new()).with_hook(Slow).into();
+        let layer = tower::ServiceBuilder::new()
+            .timeout(Duration::from_millis(50))
+            .concurrency_limit(8);
+        let (client, server) = tokio::io::duplex(4096);
+        let server = ProstServerStream::new(server, service).with_layer(layer);
+        tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+
+        let res = client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = client
+            .execute(&CommandRequest::new_hget("t2", "k1"))
+            .await?;
+        assert_eq!(res.status, StatusCode::REQUEST_TIMEOUT.as_u16() as u32);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1,16 +1,36 @@
-use crate::ProstClientStream;
+use crate::{
+    network::idle::{Activity, IdleStream},
+    CommandRequest, CompressionConfig, CredentialConfig, KvError, ProstClientStream, MAX_FRAME,
+};
 use futures::{future, Future, TryStreamExt};
-use std::marker::PhantomData;
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::{marker::PhantomData, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{oneshot, watch},
+    time,
+};
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
-use tracing::instrument;
+use tracing::{info, instrument, warn};
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 
 /// 控制结构
 pub struct YamuxCtrl<S> {
     /// yamux control 用于创建新的 stream
     ctrl: Control,
-    _conn: PhantomData<S>,
+    /// 在新打开的 stream 上协商压缩配置时所使用的期望值
+    compression: CompressionConfig,
+    /// 新打开的 stream 能够接收的最大 frame
+    max_frame: usize,
+    /// 新打开的 stream 在握手之后用于认证的用户名和密码
+    credentials: Option<CredentialConfig>,
+    /// 底层连接最后一次收到数据的时间
+    activity: Activity,
+    /// 连接关闭后 sender 会被 drop
+    closed: watch::Receiver<()>,
+    /// drop 时通知 keepalive 任务退出
+    _keepalive: Option<oneshot::Sender<()>>,
+    // 只记录底层连接的类型，用 fn() -> S 避免要求 S: Sync
+    _conn: PhantomData<fn() -> S>,
 }
 
 impl<S> YamuxCtrl<S>
@@ -37,20 +57,61 @@ where
         config.set_window_update_mode(WindowUpdateMode::OnRead);
 
         // 创建 config yamux::Stream 使用的是 futures 的 trait，所以需要 compat() 到 tokio 的 trait
+        let (stream, activity) = IdleStream::new(stream);
         let conn = Connection::new(stream.compat(), config, mode);
 
         // 创建 yamux ctrl
         let ctrl = conn.control();
 
-        // poll 所有 stream 下的数据
-        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+        // poll 所有 stream 下的数据，结束后通知连接已经关闭
+        let (done, closed) = watch::channel(());
+        tokio::spawn(async move {
+            if let Err(e) = yamux::into_stream(conn)
+                .try_for_each_concurrent(None, f)
+                .await
+            {
+                info!("Yamux connection is closed: {}", e);
+            }
+            drop(done);
+        });
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            compression: CompressionConfig::default(),
+            max_frame: MAX_FRAME,
+            credentials: None,
+            activity,
+            closed,
+            _keepalive: None,
+            _conn: PhantomData,
         }
     }
 
+    /// 设置新打开的 stream 期望使用的压缩配置
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置新打开的 stream 能够接收的最大 frame
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// 设置新打开的 stream 用于认证的用户名和密码
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some(CredentialConfig {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
     /// 创建 yamux 客户端
     pub fn new_client(stream: S, config: Option<Config>) -> Self {
         Self::new(stream, config, true, |_stream| future::ready(Ok(())))
@@ -68,13 +129,109 @@ where
         Self::new(stream, config, false, f)
     }
 
+    /// 连接上超过 timeout 没有收到任何数据时关闭连接，用于清理已经失联的对端
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        let mut ctrl = self.ctrl.clone();
+        let activity = self.activity.clone();
+        let closed = self.closed.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = activity.wait_idle(timeout) => {
+                    info!("Connection is idle for {:?}, closing it", timeout);
+                    let _ = ctrl.close().await;
+                }
+                _ = wait_closed(closed) => {}
+            }
+        });
+        self
+    }
+
+    /// 每隔 interval 在单独的 stream 上发送一次 Ping，对端没有及时回复时关闭连接。
+    /// 需要在 with_compression 等配置之后调用，YamuxCtrl 被 drop 后停止发送
+    pub fn with_keepalive(mut self, interval: Duration) -> Self {
+        let mut ctrl = self.ctrl.clone();
+        let new_stream = self.stream_builder();
+        let closed = self.closed.clone();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            tokio::select! {
+                e = ping(ctrl.clone(), new_stream, interval) => {
+                    warn!("Keepalive failed: {}, closing connection", e);
+                    let _ = ctrl.close().await;
+                }
+                _ = wait_closed(closed) => {}
+                _ = rx => {}
+            }
+        });
+        self._keepalive = Some(tx);
+        self
+    }
+
+    /// 距离最后一次从连接上收到数据经过的时间
+    pub fn idle(&self) -> Duration {
+        self.activity.idle()
+    }
+
+    /// 等待连接关闭
+    pub async fn closed(&self) {
+        wait_closed(self.closed.clone()).await
+    }
+
+    /// 连接是否已经关闭
+    pub fn is_closed(&self) -> bool {
+        self.closed.has_changed().is_err()
+    }
+
+    /// 关闭连接，所有打开的 stream 都会被关闭
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
+    }
+
     /// 打开一个新的 stream
     #[instrument(skip_all)]
     pub async fn open_stream(
-        &mut self,
+        &self,
     ) -> Result<ProstClientStream<Compat<yamux::Stream>>, ConnectionError> {
-        let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        let stream = self.ctrl.clone().open_stream().await?;
+        Ok(self.stream_builder()(stream))
+    }
+
+    /// 使用当前的配置把 yamux::Stream 包装成 ProstClientStream
+    fn stream_builder(&self) -> impl Fn(yamux::Stream) -> ProstClientStream<Compat<yamux::Stream>> {
+        let (compression, max_frame) = (self.compression.clone(), self.max_frame);
+        let credentials = self.credentials.clone();
+        move |stream| {
+            let stream = ProstClientStream::new(stream.compat())
+                .with_compression(compression.clone())
+                .with_max_frame(max_frame);
+            match credentials.clone() {
+                Some(c) => stream.with_credentials(c.username, c.password),
+                None => stream,
+            }
+        }
+    }
+}
+
+async fn wait_closed(mut closed: watch::Receiver<()>) {
+    while closed.changed().await.is_ok() {}
+}
+
+/// 定期发送 Ping，出错时返回
+async fn ping<F>(mut ctrl: Control, new_stream: F, interval: Duration) -> KvError
+where
+    F: Fn(yamux::Stream) -> ProstClientStream<Compat<yamux::Stream>>,
+{
+    let stream = match ctrl.open_stream().await {
+        Ok(stream) => stream,
+        Err(e) => return KvError::Internal(e.to_string()),
+    };
+    let mut stream = new_stream(stream).with_timeout(interval);
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = stream.execute(&CommandRequest::new_ping()).await {
+            return e;
+        }
     }
 }
 
@@ -91,7 +248,7 @@ mod tests {
     use anyhow::Result;
     use std::net::SocketAddr;
     use tokio::net::{TcpListener, TcpStream};
-    use tokio_rustls::server;
+    use tokio_rustls::{client, server};
     use tracing::warn;
 
     pub async fn start_server_with<Store>(
@@ -148,7 +305,7 @@ mod tests {
     #[tokio::test]
     async fn yamucx_ctrl_creation_should_work() -> Result<()> {
         let s = DummyStream::default();
-        let mut ctrl = YamuxCtrl::new_client(s, None);
+        let ctrl = YamuxCtrl::new_client(s, None);
 
         let stream = ctrl.open_stream().await;
         assert!(stream.is_ok());
@@ -167,7 +324,7 @@ mod tests {
         let stream = connector.connect(stream).await?;
 
         // 在 client ctrl 上打开一个新的 yamux stream
-        let mut ctrl = YamuxCtrl::new_client(stream, None);
+        let ctrl = YamuxCtrl::new_client(stream, None);
         let mut stream = ctrl.open_stream().await?;
 
         let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
@@ -178,4 +335,55 @@ mod tests {
         assert_res_ok(&res, &["v1".into()], &[]);
         Ok(())
     }
+
+    /// 创建会关闭空闲连接的 yamux server
+    async fn start_idle_yamux_server(timeout: Duration) -> Result<SocketAddr, KvError> {
+        let f = move |stream, service: Service| {
+            YamuxCtrl::new_server(stream, None, move |s| {
+                let svc = service.clone();
+                async move {
+                    let stream = ProstServerStream::new(s.compat(), svc);
+                    let _ = stream.process().await;
+                    Ok(())
+                }
+            })
+            .with_idle_timeout(timeout);
+        };
+        let acceptor = tls_acceptor(false)?;
+        start_server_with("127.0.0.1:0", acceptor, MemTable::new(), f).await
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
+        let connector = tls_connector(false)?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+        Ok(YamuxCtrl::new_client(stream, None))
+    }
+
+    #[tokio::test]
+    async fn idle_connection_should_be_closed() -> Result<()> {
+        let addr = start_idle_yamux_server(Duration::from_millis(100)).await?;
+        let ctrl = connect(addr).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let res = stream.execute(&CommandRequest::new_ping()).await?;
+        assert_res_ok(&res, &["PONG".into()], &[]);
+
+        time::timeout(Duration::from_secs(1), ctrl.closed()).await?;
+        assert!(stream.execute(&CommandRequest::new_ping()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keepalive_should_keep_connection_open() -> Result<()> {
+        let addr = start_idle_yamux_server(Duration::from_millis(300)).await?;
+        let ctrl = connect(addr)
+            .await?
+            .with_keepalive(Duration::from_millis(100));
+        let mut stream = ctrl.open_stream().await?;
+
+        time::sleep(Duration::from_millis(800)).await;
+        let res = stream.execute(&CommandRequest::new_ping()).await?;
+        assert_res_ok(&res, &["PONG".into()], &[]);
+        Ok(())
+    }
 }
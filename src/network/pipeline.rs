@@ -0,0 +1,274 @@
+use crate::{
+    command_request::RequestData, Cancel, CommandRequest, CommandResponse, Hello, KvError,
+    ProstStream,
+};
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{instrument, warn};
+
+/// 可以同时等待响应的最大请求数
+const PIPELINE_CAPACITY: usize = 128;
+
+/// 等待发送的请求，以及用于把响应交还给调用者的 channel，Cancel 请求没有响应
+type Pending = (
+    CommandRequest,
+    Option<oneshot::Sender<Result<CommandResponse, KvError>>>,
+);
+
+/// 在同一个 stream 上同时发送多个请求，并根据请求 id 把乱序返回的响应交给对应的调用者
+#[derive(Clone)]
+pub struct PipelineClient {
+    sender: mpsc::Sender<Pending>,
+    next_id: Arc<AtomicU32>,
+    server: Arc<Hello>,
+    timeout: Option<Duration>,
+}
+
+impl PipelineClient {
+    pub(crate) fn new<S>(
+        stream: ProstStream<S, CommandResponse, CommandRequest>,
+        server: Hello,
+        timeout: Option<Duration>,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(PIPELINE_CAPACITY);
+        tokio::spawn(run(stream, receiver));
+        Self {
+            sender,
+            next_id: Arc::new(AtomicU32::new(1)),
+            server: Arc::new(server),
+            timeout,
+        }
+    }
+
+    /// 设置请求的默认超时时间，超时后返回 KvError::Timeout，服务端也不再等待该请求的结果，
+    /// 但是已经开始执行的写入依然可能生效
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 发送一个请求并等待其响应，多个 execute 可以同时进行。
+    /// 同一个 stream 上的请求在服务端是并发执行的，如果请求之间有先后依赖，需要等前一个请求返回后再发送
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        if !self.server.supports(cmd.name()) {
+            return Err(KvError::UnsupportedCommand(cmd.name().into()));
+        }
+        // 订阅会在同一个 id 上持续返回数据，需要使用单独的 stream 调用 execute_streaming；
+        // cancel 没有响应，由 PipelineClient 在请求被放弃时自动发送
+        if matches!(cmd.name(), "subscribe" | "psubscribe" | "track" | "cancel") {
+            return Err(KvError::InvalidCommand(format!(
+                "{} cannot be pipelined",
+                cmd.name()
+            )));
+        }
+
+        let id = self.next_id();
+        let mut cmd = cmd.clone().with_id(id);
+        if let (Some(timeout), None) = (self.timeout, cmd.timeout()) {
+            cmd = cmd.with_timeout(timeout);
+        }
+        let (name, timeout) = (cmd.name(), cmd.timeout());
+
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send((cmd, Some(tx)))
+            .await
+            .map_err(|_| KvError::ConnectionClosed("pipeline is closed".into()))?;
+
+        // 超时或者调用者 drop 掉这个 future 时，通知服务端取消该请求
+        let guard = CancelGuard {
+            id,
+            sender: &self.sender,
+        };
+        let res = match timeout {
+            Some(timeout) => time::timeout(timeout, rx)
+                .await
+                .map_err(|_| KvError::Timeout(name.into()))?,
+            None => rx.await,
+        };
+        mem::forget(guard);
+
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(KvError::ConnectionClosed("didn't get any response".into())),
+        }
+    }
+
+    /// 服务端在握手时的回复
+    pub fn server(&self) -> &Hello {
+        &self.server
+    }
+
+    /// 生成下一个请求 id，0 表示不需要匹配响应，因此跳过
+    fn next_id(&self) -> u32 {
+        loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+}
+
+/// 请求还没有得到响应时被 drop，则发送 Cancel 请求
+struct CancelGuard<'a> {
+    id: u32,
+    sender: &'a mpsc::Sender<Pending>,
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        let cmd = CommandRequest::new_cancel(self.id);
+        if self.sender.try_send((cmd, None)).is_err() {
+            warn!("Failed to cancel request {}", self.id);
+        }
+    }
+}
+
+/// 独占 stream：把调用者的请求写入 stream，并把读到的响应按照 id 分发回去。
+/// 所有的 PipelineClient 都被 drop 且没有等待中的请求时退出
+#[instrument(name = "pipeline_run", skip_all)]
+async fn run<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut receiver: mpsc::Receiver<Pending>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut pending = HashMap::new();
+    let mut accepting = true;
+
+    loop {
+        tokio::select! {
+            req = receiver.recv(), if accepting => match req {
+                Some((cmd, tx)) => match (stream.send(&cmd).await, tx) {
+                    (Ok(_), Some(tx)) => {
+                        pending.insert(cmd.id, tx);
+                    }
+                    // 请求已经被调用者放弃，不再等待它的响应
+                    (Ok(_), None) => {
+                        if let Some(RequestData::Cancel(Cancel { id })) = cmd.request_data {
+                            pending.remove(&id);
+                        }
+                    }
+                    // 超过对端限制的请求没有写入 stream，只影响这一个请求
+                    (Err(e @ KvError::FrameError), tx) => {
+                        if let Some(tx) = tx {
+                            let _ = tx.send(Err(e));
+                        }
+                    }
+                    (Err(e), tx) => {
+                        if let Some(tx) = tx {
+                            let _ = tx.send(Err(e));
+                        }
+                        break;
+                    }
+                },
+                None => accepting = false,
+            },
+            res = stream.next() => match res {
+                Some(Ok(res)) => match pending.remove(&res.id) {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(res));
+                    }
+                    None => warn!("Got a response for unknown request {}", res.id),
+                },
+                // 连接断开时，drop 掉 pending 中的 sender，调用者会得到错误
+                _ => break,
+            },
+        }
+
+        if !accepting && pending.is_empty() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assert_res_ok, network::tests::start_server, server_handshake, CommandRequest,
+        CompressionConfig, KvError, ProstClientStream, Value,
+    };
+    use anyhow::Result;
+    use futures::future::join_all;
+    use std::time::Duration;
+    use tokio::{io::duplex, net::TcpStream, time};
+
+    #[tokio::test]
+    async fn pipeline_should_match_responses_by_id() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).into_pipeline().await?;
+
+        let sets = (0..100i64).map(|i| {
+            let client = client.clone();
+            async move {
+                let cmd = CommandRequest::new_hset("t1", format!("k{i}"), i.into());
+                client.execute(&cmd).await
+            }
+        });
+        for res in join_all(sets).await {
+            assert_res_ok(&res?, &[Value::default()], &[]);
+        }
+
+        let gets = (0..100i64).map(|i| {
+            let client = client.clone();
+            async move {
+                let cmd = CommandRequest::new_hget("t1", format!("k{i}"));
+                (i, client.execute(&cmd).await)
+            }
+        });
+        for (i, res) in join_all(gets).await {
+            assert_res_ok(&res?, &[i.into()], &[]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipeline_should_reject_subscription() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).into_pipeline().await?;
+
+        let res = client
+            .execute(&CommandRequest::new_subscribe("lobby"))
+            .await;
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipeline_should_time_out() -> Result<()> {
+        let (client, mut server) = duplex(4096);
+        tokio::spawn(async move {
+            // 完成握手之后不再响应任何请求
+            server_handshake(&mut server, &CompressionConfig::default()).await?;
+            time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, KvError>(server)
+        });
+        let client = ProstClientStream::new(client)
+            .into_pipeline()
+            .await?
+            .with_timeout(Duration::from_millis(50));
+
+        let res = client.execute(&CommandRequest::new_hget("t1", "k1")).await;
+        assert!(matches!(res, Err(KvError::Timeout(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use super::tls::{load_certs, load_key};
+use crate::{validate_namespace, KvError, SniConfig};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::rustls::{
+    sign::{self, CertifiedKey},
+    ClientHello, ResolvesServerCert,
+};
+
+/// 各个 SNI 使用的证书和 namespace，key 是小写的域名
+#[derive(Default)]
+pub(super) struct SniCerts {
+    certs: HashMap<String, SniCert>,
+}
+
+struct SniCert {
+    key: CertifiedKey,
+    namespace: Option<String>,
+}
+
+/// 根据 SNI 选择证书，没有匹配的 SNI 时使用默认的证书
+pub(super) struct SniResolver {
+    pub default: CertifiedKey,
+    pub sni: Arc<RwLock<SniCerts>>,
+}
+
+impl SniCerts {
+    pub fn new(config: &[SniConfig]) -> Result<Self, KvError> {
+        let certs = config
+            .iter()
+            .map(|sni| {
+                if let Some(namespace) = &sni.namespace {
+                    validate_namespace(namespace)?;
+                }
+                let cert = SniCert {
+                    key: certified_key(&sni.cert, &sni.key)?,
+                    namespace: sni.namespace.clone(),
+                };
+                Ok((sni.name.to_lowercase(), cert))
+            })
+            .collect::<Result<_, KvError>>()?;
+        Ok(Self { certs })
+    }
+
+    /// SNI 对应的 namespace
+    pub fn namespace(&self, name: &str) -> Option<&str> {
+        self.certs
+            .get(&name.to_lowercase())
+            .and_then(|cert| cert.namespace.as_deref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.certs.len()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        if let Some(name) = client_hello.server_name() {
+            let name: &str = name.into();
+            if let Some(cert) = self.sni.read().unwrap().certs.get(&name.to_lowercase()) {
+                return Some(cert.key.clone());
+            }
+        }
+        Some(self.default.clone())
+    }
+}
+
+/// 加载证书链和私钥
+pub(super) fn certified_key(cert: &str, key: &str) -> Result<CertifiedKey, KvError> {
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| KvError::CertificateParseError("private", "key"))?;
+    if certs.is_empty() {
+        return Err(KvError::CertificateParseError("server", "cert"));
+    }
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sni_certs_should_be_loaded() {
+        let sni = |name: &str, namespace: Option<&str>| SniConfig {
+            name: name.into(),
+            cert: include_str!("../../fixtures/server.cert").into(),
+            key: include_str!("../../fixtures/server.key").into(),
+            namespace: namespace.map(|ns| ns.into()),
+        };
+        let certs = SniCerts::new(&[
+            sni("Tenant-A.acme.inc", Some("a")),
+            sni("tenant-b.acme.inc", None),
+        ])
+        .unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs.namespace("tenant-a.acme.inc"), Some("a"));
+        assert_eq!(certs.namespace("tenant-b.acme.inc"), None);
+        assert_eq!(certs.namespace("unknown.acme.inc"), None);
+
+        let invalid = SniConfig {
+            key: "invalid".into(),
+            ..sni("tenant-c.acme.inc", None)
+        };
+        assert!(SniCerts::new(&[invalid]).is_err());
+
+        // namespace 和 table 之间用 ':' 分隔
+        assert!(SniCerts::new(&[sni("tenant-c.acme.inc", Some("a:b"))]).is_err());
+        assert!(SniCerts::new(&[sni("tenant-c.acme.inc", Some(""))]).is_err());
+    }
+}
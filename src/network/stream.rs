@@ -1,13 +1,15 @@
 use bytes::BytesMut;
-use futures::{ready, FutureExt, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use std::{
     marker::PhantomData,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
 
-use crate::{read_frame, FrameCoder, KvError};
+use super::frame::{frame_len, LEN_LEN, MAX_FRAME, MAX_FRAME_LIMIT};
+use crate::{CompressionConfig, FrameCoder, KvError};
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -15,6 +17,11 @@ pub struct ProstStream<S, In, Out> {
     rbuf: BytesMut,
     wbuf: BytesMut,
     written: usize,
+    compression: CompressionConfig,
+    /// 能够接收的最大 frame
+    max_frame: usize,
+    /// 对端在握手时给出的能够接收的最大 frame，发送时超过该值返回 KvError::FrameError
+    peer_max_frame: usize,
     _in: PhantomData<In>,
     _out: PhantomData<Out>,
 }
@@ -27,22 +34,40 @@ where
 {
     /// 当调用 next() 时，得到 Result
     type Item = Result<In, KvError>;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
-
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            // rbuf 中已经有一个完整的 frame 时，直接从 rbuf 中分离出来并 decode
+            if let Some(len) = frame_len(&this.rbuf) {
+                // 对端给出的长度不可信，超过限制时直接报错，不再为其分配内存
+                if len > LEN_LEN + this.max_frame {
+                    return Poll::Ready(Some(Err(KvError::FrameError)));
+                }
+                if this.rbuf.len() >= len {
+                    let mut frame = this.rbuf.split_to(len);
+                    let res = In::decode_frame_with_limit(&mut frame, this.max_frame);
+                    return Poll::Ready(Some(res));
+                }
+                // 先分配至少一个 frame 的内存
+                this.rbuf.reserve(len - this.rbuf.len());
+            } else {
+                this.rbuf.reserve(LEN_LEN);
+            }
+
+            // 读到的数据会保留在 rbuf 中，因此即便 Pending 之后 future 被丢弃也不会丢失数据
+            let read = poll_read_buf(Pin::new(&mut this.stream), cx, &mut this.rbuf);
+            let n = match ready!(read) {
+                Ok(n) => n,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            };
+
+            if n == 0 {
+                return match this.rbuf.is_empty() {
+                    true => Poll::Ready(None),
+                    false => Poll::Ready(Some(Err(KvError::FrameError))),
+                };
+            }
+        }
     }
 }
 
@@ -86,7 +111,8 @@ where
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        // 超过对端限制的 frame 不会写入 wbuf，stream 可以继续使用
+        item.encode_frame_with_limit(&mut this.wbuf, &this.compression, this.peer_max_frame)?;
         Ok(())
     }
 }
@@ -103,12 +129,38 @@ where
         Self {
             stream,
             written: 0,
+            compression: CompressionConfig::default(),
+            max_frame: MAX_FRAME,
+            peer_max_frame: MAX_FRAME,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            _in: PhantomData,
+            _out: PhantomData,
         }
     }
+
+    /// 设置发送 frame 时使用的压缩配置，通常是握手协商的结果
+    pub fn set_compression(&mut self, compression: CompressionConfig) {
+        self.compression = compression;
+    }
+
+    /// 设置能够接收的最大 frame，超过该值的 frame（包括解压后的数据）会被拒绝
+    pub fn set_max_frame(&mut self, max_frame: usize) {
+        self.max_frame = max_frame.min(MAX_FRAME_LIMIT);
+    }
+
+    /// 设置对端能够接收的最大 frame，通常来自握手时对端的 Hello，0 表示对端没有给出，使用默认值
+    pub fn set_peer_max_frame(&mut self, max_frame: usize) {
+        self.peer_max_frame = match max_frame {
+            0 => MAX_FRAME,
+            n => n.min(MAX_FRAME_LIMIT),
+        };
+    }
+
+    /// 获取底层的 stream，用于在收发 frame 之前完成握手
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
 }
 
 #[cfg(test)]
@@ -132,4 +184,38 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_should_read_frames_in_pieces() -> Result<()> {
+        let (client, server) = tokio::io::duplex(7);
+        let mut client = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+
+        let cmds: Vec<_> = (0..10i64)
+            .map(|i| CommandRequest::new_hset("t1", format!("k{i}"), i.into()).with_id(i as _))
+            .collect();
+        let sent = cmds.clone();
+        tokio::spawn(async move {
+            for cmd in sent.iter() {
+                client.send(cmd).await.unwrap();
+            }
+        });
+
+        for cmd in cmds {
+            assert_eq!(server.next().await.unwrap()?, cmd);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_oversized_frame() -> Result<()> {
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hget("t1", "k1").encode_frame(&mut buf)?;
+        let stream = DummyStream { buf };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_max_frame(4);
+        let res = stream.next().await.unwrap();
+        assert!(matches!(res, Err(KvError::FrameError)));
+        Ok(())
+    }
 }
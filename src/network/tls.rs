@@ -1,28 +1,43 @@
-use crate::KvError;
+use super::crl::{CrlVerifier, Revocations};
+use super::sni::{certified_key, SniCerts, SniResolver};
+use crate::{KvError, SniConfig};
 use std::io::Cursor;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
-use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, Session,
+};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{
     client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
 };
-use tracing::instrument;
+use tracing::{info, instrument};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, oid_registry::*, traits::FromDer,
+};
 
 /// KV Server 自己的 ALPN
 const ALPN_KV: &str = "kv";
 
-/// 存放 TLS ServerConfig 并提供方法 accept 将底层的协议转换成为 TLS
+/// 存放 TLS ServerConfig 并提供方法 accept 将底层的协议转换成为 TLS。
+/// clone 出来的 acceptor 共享同一个配置，reload 之后新的握手都使用新的证书
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
-    inner: Arc<ServerConfig>,
+    inner: Arc<RwLock<Arc<ServerConfig>>>,
+    /// 已经吊销的客户端证书，reload 证书之后继续使用
+    revocations: Arc<RwLock<Revocations>>,
+    /// 签发客户端证书的 CA，用来校验 CRL 的签名
+    client_ca: Arc<RwLock<Vec<Certificate>>>,
+    /// 根据 SNI 选择的证书，reload 默认证书之后继续使用
+    sni: Arc<RwLock<SniCerts>>,
 }
 /// 存放 TLS Client 并提供方法 connect 来将底层协议转换为 TLS
 #[derive(Clone)]
 pub struct TlsClientConnector {
-    pub config: Arc<ClientConfig>,
+    config: Arc<RwLock<Arc<ClientConfig>>>,
     pub domain: Arc<String>,
 }
 
@@ -34,38 +49,32 @@ impl TlsClientConnector {
         identity: Option<(&str, &str)>,
         server_ca: Option<&str>,
     ) -> Result<Self, KvError> {
-        let mut config = ClientConfig::new();
-
-        // 如果有客户端证书，加载之
-        if let Some((cert, key)) = identity {
-            let certs = load_certs(cert)?;
-            let key = load_key(key)?;
-            config.set_single_client_cert(certs, key)?;
-        }
-
-        if let Some(cert) = server_ca {
-            let mut buf = Cursor::new(cert);
-            config.root_store.add_pem_file(&mut buf).unwrap();
-        } else {
-            // 加载本地信任的根证书链
-            config.root_store = match rustls_native_certs::load_native_certs() {
-                Ok(store) | Err((Some(store), _)) => store,
-                Err((None, error)) => return Err(error.into()),
-            };
-        }
-
-        // 如果有签署服务器的 CA 证书，则加载到根证书链中
-        if let Some(cert) = server_ca {
-            let mut buf = Cursor::new(cert);
-            config.root_store.add_pem_file(&mut buf).unwrap();
-        }
-
+        let config = client_config(identity, server_ca)?;
         Ok(Self {
-            config: Arc::new(config),
+            config: Arc::new(RwLock::new(Arc::new(config))),
             domain: Arc::new(domain.into()),
         })
     }
 
+    /// 重新加载客户端证书和 CA 证书，之后建立的连接使用新的配置，已经建立的连接不受影响。
+    /// 加载失败时继续使用原来的配置
+    #[instrument(name = "tls_connect_reload", skip_all)]
+    pub fn reload(
+        &self,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<(), KvError> {
+        let config = client_config(identity, server_ca)?;
+        *self.config.write().unwrap() = Arc::new(config);
+        info!("TLS client config is reloaded");
+        Ok(())
+    }
+
+    /// 当前使用的 ClientConfig
+    pub fn config(&self) -> Arc<ClientConfig> {
+        self.config.read().unwrap().clone()
+    }
+
     /// 触发了 TLS 协议，把底层的 stream 转换成 TLS stream
     #[instrument(name = "tls_client_connect", skip_all)]
     pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
@@ -75,7 +84,7 @@ impl TlsClientConnector {
         let dns = DNSNameRef::try_from_ascii_str(self.domain.as_str())
             .map_err(|_| KvError::Internal("Invalid DNS name".into()))?;
 
-        let stream = TlsConnector::from(self.config.clone())
+        let stream = TlsConnector::from(self.config())
             .connect(dns, stream)
             .await?;
 
@@ -87,34 +96,68 @@ impl TlsServerAcceptor {
     /// 加载 server cert / CA cert 生成 ServerConfig
     #[instrument(name = "tls_acceptor_new", skip_all)]
     pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
-        let certs = load_certs(cert)?;
-        let key = load_key(key)?;
+        let revocations = Arc::new(RwLock::new(Revocations::default()));
+        let sni = Arc::new(RwLock::new(SniCerts::default()));
+        let config = server_config(cert, key, client_ca, &revocations, &sni)?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+            revocations,
+            client_ca: Arc::new(RwLock::new(
+                client_ca.map(load_certs).transpose()?.unwrap_or_default(),
+            )),
+            sni,
+        })
+    }
 
-        let mut config = match client_ca {
-            None => ServerConfig::new(NoClientAuth::new()),
-            Some(cert) => {
-                // 如果 client 证书是由某个 CA 证书签发的，则把这个 CA 证书加载到信任链中
-                let mut cert = Cursor::new(cert);
-                let mut client_root_cert_store = RootCertStore::empty();
-                client_root_cert_store
-                    .add_pem_file(&mut cert)
-                    .map_err(|_| KvError::CertificateParseError("CA", "cert"))?;
-
-                let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
-                ServerConfig::new(client_auth)
-            }
-        };
+    /// 加载根据 SNI 选择的证书，客户端的 SNI 没有匹配时使用默认的证书
+    pub fn with_sni(self, config: &[SniConfig]) -> Result<Self, KvError> {
+        self.reload_sni(config)?;
+        Ok(self)
+    }
 
-        // 加载服务器证书
-        config
-            .set_single_cert(certs, key)
-            .map_err(|_| KvError::CertificateParseError("server", "cert"))?;
+    /// 重新加载根据 SNI 选择的证书和 namespace，替换掉之前加载的所有证书。
+    /// 加载失败时继续使用原来的证书
+    #[instrument(name = "tls_acceptor_reload_sni", skip_all)]
+    pub fn reload_sni(&self, config: &[SniConfig]) -> Result<(), KvError> {
+        let sni = SniCerts::new(config)?;
+        info!("Loaded certificates for {} SNI names", sni.len());
+        *self.sni.write().unwrap() = sni;
+        Ok(())
+    }
 
-        config.set_protocols(&[Vec::from(ALPN_KV)]);
+    /// 连接的 SNI 对应的 namespace，没有配置 namespace 时返回 None
+    pub fn namespace<S>(&self, stream: &ServerTlsStream<S>) -> Option<String> {
+        let name = stream.get_ref().1.get_sni_hostname()?;
+        self.sni.read().unwrap().namespace(name).map(|ns| ns.into())
+    }
 
-        Ok(Self {
-            inner: Arc::new(config),
-        })
+    /// 加载 PEM 格式的 CRL，拒绝其中被吊销的客户端证书，只在设置了 client_ca 时生效。
+    /// CRL 需要由 client_ca 签发
+    pub fn with_crls(self, crls: &[impl AsRef<str>]) -> Result<Self, KvError> {
+        self.reload_crls(crls)?;
+        Ok(self)
+    }
+
+    /// 重新加载 CRL，替换掉之前加载的所有 CRL，之后的握手使用新的 CRL。
+    /// 加载失败时继续使用原来的 CRL
+    #[instrument(name = "tls_acceptor_reload_crls", skip_all)]
+    pub fn reload_crls(&self, crls: &[impl AsRef<str>]) -> Result<(), KvError> {
+        let revocations = Revocations::new(crls, &self.client_ca.read().unwrap())?;
+        info!("Loaded {} revoked certificates", revocations.len());
+        *self.revocations.write().unwrap() = revocations;
+        Ok(())
+    }
+
+    /// 重新加载证书，之后的握手使用新的证书，已经建立的连接不受影响。
+    /// 加载失败时继续使用原来的证书，已经加载的 CRL 需要重新加载才会按照新的 CA 校验
+    #[instrument(name = "tls_acceptor_reload", skip_all)]
+    pub fn reload(&self, cert: &str, key: &str, client_ca: Option<&str>) -> Result<(), KvError> {
+        let config = server_config(cert, key, client_ca, &self.revocations, &self.sni)?;
+        let ca = client_ca.map(load_certs).transpose()?.unwrap_or_default();
+        *self.inner.write().unwrap() = Arc::new(config);
+        *self.client_ca.write().unwrap() = ca;
+        info!("TLS server config is reloaded");
+        Ok(())
     }
 
     /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
@@ -123,17 +166,175 @@ impl TlsServerAcceptor {
     where
         S: AsyncRead + AsyncWrite + Send + Unpin,
     {
-        let acceptor = TlsAcceptor::from(self.inner.clone());
+        let config = self.inner.read().unwrap().clone();
+        let acceptor = TlsAcceptor::from(config);
         Ok(acceptor.accept(stream).await?)
     }
 }
 
-fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
+/// 生成客户端的 ClientConfig
+fn client_config(
+    identity: Option<(&str, &str)>,
+    server_ca: Option<&str>,
+) -> Result<ClientConfig, KvError> {
+    let mut config = ClientConfig::new();
+
+    // 如果有客户端证书，加载之
+    if let Some((cert, key)) = identity {
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+        config.set_single_client_cert(certs, key)?;
+    }
+
+    if let Some(cert) = server_ca {
+        let mut buf = Cursor::new(cert);
+        config.root_store.add_pem_file(&mut buf).unwrap();
+    } else {
+        // 加载本地信任的根证书链
+        config.root_store = match rustls_native_certs::load_native_certs() {
+            Ok(store) | Err((Some(store), _)) => store,
+            Err((None, error)) => return Err(error.into()),
+        };
+    }
+
+    // 如果有签署服务器的 CA 证书，则加载到根证书链中
+    if let Some(cert) = server_ca {
+        let mut buf = Cursor::new(cert);
+        config.root_store.add_pem_file(&mut buf).unwrap();
+    }
+
+    Ok(config)
+}
+
+/// 生成服务端的 ServerConfig，有 client_ca 时要求客户端提供由它签发并且没有被吊销的证书
+fn server_config(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+    revocations: &Arc<RwLock<Revocations>>,
+    sni: &Arc<RwLock<SniCerts>>,
+) -> Result<ServerConfig, KvError> {
+    let default = certified_key(cert, key)?;
+
+    let mut config = match client_ca {
+        None => ServerConfig::new(NoClientAuth::new()),
+        Some(cert) => {
+            // 如果 client 证书是由某个 CA 证书签发的，则把这个 CA 证书加载到信任链中
+            let mut cert = Cursor::new(cert);
+            let mut client_root_cert_store = RootCertStore::empty();
+            client_root_cert_store
+                .add_pem_file(&mut cert)
+                .map_err(|_| KvError::CertificateParseError("CA", "cert"))?;
+
+            let client_auth = AllowAnyAuthenticatedClient::new(client_root_cert_store);
+            ServerConfig::new(Arc::new(CrlVerifier {
+                inner: client_auth,
+                revocations: revocations.clone(),
+            }))
+        }
+    };
+
+    // 加载服务器证书，有匹配的 SNI 时使用 SNI 对应的证书
+    config.cert_resolver = Arc::new(SniResolver {
+        default,
+        sni: sni.clone(),
+    });
+
+    config.set_protocols(&[Vec::from(ALPN_KV)]);
+
+    Ok(config)
+}
+
+/// 客户端证书中的身份信息，用于鉴权
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// subject 的 CN
+    pub common_name: Option<String>,
+    /// 完整的 subject，比如 C=CN,O=Acme Inc.,CN=awesome-device-id
+    pub subject: String,
+    /// subjectAltName 中的 DNS、email、URI 和 IP 地址
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// 用于匹配 ACL 的所有名字：CN、完整的 subject 以及各个 SAN
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.common_name
+            .as_deref()
+            .into_iter()
+            .chain(Some(self.subject.as_str()).filter(|s| !s.is_empty()))
+            .chain(self.sans.iter().map(|s| s.as_str()))
+    }
+}
+
+/// 客户端证书中 subject 的 CN，客户端没有提供证书时返回 None
+pub fn peer_common_name<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    peer_identity(stream)?.common_name
+}
+
+/// 客户端证书中的身份信息，客户端没有提供证书时返回 None
+pub fn peer_identity<S>(stream: &ServerTlsStream<S>) -> Option<PeerIdentity> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    parse_identity(&certs.first()?.0)
+}
+
+/// 从 DER 编码的 X.509 证书中取出 subject 和 subjectAltName
+fn parse_identity(cert: &[u8]) -> Option<PeerIdentity> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let mut identity = PeerIdentity::default();
+    let mut parts = Vec::new();
+    for attr in cert.subject().iter_attributes() {
+        let oid = attr.attr_type();
+        // 不认识的属性不出现在 subject 中
+        let name = if *oid == OID_X509_COMMON_NAME {
+            "CN"
+        } else if *oid == OID_X509_COUNTRY_NAME {
+            "C"
+        } else if *oid == OID_X509_LOCALITY_NAME {
+            "L"
+        } else if *oid == OID_X509_STATE_OR_PROVINCE_NAME {
+            "ST"
+        } else if *oid == OID_X509_ORGANIZATION_NAME {
+            "O"
+        } else if *oid == OID_X509_ORGANIZATIONAL_UNIT {
+            "OU"
+        } else {
+            continue;
+        };
+        let value = attr.as_str().ok()?;
+        if name == "CN" && identity.common_name.is_none() {
+            identity.common_name = Some(value.to_string());
+        }
+        parts.push(format!("{}={}", name, value));
+    }
+    identity.subject = parts.join(",");
+
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        identity.sans = san
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::RFC822Name(name)
+                | GeneralName::DNSName(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => Some(IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                    16 => Some(IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+    }
+    Some(identity)
+}
+
+pub(super) fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
     pemfile::certs(&mut cert).map_err(|_| KvError::CertificateParseError("server", "cert"))
 }
 
-fn load_key(key: &str) -> Result<PrivateKey, KvError> {
+pub(super) fn load_key(key: &str) -> Result<PrivateKey, KvError> {
     let mut cursor = Cursor::new(key);
 
     // 先尝试用 PKCS8 加载密钥
@@ -185,7 +386,10 @@ pub mod tls_utils {
 
 #[cfg(test)]
 mod tests {
-    use super::tls_utils::{tls_acceptor, tls_connector};
+    use super::{
+        load_certs, parse_identity,
+        tls_utils::{tls_acceptor, tls_connector},
+    };
     use anyhow::Result;
     use std::net::SocketAddr;
     use std::sync::Arc;
@@ -235,6 +439,67 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn reloaded_acceptor_should_be_used_for_new_handshakes() -> Result<()> {
+        let acceptor = tls_acceptor(false)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = acceptor.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = server.accept(stream).await {
+                    let mut buf = [0; 12];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&buf).await;
+                    }
+                }
+            }
+        });
+        let echo = |client_cert: bool| async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = tls_connector(client_cert)?.connect(stream).await?;
+            stream.write_all(b"hello world!").await?;
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await?;
+            anyhow::Ok(buf)
+        };
+        assert_eq!(&echo(false).await?, b"hello world!");
+
+        // 加载失败时继续使用原来的证书
+        let cert = include_str!("../../fixtures/server.cert");
+        let key = include_str!("../../fixtures/server.key");
+        let ca = include_str!("../../fixtures/ca.cert");
+        assert!(acceptor.reload(cert, "invalid", Some(ca)).is_err());
+        assert_eq!(&echo(false).await?, b"hello world!");
+
+        // 重新加载之后要求客户端证书
+        acceptor.reload(cert, key, Some(ca))?;
+        assert!(echo(false).await.is_err());
+        assert_eq!(&echo(true).await?, b"hello world!");
+        Ok(())
+    }
+
+    #[test]
+    fn common_name_should_be_parsed() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        let identity = parse_identity(&certs[0].0).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("awesome-device-id"));
+
+        assert_eq!(parse_identity(b"\x30\x82\xff"), None);
+    }
+
+    #[test]
+    fn identity_should_be_parsed() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        let identity = parse_identity(&certs[0].0).unwrap();
+        assert_eq!(identity.subject, "C=CN,O=Acme Inc.,CN=awesome-device-id");
+        assert!(identity.sans.is_empty());
+
+        let certs = load_certs(include_str!("../../fixtures/server.cert")).unwrap();
+        let identity = parse_identity(&certs[0].0).unwrap();
+        assert!(identity.sans.contains(&"kvserver.acme.inc".to_string()));
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(false).await?;
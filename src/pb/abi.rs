@@ -1,9 +1,18 @@
 /// 来自客户端的命令请求
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// 请求 id，用于在同一个 stream 上匹配乱序返回的响应，0 表示不需要匹配
+    #[prost(uint32, tag = "15")]
+    pub id: u32,
+    /// 请求的超时时间（毫秒），0 表示不限制
+    #[prost(uint64, tag = "16")]
+    pub timeout_ms: u64,
+    /// 读请求所属的 Track 订阅 id，非 0 时服务端会记住读取的 key，并在 key 被修改时推送失效通知
+    #[prost(uint32, tag = "22")]
+    pub tracking: u32,
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 17, 18, 19, 20, 21, 23, 24"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
@@ -39,6 +48,20 @@ pub mod command_request {
         Psubscribe(super::PSubscribe),
         #[prost(message, tag = "14")]
         Punsubscribe(super::PUnsubscribe),
+        #[prost(message, tag = "17")]
+        Cancel(super::Cancel),
+        #[prost(message, tag = "18")]
+        Upload(super::Upload),
+        #[prost(message, tag = "19")]
+        Download(super::Download),
+        #[prost(message, tag = "20")]
+        Ping(super::Ping),
+        #[prost(message, tag = "21")]
+        Track(super::Track),
+        #[prost(message, tag = "23")]
+        Auth(super::Auth),
+        #[prost(message, tag = "24")]
+        Custom(super::Custom),
     }
 }
 /// 服务端的命令响应
@@ -56,6 +79,9 @@ pub struct CommandResponse {
     /// 成功返回 Kv pairs，针对 getall 等命令
     #[prost(message, repeated, tag = "4")]
     pub kvpairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 对应请求的 id
+    #[prost(uint32, tag = "5")]
+    pub id: u32,
 }
 /// get 相关命令
 #[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
@@ -150,6 +176,61 @@ pub struct PUnsubscribe {
     #[prost(uint32, tag = "2")]
     pub id: u32,
 }
+/// 取消同一个 stream 上尚未完成的请求
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Cancel {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+}
+/// 分块上传一个超过 frame 大小限制的 value
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Upload {
+    /// 第一个分块为 0，服务端会在响应中返回分配的上传 id，后续分块需要带上
+    #[prost(uint32, tag = "1")]
+    pub upload_id: u32,
+    #[prost(string, tag = "2")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub key: ::prost::alloc::string::String,
+    /// 本分块在 value 中的偏移，必须等于服务端已经收到的数据长度
+    #[prost(uint64, tag = "4")]
+    pub offset: u64,
+    #[prost(bytes = "bytes", tag = "5")]
+    pub data: ::prost::bytes::Bytes,
+    /// 最后一个分块，服务端收到后把完整的 value 写入存储
+    #[prost(bool, tag = "6")]
+    pub finish: bool,
+}
+/// 分块下载一个 value，第一个响应返回 value 的总长度，之后的响应依次返回各个分块
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Download {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 心跳，服务端返回 PONG，用于检测失联的连接
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Ping {}
+/// 开启客户端缓存的失效通知，第一个响应返回订阅 id，之后每个响应是 [table, key...] 形式的失效通知
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Track {}
+/// 使用用户名和密码认证当前的 stream，服务端开启认证后，认证之前只能执行 Auth
+#[derive(PartialOrd, Eq, Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag = "1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub password: ::prost::alloc::string::String,
+}
+/// 执行服务端注册的自定义命令
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Custom {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
 /// 发布数据到某个主题
 #[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
@@ -188,3 +269,28 @@ pub struct Kvpair {
     #[prost(message, optional, tag = "2")]
     pub value: ::core::option::Option<Value>,
 }
+/// 连接（或 yamux stream）建立时双方交换的握手消息
+#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    /// 协议版本，服务端回复的是双方协商后的版本
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    /// 支持的命令
+    #[prost(string, repeated, tag = "2")]
+    pub commands: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 客户端：按偏好排列的压缩算法；服务端：最终选定的压缩算法
+    #[prost(uint32, repeated, tag = "3")]
+    pub codecs: ::prost::alloc::vec::Vec<u32>,
+    /// 压缩级别
+    #[prost(uint32, tag = "4")]
+    pub level: u32,
+    /// 超过该阈值的 payload 才会被压缩
+    #[prost(uint64, tag = "5")]
+    pub threshold: u64,
+    /// 能够接收的最大 frame
+    #[prost(uint64, tag = "6")]
+    pub max_frame_size: u64,
+    /// 服务端标识
+    #[prost(string, tag = "7")]
+    pub server: ::prost::alloc::string::String,
+}
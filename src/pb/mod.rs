@@ -2,10 +2,40 @@ pub mod abi;
 
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
+use glob::Pattern;
 use http::StatusCode;
 use prost::Message;
-
-use crate::KvError;
+use std::time::Duration;
+
+use crate::{CompressionCodec, CompressionConfig, KvError, MAX_FRAME, SUPPORTED_CODECS};
+
+/// 当前的协议版本
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 当前版本支持的所有命令
+pub const COMMANDS: [&str; 21] = [
+    "hget",
+    "hmget",
+    "hgetall",
+    "hset",
+    "hmset",
+    "hdel",
+    "hmdel",
+    "hexist",
+    "hmexist",
+    "subscribe",
+    "unsubscribe",
+    "publish",
+    "psubscribe",
+    "punsubscribe",
+    "cancel",
+    "upload",
+    "download",
+    "ping",
+    "track",
+    "auth",
+    "custom",
+];
 
 impl CommandRequest {
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
@@ -14,6 +44,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -23,6 +54,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -31,6 +63,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -40,6 +73,7 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -49,6 +83,7 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
@@ -58,6 +93,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -67,6 +103,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -76,6 +113,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -85,6 +123,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -93,6 +132,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Subscribe(Subscribe {
                 topic: topic.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -101,6 +141,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Psubscribe(PSubscribe {
                 pattern: pattern.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -110,6 +151,7 @@ impl CommandRequest {
                 topic: topic.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -119,6 +161,7 @@ impl CommandRequest {
                 pattern: pattern.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -128,6 +171,217 @@ impl CommandRequest {
                 topic: topic.into(),
                 value: values,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_cancel(id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Cancel(Cancel { id })),
+            ..Default::default()
+        }
+    }
+
+    /// 上传一个分块，第一个分块的 upload_id 为 0
+    pub fn new_upload(
+        upload_id: u32,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        offset: u64,
+        data: Bytes,
+        finish: bool,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Upload(Upload {
+                upload_id,
+                table: table.into(),
+                key: key.into(),
+                offset,
+                data,
+                finish,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_download(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Download(Download {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_ping() -> Self {
+        Self {
+            request_data: Some(RequestData::Ping(Ping {})),
+            ..Default::default()
+        }
+    }
+
+    /// 开启客户端缓存的失效通知
+    pub fn new_track() -> Self {
+        Self {
+            request_data: Some(RequestData::Track(Track {})),
+            ..Default::default()
+        }
+    }
+
+    /// 使用用户名和密码认证当前的 stream
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                username: username.into(),
+                password: password.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 执行服务端注册的自定义命令
+    pub fn new_custom(name: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Custom(Custom {
+                name: name.into(),
+                args,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 设置请求 id，在同一个 stream 上并发发送请求时用于匹配响应
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// 设置请求的超时时间，服务端在超时后不再等待执行结果并返回超时错误。
+    /// 已经开始执行的存储调用不会被中断，超时的写请求可能已经生效
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout_ms = timeout.as_millis().max(1) as _;
+        self
+    }
+
+    /// 设置读请求所属的 Track 订阅，服务端会在读取的 key 被修改时通过该订阅推送失效通知
+    pub fn with_tracking(mut self, id: u32) -> Self {
+        self.tracking = id;
+        self
+    }
+
+    /// 请求的超时时间，None 表示不限制
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// 命令访问的 table，不访问 table 的命令返回 None
+    pub fn table(&self) -> Option<&str> {
+        match &self.request_data {
+            Some(RequestData::Hgetall(v)) => Some(&v.table),
+            _ => self.keys().map(|(table, _)| table),
+        }
+    }
+
+    /// 在命令访问的 table 前面加上 prefix，用于隔离不同 namespace 的数据
+    pub fn with_table_prefix(mut self, prefix: &str) -> Self {
+        let table = match &mut self.request_data {
+            Some(RequestData::Hget(v)) => &mut v.table,
+            Some(RequestData::Hmget(v)) => &mut v.table,
+            Some(RequestData::Hgetall(v)) => &mut v.table,
+            Some(RequestData::Hset(v)) => &mut v.table,
+            Some(RequestData::Hmset(v)) => &mut v.table,
+            Some(RequestData::Hdel(v)) => &mut v.table,
+            Some(RequestData::Hmdel(v)) => &mut v.table,
+            Some(RequestData::Hexist(v)) => &mut v.table,
+            Some(RequestData::Hmexist(v)) => &mut v.table,
+            Some(RequestData::Upload(v)) => &mut v.table,
+            Some(RequestData::Download(v)) => &mut v.table,
+            _ => return self,
+        };
+        table.insert_str(0, prefix);
+        self
+    }
+
+    /// 在命令访问的 topic 前面加上 prefix，pattern 中的 prefix 会被转义，只能匹配 prefix 下的 topic
+    pub fn with_topic_prefix(mut self, prefix: &str) -> Self {
+        match &mut self.request_data {
+            Some(RequestData::Subscribe(v)) => v.topic.insert_str(0, prefix),
+            Some(RequestData::Unsubscribe(v)) => v.topic.insert_str(0, prefix),
+            Some(RequestData::Publish(v)) => v.topic.insert_str(0, prefix),
+            Some(RequestData::Psubscribe(v)) => v.pattern.insert_str(0, &Pattern::escape(prefix)),
+            Some(RequestData::Punsubscribe(v)) => v.pattern.insert_str(0, &Pattern::escape(prefix)),
+            _ => {}
+        }
+        self
+    }
+
+    /// 命令访问的 topic，psubscribe 和 punsubscribe 返回 pattern
+    pub fn topic(&self) -> Option<&str> {
+        match &self.request_data {
+            Some(RequestData::Subscribe(v)) => Some(&v.topic),
+            Some(RequestData::Unsubscribe(v)) => Some(&v.topic),
+            Some(RequestData::Publish(v)) => Some(&v.topic),
+            Some(RequestData::Psubscribe(v)) => Some(&v.pattern),
+            Some(RequestData::Punsubscribe(v)) => Some(&v.pattern),
+            _ => None,
+        }
+    }
+
+    /// 命令访问的 table 和 key，不针对具体 key 的命令返回 None
+    pub fn keys(&self) -> Option<(&str, Vec<&str>)> {
+        fn strs(keys: &[String]) -> Vec<&str> {
+            keys.iter().map(|k| k.as_str()).collect()
+        }
+        match &self.request_data {
+            Some(RequestData::Hget(v)) => Some((&v.table, vec![&v.key])),
+            Some(RequestData::Hmget(v)) => Some((&v.table, strs(&v.keys))),
+            Some(RequestData::Hset(v)) => {
+                let key = v.pair.as_ref().map(|p| p.key.as_str()).unwrap_or_default();
+                Some((&v.table, vec![key]))
+            }
+            Some(RequestData::Hmset(v)) => {
+                let keys = v.pairs.iter().map(|p| p.key.as_str()).collect();
+                Some((&v.table, keys))
+            }
+            Some(RequestData::Hdel(v)) => Some((&v.table, vec![&v.key])),
+            Some(RequestData::Hmdel(v)) => Some((&v.table, strs(&v.keys))),
+            Some(RequestData::Hexist(v)) => Some((&v.table, vec![&v.key])),
+            Some(RequestData::Hmexist(v)) => Some((&v.table, strs(&v.keys))),
+            Some(RequestData::Upload(v)) => Some((&v.table, vec![&v.key])),
+            Some(RequestData::Download(v)) => Some((&v.table, vec![&v.key])),
+            _ => None,
+        }
+    }
+
+    /// 命令的名字，和握手时声明的命令一一对应
+    pub fn name(&self) -> &'static str {
+        match self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Psubscribe(_)) => "psubscribe",
+            Some(RequestData::Punsubscribe(_)) => "punsubscribe",
+            Some(RequestData::Cancel(_)) => "cancel",
+            Some(RequestData::Upload(_)) => "upload",
+            Some(RequestData::Download(_)) => "download",
+            Some(RequestData::Ping(_)) => "ping",
+            Some(RequestData::Track(_)) => "track",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Custom(_)) => "custom",
+            None => "",
         }
     }
 }
@@ -138,6 +392,41 @@ impl Value {
     }
 }
 
+impl Hello {
+    /// 根据期望的压缩配置创建客户端的握手消息，首选算法排在最前面
+    pub fn new(compression: &CompressionConfig) -> Self {
+        let codecs = match compression.codec {
+            CompressionCodec::None => vec![CompressionCodec::None],
+            preferred => std::iter::once(preferred)
+                .chain(
+                    SUPPORTED_CODECS
+                        .into_iter()
+                        .filter(|c| *c != preferred && *c != CompressionCodec::None),
+                )
+                .collect(),
+        };
+
+        Self {
+            version: PROTOCOL_VERSION,
+            commands: COMMANDS.iter().map(|c| c.to_string()).collect(),
+            codecs: codecs.into_iter().map(|c| c.bits() as _).collect(),
+            level: compression.level,
+            threshold: compression.threshold as _,
+            max_frame_size: MAX_FRAME as _,
+            server: String::new(),
+        }
+    }
+
+    /// 对端是否支持某个命令
+    pub fn supports(&self, name: &str) -> bool {
+        self.commands.iter().any(|c| c == name)
+    }
+
+    pub fn format(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
 impl CommandResponse {
     pub fn ok() -> Self {
         Self {
@@ -148,6 +437,14 @@ impl CommandResponse {
     pub fn format(&self) -> String {
         format!("{self:?}")
     }
+
+    /// 把非 2XX 的响应转换成对应的 KvError
+    pub fn into_result(self) -> Result<Self, KvError> {
+        match self.status {
+            200..=299 => Ok(self),
+            _ => Err((&self).into()),
+        }
+    }
 }
 
 impl Kvpair {
@@ -305,22 +602,82 @@ impl From<Value> for CommandResponse {
 
 impl From<KvError> for CommandResponse {
     fn from(error: KvError) -> Self {
+        (&error).into()
+    }
+}
+
+impl From<&KvError> for CommandResponse {
+    fn from(error: &KvError) -> Self {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: error.to_string(),
-            values: vec![],
-            kvpairs: vec![],
+            ..Default::default()
         };
 
         match error {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::InvalidFrame(_) | KvError::DecodeError(_) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::FrameError => result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _,
+            KvError::Timeout(_) => result.status = StatusCode::REQUEST_TIMEOUT.as_u16() as _,
+            KvError::ShuttingDown | KvError::Overloaded => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::RateLimited(_) => result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::Unauthenticated(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::UnsupportedCommand(_) => {
+                result.status = StatusCode::NOT_IMPLEMENTED.as_u16() as _
+            }
             _ => {}
         }
         result
     }
 }
 
+/// 从错误响应中还原 KvError，状态码无法区分的错误都还原成 KvError::Internal
+impl From<&CommandResponse> for KvError {
+    fn from(res: &CommandResponse) -> Self {
+        let msg = res.message.as_str();
+        // 去掉 KvError 在 Display 时添加的前缀
+        let detail = |empty: KvError| {
+            let prefix = empty.to_string();
+            msg.strip_prefix(prefix.as_str()).unwrap_or(msg).to_owned()
+        };
+
+        match StatusCode::from_u16(res.status as _) {
+            Ok(StatusCode::BAD_REQUEST) => {
+                KvError::InvalidCommand(detail(KvError::InvalidCommand(String::new())))
+            }
+            Ok(StatusCode::NOT_FOUND) => {
+                KvError::NotFound(detail(KvError::NotFound(String::new())))
+            }
+            Ok(StatusCode::REQUEST_TIMEOUT) => {
+                KvError::Timeout(detail(KvError::Timeout(String::new())))
+            }
+            Ok(StatusCode::PAYLOAD_TOO_LARGE) => KvError::FrameError,
+            Ok(StatusCode::UNAUTHORIZED) => {
+                KvError::Unauthenticated(detail(KvError::Unauthenticated(String::new())))
+            }
+            Ok(StatusCode::FORBIDDEN) => {
+                KvError::PermissionDenied(detail(KvError::PermissionDenied(String::new())))
+            }
+            Ok(StatusCode::TOO_MANY_REQUESTS) => {
+                KvError::RateLimited(detail(KvError::RateLimited(String::new())))
+            }
+            Ok(StatusCode::NOT_IMPLEMENTED) => {
+                KvError::UnsupportedCommand(detail(KvError::UnsupportedCommand(String::new())))
+            }
+            Ok(StatusCode::SERVICE_UNAVAILABLE) if msg == KvError::ShuttingDown.to_string() => {
+                KvError::ShuttingDown
+            }
+            Ok(StatusCode::SERVICE_UNAVAILABLE) => KvError::Overloaded,
+            _ => KvError::Internal(detail(KvError::Internal(String::new()))),
+        }
+    }
+}
+
 impl From<Vec<Value>> for CommandResponse {
     fn from(values: Vec<Value>) -> Self {
         Self {
@@ -348,9 +705,27 @@ impl TryFrom<&CommandResponse> for i64 {
         if value.status != StatusCode::OK.as_u16() as u32 {
             return Err(KvError::ConvertError(value.format(), "CommandResponse"));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(value.format(), "CommandResponse")),
         }
     }
 }
+
+impl TryFrom<&Hello> for CompressionConfig {
+    type Error = KvError;
+
+    fn try_from(hello: &Hello) -> Result<Self, Self::Error> {
+        let codec = match hello.codecs[..] {
+            [codec] => CompressionCodec::from_bits(codec as _),
+            _ => None,
+        };
+        let codec =
+            codec.ok_or_else(|| KvError::ConvertError(hello.format(), "CompressionConfig"))?;
+        Ok(Self {
+            codec,
+            level: hello.level,
+            threshold: hello.threshold as _,
+        })
+    }
+}
@@ -0,0 +1,614 @@
+use crate::{KvClient, KvError, Kvpair, PoolConfig, Subscription, Value};
+use futures::{future::BoxFuture, Future, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, mpsc, Mutex},
+    task::JoinHandle,
+    time,
+};
+use tracing::{info, warn};
+
+/// 事件 channel 的容量，来不及处理的事件会被丢弃
+const EVENT_CAPACITY: usize = 64;
+
+/// 订阅数据在转发给调用者之前最多缓存的数量
+const SUBSCRIPTION_CAPACITY: usize = 128;
+
+/// 创建新连接的函数
+type Connector<S> = Arc<dyn Fn() -> BoxFuture<'static, Result<KvClient<S>, KvError>> + Send + Sync>;
+
+/// 连接池中连接状态的变化
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// 连接断开
+    Disconnected { slot: usize },
+    /// 断开的连接重新建立，attempts 是本次重连尝试的次数
+    Reconnected { slot: usize, attempts: u32 },
+    /// 重连的次数达到上限，之后取用该连接时会再次重连
+    ReconnectFailed { slot: usize, error: String },
+    /// 订阅在重连后恢复，id 是新的订阅 id
+    Resubscribed { topic: String, id: u32 },
+}
+
+/// 管理多个 KvClient 的连接池。连接断开后会按照指数退避的方式重连，
+/// 幂等的读请求会在重连后重试，订阅会在重连后自动恢复
+pub struct KvPool<S> {
+    inner: Arc<PoolInner<S>>,
+}
+
+struct PoolInner<S> {
+    connector: Connector<S>,
+    config: PoolConfig,
+    slots: Vec<Mutex<Slot<S>>>,
+    next: AtomicUsize,
+    events: broadcast::Sender<PoolEvent>,
+}
+
+struct Slot<S> {
+    client: Option<Arc<KvClient<S>>>,
+    /// 是否曾经连接成功，用于区分首次连接和重连
+    connected: bool,
+}
+
+/// 连接池上的订阅，连接断开后会重新订阅，订阅 id 也会随之改变
+pub struct PoolSubscription {
+    channel: Channel,
+    id: Arc<AtomicU32>,
+    receiver: mpsc::Receiver<Result<Vec<Value>, KvError>>,
+    task: JoinHandle<()>,
+}
+
+/// 订阅的 topic 或者 pattern
+#[derive(Clone, Debug)]
+enum Channel {
+    Topic(String),
+    Pattern(String),
+}
+
+impl<S> Clone for KvPool<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S> KvPool<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    /// 创建连接池，connect 用于建立新的连接。连接在第一次使用时才会建立
+    pub fn new<F, Fut>(config: PoolConfig, connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<KvClient<S>, KvError>> + Send + 'static,
+    {
+        let slots = (0..config.size.max(1))
+            .map(|_| {
+                Mutex::new(Slot {
+                    client: None,
+                    connected: false,
+                })
+            })
+            .collect();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            inner: Arc::new(PoolInner {
+                connector: Arc::new(move || Box::pin(connect())),
+                config,
+                slots,
+                next: AtomicUsize::new(0),
+                events,
+            }),
+        }
+    }
+
+    /// 订阅连接状态变化的事件
+    pub fn events(&self) -> broadcast::Receiver<PoolEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// 取出一个可用的连接，连接已经断开时先重连
+    pub async fn client(&self) -> Result<Arc<KvClient<S>>, KvError> {
+        self.checkout().await.map(|(_, client)| client)
+    }
+
+    /// 获取 key 对应的 value，key 不存在时返回 None
+    pub async fn hget(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.read(|c| async move { c.hget(table, key).await }).await
+    }
+
+    /// 获取多个 key 对应的 value，不存在的 key 对应 None
+    pub async fn hmget(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        self.read(|c| {
+            let keys = keys.clone();
+            async move { c.hmget(table, keys).await }
+        })
+        .await
+    }
+
+    /// 获取 table 中所有的 kv pair
+    pub async fn hgetall(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|c| async move { c.hgetall(table).await }).await
+    }
+
+    /// key 是否存在
+    pub async fn exists(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.read(|c| async move { c.exists(table, key).await })
+            .await
+    }
+
+    /// 多个 key 是否存在
+    pub async fn hmexist(&self, table: &str, keys: Vec<String>) -> Result<Vec<bool>, KvError> {
+        self.read(|c| {
+            let keys = keys.clone();
+            async move { c.hmexist(table, keys).await }
+        })
+        .await
+    }
+
+    /// 设置 key 对应的 value，返回之前的 value
+    pub async fn hset(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let value = value.into();
+        self.write(|c| async move { c.hset(table, key, value).await })
+            .await
+    }
+
+    /// 设置多个 kv pair，返回之前的 value
+    pub async fn hmset(
+        &self,
+        table: &str,
+        pairs: Vec<Kvpair>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        self.write(|c| async move { c.hmset(table, pairs).await })
+            .await
+    }
+
+    /// 删除 key，返回被删除的 value
+    pub async fn hdel(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|c| async move { c.hdel(table, key).await })
+            .await
+    }
+
+    /// 删除多个 key，返回被删除的 value
+    pub async fn hmdel(
+        &self,
+        table: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        self.write(|c| async move { c.hmdel(table, keys).await })
+            .await
+    }
+
+    /// 发布数据到 topic
+    pub async fn publish(&self, topic: &str, values: Vec<Value>) -> Result<(), KvError> {
+        self.write(|c| async move { c.publish(topic, values).await })
+            .await
+    }
+
+    /// 检查服务端是否可用
+    pub async fn ping(&self) -> Result<(), KvError> {
+        self.read(|c| async move { c.ping().await }).await
+    }
+
+    /// 订阅 topic，连接断开后会自动重新订阅
+    pub async fn subscribe(&self, topic: &str) -> Result<PoolSubscription, KvError> {
+        self.watch(Channel::Topic(topic.into())).await
+    }
+
+    /// 订阅所有匹配 pattern 的 topic，连接断开后会自动重新订阅
+    pub async fn psubscribe(&self, pattern: &str) -> Result<PoolSubscription, KvError> {
+        self.watch(Channel::Pattern(pattern.into())).await
+    }
+
+    /// 退订，之后不再重新订阅
+    pub async fn unsubscribe(&self, sub: PoolSubscription) -> Result<(), KvError> {
+        sub.task.abort();
+        let (channel, id) = (&sub.channel, sub.id());
+        self.write(|c| async move { channel.unsubscribe(&c, id).await })
+            .await
+    }
+
+    async fn watch(&self, channel: Channel) -> Result<PoolSubscription, KvError> {
+        let sub = self.subscribe_channel(&channel).await?;
+        let id = Arc::new(AtomicU32::new(sub.id));
+        let (tx, receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        let task = tokio::spawn(forward(self.clone(), channel.clone(), sub, id.clone(), tx));
+        Ok(PoolSubscription {
+            channel,
+            id,
+            receiver,
+            task,
+        })
+    }
+
+    async fn subscribe_channel(&self, channel: &Channel) -> Result<Subscription, KvError> {
+        self.read(|c| async move { channel.subscribe(&c).await })
+            .await
+    }
+
+    /// 执行幂等的请求，连接断开时重连并重试
+    async fn read<T, F, Fut>(&self, f: F) -> Result<T, KvError>
+    where
+        F: Fn(Arc<KvClient<S>>) -> Fut,
+        Fut: Future<Output = Result<T, KvError>>,
+    {
+        self.execute(f, self.inner.config.max_retries).await
+    }
+
+    /// 执行非幂等的请求，连接断开时不重试，下一次取用连接时再重连
+    async fn write<T, F, Fut>(&self, f: F) -> Result<T, KvError>
+    where
+        F: FnOnce(Arc<KvClient<S>>) -> Fut,
+        Fut: Future<Output = Result<T, KvError>>,
+    {
+        let (index, client) = self.checkout().await?;
+        let res = f(client.clone()).await;
+        if matches!(&res, Err(e) if is_disconnected(e)) {
+            self.invalidate(index, &client).await;
+        }
+        res
+    }
+
+    async fn execute<T, F, Fut>(&self, f: F, max_retries: u32) -> Result<T, KvError>
+    where
+        F: Fn(Arc<KvClient<S>>) -> Fut,
+        Fut: Future<Output = Result<T, KvError>>,
+    {
+        let mut retries = 0;
+        loop {
+            let (index, client) = self.checkout().await?;
+            match f(client.clone()).await {
+                Err(e) if is_disconnected(&e) => {
+                    self.invalidate(index, &client).await;
+                    if retries >= max_retries {
+                        return Err(e);
+                    }
+                    retries += 1;
+                    warn!(
+                        "Request failed: {}, retrying ({}/{})",
+                        e, retries, max_retries
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// 按顺序轮流使用连接池中的连接，连接已经断开时重连
+    async fn checkout(&self) -> Result<(usize, Arc<KvClient<S>>), KvError> {
+        let inner = &self.inner;
+        let index = inner.next.fetch_add(1, Ordering::Relaxed) % inner.slots.len();
+        let mut slot = inner.slots[index].lock().await;
+        if let Some(client) = &slot.client {
+            if !client.is_closed() {
+                return Ok((index, client.clone()));
+            }
+            slot.client = None;
+            self.emit(PoolEvent::Disconnected { slot: index });
+        }
+
+        // 持有 slot 的锁进行重连，同时取用这个 slot 的请求会等待同一次重连的结果
+        let (client, attempts) = match self.connect().await {
+            Ok(v) => v,
+            Err(e) => {
+                if slot.connected {
+                    let error = e.to_string();
+                    self.emit(PoolEvent::ReconnectFailed { slot: index, error });
+                }
+                return Err(e);
+            }
+        };
+        if slot.connected {
+            self.emit(PoolEvent::Reconnected {
+                slot: index,
+                attempts,
+            });
+        }
+        let client = Arc::new(client);
+        slot.client = Some(client.clone());
+        slot.connected = true;
+        Ok((index, client))
+    }
+
+    /// 按照指数退避的方式建立连接，返回新的连接和尝试的次数
+    async fn connect(&self) -> Result<(KvClient<S>, u32), KvError> {
+        let config = &self.inner.config;
+        let max_backoff = Duration::from_millis(config.max_backoff);
+        let mut backoff = Duration::from_millis(config.min_backoff);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match (self.inner.connector)().await {
+                Ok(client) => return Ok((client, attempts)),
+                Err(e) if attempts >= config.reconnect_attempts.max(1) => return Err(e),
+                Err(e) => {
+                    warn!("Failed to connect: {}, retry in {:?}", e, backoff);
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+            }
+        }
+    }
+
+    /// 请求因为连接断开而失败时，丢弃这个连接
+    async fn invalidate(&self, index: usize, client: &Arc<KvClient<S>>) {
+        let mut slot = self.inner.slots[index].lock().await;
+        if matches!(&slot.client, Some(c) if Arc::ptr_eq(c, client)) {
+            slot.client = None;
+            self.emit(PoolEvent::Disconnected { slot: index });
+        }
+    }
+
+    fn emit(&self, event: PoolEvent) {
+        info!("Connection pool: {:?}", event);
+        // 没有人关心事件时直接丢弃
+        let _ = self.inner.events.send(event);
+    }
+}
+
+impl PoolSubscription {
+    /// 当前的订阅 id，重新订阅后会改变
+    pub fn id(&self) -> u32 {
+        self.id.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for PoolSubscription {
+    type Item = Result<Vec<Value>, KvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for PoolSubscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Channel {
+    fn name(&self) -> &str {
+        match self {
+            Self::Topic(name) | Self::Pattern(name) => name,
+        }
+    }
+
+    async fn subscribe<S>(&self, client: &KvClient<S>) -> Result<Subscription, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            Self::Topic(topic) => client.subscribe(topic).await,
+            Self::Pattern(pattern) => client.psubscribe(pattern).await,
+        }
+    }
+
+    async fn unsubscribe<S>(&self, client: &KvClient<S>, id: u32) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            Self::Topic(topic) => client.unsubscribe(topic, id).await,
+            Self::Pattern(pattern) => client.punsubscribe(pattern, id).await,
+        }
+    }
+}
+
+/// 把订阅的数据转发给 PoolSubscription，订阅因为连接断开而结束时重新订阅
+async fn forward<S>(
+    pool: KvPool<S>,
+    channel: Channel,
+    mut sub: Subscription,
+    id: Arc<AtomicU32>,
+    tx: mpsc::Sender<Result<Vec<Value>, KvError>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    let backoff = Duration::from_millis(pool.inner.config.max_backoff);
+    loop {
+        while let Some(res) = sub.next().await {
+            match res {
+                Err(e) if is_disconnected(&e) => break,
+                res => {
+                    if tx.send(res).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        warn!(
+            "Subscription to {} is interrupted, resubscribing",
+            channel.name()
+        );
+        sub = loop {
+            match pool.subscribe_channel(&channel).await {
+                Ok(sub) => break sub,
+                Err(e) if is_disconnected(&e) => {
+                    warn!("Failed to resubscribe to {}: {}", channel.name(), e);
+                    time::sleep(backoff).await;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        };
+        id.store(sub.id, Ordering::Relaxed);
+        pool.emit(PoolEvent::Resubscribed {
+            topic: channel.name().into(),
+            id: sub.id,
+        });
+    }
+}
+
+/// 连接断开或者服务器正在关闭，换一个连接后请求可能成功
+fn is_disconnected(e: &KvError) -> bool {
+    matches!(
+        e,
+        KvError::ConnectionClosed(_) | KvError::IOError(_) | KvError::ShuttingDown
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner, YamuxCtrl};
+    use anyhow::Result;
+    use std::mem;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+    /// 在内存中建立连接的服务器，所有连接共享同一个 Service
+    struct TestServer {
+        service: Service,
+        conns: std::sync::Mutex<Vec<YamuxCtrl<DuplexStream>>>,
+        /// 接下来需要失败的连接次数
+        failures: AtomicU32,
+    }
+
+    impl TestServer {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                service: ServiceInner::new(MemTable::new()).into(),
+                conns: Default::default(),
+                failures: AtomicU32::new(0),
+            })
+        }
+
+        fn pool(self: &Arc<Self>) -> KvPool<DuplexStream> {
+            let config = PoolConfig {
+                size: 1,
+                min_backoff: 10,
+                max_backoff: 50,
+                ..Default::default()
+            };
+            let server = self.clone();
+            KvPool::new(config, move || {
+                let server = server.clone();
+                async move { server.connect().await }
+            })
+        }
+
+        async fn connect(&self) -> Result<KvClient<DuplexStream>, KvError> {
+            let failures = self.failures.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::Relaxed);
+                return Err(KvError::ConnectionClosed("connection refused".into()));
+            }
+
+            let (client, server) = duplex(64 * 1024);
+            let service = self.service.clone();
+            let ctrl = YamuxCtrl::new_server(server, None, move |stream| {
+                let service = service.clone();
+                async move {
+                    let _ = ProstServerStream::new(stream.compat(), service)
+                        .process()
+                        .await;
+                    Ok(())
+                }
+            });
+            self.conns.lock().unwrap().push(ctrl);
+            KvClient::new(YamuxCtrl::new_client(client, None)).await
+        }
+
+        /// 断开所有的连接，并让接下来的 failures 次连接失败
+        async fn restart(&self, failures: u32) {
+            self.failures.store(failures, Ordering::Relaxed);
+            let conns = mem::take(&mut *self.conns.lock().unwrap());
+            for mut ctrl in conns {
+                let _ = ctrl.close().await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_should_reconnect_and_retry_reads() -> Result<()> {
+        let server = TestServer::new();
+        let pool = server.pool();
+        let mut events = pool.events();
+
+        pool.hset("t1", "k1", "v1").await?;
+        server.restart(2).await;
+
+        assert_eq!(pool.hget("t1", "k1").await?, Some("v1".into()));
+        assert_eq!(events.recv().await?, PoolEvent::Disconnected { slot: 0 });
+        let event = events.recv().await?;
+        assert_eq!(
+            event,
+            PoolEvent::Reconnected {
+                slot: 0,
+                attempts: 3
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_report_failed_reconnect() -> Result<()> {
+        let server = TestServer::new();
+        let pool = server.pool();
+        let mut events = pool.events();
+
+        pool.ping().await?;
+        server.restart(u32::MAX).await;
+
+        assert!(pool.ping().await.is_err());
+        assert_eq!(events.recv().await?, PoolEvent::Disconnected { slot: 0 });
+        assert!(matches!(
+            events.recv().await?,
+            PoolEvent::ReconnectFailed { slot: 0, .. }
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_resume_after_reconnect() -> Result<()> {
+        let server = TestServer::new();
+        let pool = server.pool();
+        let mut events = pool.events();
+
+        let mut sub = pool.subscribe("lobby").await?;
+        let old_id = sub.id();
+        server.restart(1).await;
+
+        let id = time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Ok(PoolEvent::Resubscribed { topic, id }) = events.recv().await {
+                    assert_eq!(topic, "lobby");
+                    return id;
+                }
+            }
+        })
+        .await?;
+        assert_ne!(id, old_id);
+        assert_eq!(sub.id(), id);
+
+        pool.publish("lobby", vec!["hello".into()]).await?;
+        let data = sub.next().await.unwrap()?;
+        assert_eq!(data, vec!["hello".into()]);
+
+        pool.unsubscribe(sub).await?;
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 use anyhow::Result;
-use simple_kv::{start_server_with_config, LevelConfig, RotationConfig, ServerConfig};
+use simple_kv::{
+    start_server_with_acceptor, LevelConfig, RotationConfig, ServerConfig, TlsServerAcceptor,
+};
 use std::env;
-use tokio::fs;
-use tracing::span;
+use tokio::{fs, signal};
+use tracing::{info, span, warn};
 use tracing_subscriber::{
     filter::LevelFilter,
     fmt::{self, format},
@@ -64,6 +66,82 @@ async fn main() -> Result<()> {
     let root = span!(tracing::Level::INFO, "app_strat", work_units = 2);
     let _enter = root.enter();
 
-    start_server_with_config(&config).await?;
+    let tls = &config.tls;
+    let acceptor = TlsServerAcceptor::new(&tls.cert, &tls.key, tls.ca.as_deref())?
+        .with_crls(&tls.crls)?
+        .with_sni(&tls.sni)?;
+    tokio::spawn(reload_on_hangup(acceptor.clone()));
+    start_server_with_acceptor(&config, acceptor, shutdown_signal()).await?;
     Ok(())
 }
+
+/// 收到 SIGHUP 时重新读取配置文件中的证书和 CRL，之后的握手使用新的配置，已经建立的连接不受影响
+#[cfg(unix)]
+async fn reload_on_hangup(acceptor: TlsServerAcceptor) {
+    let mut sig = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(sig) => sig,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while sig.recv().await.is_some() {
+        info!("Received SIGHUP, reloading certificates and CRLs");
+        let path = match env::var("KV_SERVER_CONFIG") {
+            Ok(path) => path,
+            Err(_) => {
+                warn!("KV_SERVER_CONFIG is not set, nothing to reload");
+                continue;
+            }
+        };
+        let config = match fs::read_to_string(&path).await {
+            Ok(config) => toml::from_str::<ServerConfig>(&config).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let res = config.and_then(|config| {
+            let tls = &config.tls;
+            acceptor
+                .reload(&tls.cert, &tls.key, tls.ca.as_deref())
+                .and_then(|_| acceptor.reload_crls(&tls.crls))
+                .and_then(|_| acceptor.reload_sni(&tls.sni))
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = res {
+            warn!("Failed to reload certificates from {}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_acceptor: TlsServerAcceptor) {}
+
+/// 收到 SIGINT 或 SIGTERM 时返回
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            warn!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Received shutdown signal, shutting down");
+}
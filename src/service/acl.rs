@@ -0,0 +1,312 @@
+use crate::{
+    command_request::RequestData, AclConfig, CommandClass, CommandRequest, KvError, PeerIdentity,
+};
+use glob::Pattern;
+use std::borrow::Cow;
+
+/// 根据客户端证书中的身份检查请求是否被允许
+#[derive(Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+/// 编译好的 AclRule
+struct Rule {
+    identity: Pattern,
+    commands: Vec<Pattern>,
+    tables: Vec<Pattern>,
+    topics: Vec<Pattern>,
+}
+
+impl Acl {
+    /// 编译配置中的 glob 模式，模式不合法时返回错误
+    pub fn new(config: &AclConfig) -> Result<Self, KvError> {
+        let compile_all = |patterns: &[String]| -> Result<Vec<_>, KvError> {
+            patterns.iter().map(|p| compile(p)).collect()
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(Rule {
+                    identity: compile(&rule.identity)?,
+                    commands: compile_all(&rule.commands)?,
+                    tables: compile_all(&rule.tables)?,
+                    topics: compile_all(&rule.topics)?,
+                })
+            })
+            .collect::<Result<_, KvError>>()?;
+        Ok(Self { rules })
+    }
+
+    /// 检查 identity 能否执行 cmd，identity 为 None 表示客户端没有提供证书
+    pub fn check(
+        &self,
+        identity: Option<&PeerIdentity>,
+        cmd: &CommandRequest,
+    ) -> Result<(), KvError> {
+        // ping、cancel 等控制命令不受限制，auth 由用户认证负责
+        if self.rules.is_empty() || matches!(cmd.name(), "" | "ping" | "cancel") {
+            return Ok(());
+        }
+        let allowed = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(identity))
+            .any(|rule| rule.allows(cmd));
+        match allowed {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!(
+                "{} is not allowed to {}",
+                client(identity),
+                command(cmd)
+            ))),
+        }
+    }
+
+    /// 检查自定义命令 cmd 能否访问 table，自定义命令访问哪些 table 在执行时才知道，
+    /// 需要允许这个命令的规则同时允许 table
+    pub fn check_table(
+        &self,
+        identity: Option<&PeerIdentity>,
+        cmd: &CommandRequest,
+        table: &str,
+    ) -> Result<(), KvError> {
+        self.check_resource(identity, cmd, table, |rule| &rule.tables)
+    }
+
+    /// 检查自定义命令 cmd 能否向 topic 发布消息，规则同 check_table
+    pub fn check_topic(
+        &self,
+        identity: Option<&PeerIdentity>,
+        cmd: &CommandRequest,
+        topic: &str,
+    ) -> Result<(), KvError> {
+        self.check_resource(identity, cmd, topic, |rule| &rule.topics)
+    }
+
+    fn check_resource(
+        &self,
+        identity: Option<&PeerIdentity>,
+        cmd: &CommandRequest,
+        name: &str,
+        patterns: fn(&Rule) -> &[Pattern],
+    ) -> Result<(), KvError> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let allowed = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(identity) && rule.allows(cmd))
+            .any(|rule| patterns(rule).iter().any(|p| p.matches(name)));
+        match allowed {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!(
+                "{} is not allowed to access {} in {}",
+                client(identity),
+                name,
+                command(cmd)
+            ))),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, identity: Option<&PeerIdentity>) -> bool {
+        match identity {
+            Some(identity) => identity.names().any(|name| self.identity.matches(name)),
+            None => self.identity.as_str() == "*",
+        }
+    }
+
+    fn allows(&self, cmd: &CommandRequest) -> bool {
+        let any = |patterns: &[Pattern], name: &str| patterns.iter().any(|p| p.matches(name));
+        let name = command(cmd);
+        let allowed = match cmd.request_data {
+            // 自定义命令可以访问任意的 table，必须显式地允许，"*" 不包含它们
+            Some(RequestData::Custom(_)) => self
+                .commands
+                .iter()
+                .any(|p| p.as_str().starts_with("custom") && p.matches(&name)),
+            _ => any(&self.commands, &name),
+        };
+        if !allowed {
+            return false;
+        }
+        if let Some(table) = cmd.table() {
+            // 带有 tracking 的读请求会收到 table 中 key 的失效通知，需要同时允许 track
+            if cmd.tracking != 0
+                && matches!(CommandClass::of(cmd), CommandClass::Read)
+                && !any(&self.commands, "track")
+            {
+                return false;
+            }
+            return any(&self.tables, table);
+        }
+        if let Some(topic) = cmd.topic() {
+            return any(&self.topics, topic);
+        }
+        true
+    }
+}
+
+fn client(identity: Option<&PeerIdentity>) -> &str {
+    identity
+        .and_then(|id| id.common_name.as_deref())
+        .unwrap_or("anonymous client")
+}
+
+/// 规则中使用的命令名，自定义命令是 custom:<name>
+fn command(cmd: &CommandRequest) -> Cow<'static, str> {
+    match &cmd.request_data {
+        Some(RequestData::Custom(v)) => format!("custom:{}", v.name).into(),
+        _ => cmd.name().into(),
+    }
+}
+
+fn compile(pattern: &str) -> Result<Pattern, KvError> {
+    Pattern::new(pattern)
+        .map_err(|e| KvError::Internal(format!("Invalid ACL pattern {pattern}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AclRule;
+
+    fn acl() -> Acl {
+        let rule = |identity: &str, commands: &[&str], tables: &[&str], topics: &[&str]| AclRule {
+            identity: identity.into(),
+            commands: commands.iter().map(|s| s.to_string()).collect(),
+            tables: tables.iter().map(|s| s.to_string()).collect(),
+            topics: topics.iter().map(|s| s.to_string()).collect(),
+        };
+        Acl::new(&AclConfig {
+            rules: vec![
+                rule("*.acme.inc", &["*"], &["t*"], &["lobby"]),
+                rule("*", &["hget"], &["public"], &[]),
+                rule(
+                    "admin",
+                    &["hget", "track", "custom:reserve"],
+                    &["t1"],
+                    &["orders"],
+                ),
+            ],
+        })
+        .unwrap()
+    }
+
+    fn identity(san: &str) -> PeerIdentity {
+        PeerIdentity {
+            common_name: Some("device".into()),
+            subject: "CN=device".into(),
+            sans: vec![san.into()],
+        }
+    }
+
+    #[test]
+    fn empty_acl_should_allow_everything() {
+        let acl = Acl::new(&AclConfig::default()).unwrap();
+        assert!(acl
+            .check(None, &CommandRequest::new_hdel("t1", "k1"))
+            .is_ok());
+    }
+
+    #[test]
+    fn acl_should_check_commands_tables_and_topics() {
+        let acl = acl();
+        let device = identity("kvclient.acme.inc");
+
+        assert!(acl
+            .check(
+                Some(&device),
+                &CommandRequest::new_hset("t1", "k1", "v1".into())
+            )
+            .is_ok());
+        assert!(acl
+            .check(Some(&device), &CommandRequest::new_hgetall("t2"))
+            .is_ok());
+        assert!(acl
+            .check(Some(&device), &CommandRequest::new_subscribe("lobby"))
+            .is_ok());
+        let res = acl.check(Some(&device), &CommandRequest::new_hgetall("x1"));
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        let res = acl.check(Some(&device), &CommandRequest::new_psubscribe("*"));
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+
+        // 没有证书的客户端只能匹配 "*"
+        assert!(acl
+            .check(None, &CommandRequest::new_hget("public", "k1"))
+            .is_ok());
+        let res = acl.check(None, &CommandRequest::new_hset("public", "k1", "v1".into()));
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        assert!(acl.check(None, &CommandRequest::new_ping()).is_ok());
+    }
+
+    #[test]
+    fn acl_should_check_transfers_and_tracking() {
+        let acl = acl();
+        let device = identity("kvclient.acme.inc");
+        let admin = identity("admin");
+
+        assert!(acl
+            .check(Some(&device), &CommandRequest::new_download("t1", "k1"))
+            .is_ok());
+        let res = acl.check(Some(&device), &CommandRequest::new_download("x1", "k1"));
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+
+        // 带有 tracking 的读请求需要在同一条规则中允许 track
+        let mut cmd = CommandRequest::new_hget("public", "k1");
+        cmd.tracking = 1;
+        let res = acl.check(None, &cmd);
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+        assert!(acl
+            .check(Some(&admin), &CommandRequest::new_track())
+            .is_ok());
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+        cmd.tracking = 1;
+        assert!(acl.check(Some(&admin), &cmd).is_ok());
+        assert!(acl.check(Some(&device), &cmd).is_ok());
+    }
+
+    #[test]
+    fn custom_commands_should_be_allowed_explicitly() {
+        let acl = acl();
+        let custom = |name: &str| CommandRequest::new_custom(name, vec![]);
+
+        // "*" 不包含自定义命令
+        let device = identity("kvclient.acme.inc");
+        let res = acl.check(Some(&device), &custom("reserve"));
+        assert!(
+            matches!(res, Err(KvError::PermissionDenied(msg)) if msg.contains("custom:reserve"))
+        );
+
+        let admin = identity("admin");
+        assert!(acl.check(Some(&admin), &custom("reserve")).is_ok());
+        let res = acl.check(Some(&admin), &custom("refund"));
+        assert!(matches!(res, Err(KvError::PermissionDenied(_))));
+
+        // 自定义命令访问的 table 同样需要规则允许
+        let cmd = custom("reserve");
+        assert!(acl.check_table(Some(&admin), &cmd, "t1").is_ok());
+        let res = acl.check_table(Some(&admin), &cmd, "t2");
+        assert!(matches!(res, Err(KvError::PermissionDenied(msg)) if msg.contains("t2")));
+
+        // 发布消息的 topic 也是
+        assert!(acl.check_topic(Some(&admin), &cmd, "orders").is_ok());
+        let res = acl.check_topic(Some(&admin), &cmd, "lobby");
+        assert!(matches!(res, Err(KvError::PermissionDenied(msg)) if msg.contains("lobby")));
+    }
+
+    #[test]
+    fn invalid_pattern_should_be_rejected() {
+        let config = AclConfig {
+            rules: vec![AclRule {
+                identity: "[".into(),
+                ..Default::default()
+            }],
+        };
+        assert!(Acl::new(&config).is_err());
+    }
+}
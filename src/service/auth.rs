@@ -0,0 +1,308 @@
+use crate::{AuthConfig, CommandClass, CommandRequest, KvError, Session, UserConfig};
+use glob::Pattern;
+use ring::pbkdf2;
+use std::{collections::HashMap, num::NonZeroU32, sync::Arc};
+
+/// 密码哈希使用的算法，对应哈希字符串的前缀
+const ALGORITHM: &str = "pbkdf2-sha256";
+/// 生成密码哈希时的迭代次数
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// 用户数据库，没有任何用户时不需要认证
+#[derive(Default)]
+pub struct Users {
+    users: HashMap<String, Arc<User>>,
+}
+
+/// 编译好的 UserConfig
+pub struct User {
+    name: String,
+    password: PasswordHash,
+    read: Vec<Pattern>,
+    write: Vec<Pattern>,
+    publish: Vec<Pattern>,
+    subscribe: Vec<Pattern>,
+    admin: bool,
+}
+
+/// 解析好的密码哈希
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Users {
+    /// 编译配置中的用户，密码哈希或者 glob 模式不合法时返回错误
+    pub fn new(config: &AuthConfig) -> Result<Self, KvError> {
+        let users = config
+            .users
+            .iter()
+            .map(|user| Ok((user.name.clone(), Arc::new(User::new(user)?))))
+            .collect::<Result<_, KvError>>()?;
+        Ok(Self { users })
+    }
+
+    /// 是否开启了认证
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// 校验用户名和密码，成功后 session 以该用户的身份执行之后的请求
+    pub fn authenticate(
+        &self,
+        session: &Session,
+        username: &str,
+        password: &str,
+    ) -> Result<(), KvError> {
+        // 没有开启认证时 Auth 总是成功
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        match self.users.get(username) {
+            Some(user) if user.password.verify(password) => {
+                session.set_user(user.clone());
+                Ok(())
+            }
+            _ => Err(KvError::Unauthenticated(
+                "invalid username or password".into(),
+            )),
+        }
+    }
+
+    /// 检查 session 能否执行 cmd，认证之前只能执行 Auth
+    pub fn check(&self, session: &Session, cmd: &CommandRequest) -> Result<(), KvError> {
+        if !self.is_enabled() || cmd.name() == "auth" {
+            return Ok(());
+        }
+        let user = match session.user() {
+            Some(user) => user,
+            None => {
+                return Err(KvError::Unauthenticated(format!(
+                    "{} requires authentication",
+                    cmd.name()
+                )))
+            }
+        };
+        match user.allows(cmd) {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!(
+                "{} is not allowed to {}",
+                user.name,
+                cmd.name()
+            ))),
+        }
+    }
+}
+
+impl User {
+    fn new(config: &UserConfig) -> Result<Self, KvError> {
+        let compile_all = |patterns: &[String]| -> Result<Vec<_>, KvError> {
+            patterns.iter().map(|p| compile(p)).collect()
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            password: config.password.parse()?,
+            read: compile_all(&config.read)?,
+            write: compile_all(&config.write)?,
+            publish: compile_all(&config.publish)?,
+            subscribe: compile_all(&config.subscribe)?,
+            admin: config.admin,
+        })
+    }
+
+    /// 用户名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn allows(&self, cmd: &CommandRequest) -> bool {
+        let any = |patterns: &[Pattern], name: Option<&str>| {
+            name.is_some_and(|name| patterns.iter().any(|p| p.matches(name)))
+        };
+        if self.admin {
+            return true;
+        }
+        match (CommandClass::of(cmd), cmd.name()) {
+            (CommandClass::Read, _) => any(&self.read, cmd.table()),
+            // 自定义命令可以访问任意的 table，只有管理员能够执行
+            (CommandClass::Write, "custom") => false,
+            (CommandClass::Write, _) => any(&self.write, cmd.table()),
+            (CommandClass::PubSub, "publish") => any(&self.publish, cmd.topic()),
+            // 失效通知只包含读取过的 key，不需要单独的权限
+            (CommandClass::PubSub, "track") => true,
+            (CommandClass::PubSub, _) => any(&self.subscribe, cmd.topic()),
+            // Auth 切换到其它用户，不需要权限
+            (CommandClass::Auth, _) => true,
+            (CommandClass::Control, "ping") => true,
+            // 其它的控制命令都是管理命令
+            (CommandClass::Control, _) => false,
+        }
+    }
+}
+
+impl PasswordHash {
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl std::str::FromStr for PasswordHash {
+    type Err = KvError;
+
+    /// 格式为 pbkdf2-sha256$迭代次数$salt$hash，salt 和 hash 使用十六进制表示
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || KvError::Internal(format!("Invalid password hash: {s}"));
+        let parts: Vec<_> = s.split('$').collect();
+        match parts[..] {
+            [ALGORITHM, iterations, salt, hash] => Ok(Self {
+                iterations: iterations.parse().map_err(|_| invalid())?,
+                salt: from_hex(salt).ok_or_else(invalid)?,
+                hash: from_hex(hash).ok_or_else(invalid)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// 使用随机的 salt 生成密码哈希，结果可以直接填入 UserConfig 的 password
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let mut hash = [0u8; HASH_LEN];
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{}${}${}${}",
+        ALGORITHM,
+        ITERATIONS,
+        to_hex(&salt),
+        to_hex(&hash)
+    )
+}
+
+fn compile(pattern: &str) -> Result<Pattern, KvError> {
+    Pattern::new(pattern)
+        .map_err(|e| KvError::Internal(format!("Invalid user pattern {pattern}: {e}")))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> Users {
+        let user = |name: &str, read: &[&str], subscribe: &[&str]| UserConfig {
+            name: name.into(),
+            password: hash_password("secret"),
+            read: read.iter().map(|s| s.to_string()).collect(),
+            write: vec!["t1".into()],
+            publish: vec!["news.*".into()],
+            subscribe: subscribe.iter().map(|s| s.to_string()).collect(),
+            admin: false,
+        };
+        Users::new(&AuthConfig {
+            users: vec![user("alice", &["t*"], &["news.*"])],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn hashed_password_should_be_verified() {
+        let hash: PasswordHash = hash_password("secret").parse().unwrap();
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("Secret"));
+        // 相同的密码使用不同的 salt
+        assert_ne!(hash_password("secret"), hash_password("secret"));
+    }
+
+    #[test]
+    fn invalid_password_hash_should_be_rejected() {
+        for hash in ["secret", "pbkdf2-sha256$0$00$00", "pbkdf2-sha256$1$0g$00"] {
+            let config = AuthConfig {
+                users: vec![UserConfig {
+                    password: hash.into(),
+                    ..Default::default()
+                }],
+            };
+            assert!(Users::new(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn disabled_auth_should_allow_everything() {
+        let users = Users::default();
+        let session = Session::default();
+        assert!(users
+            .check(&session, &CommandRequest::new_hdel("t1", "k1"))
+            .is_ok());
+    }
+
+    #[test]
+    fn unauthenticated_session_should_only_auth() {
+        let users = users();
+        let session = Session::default();
+        let res = users.check(&session, &CommandRequest::new_hget("t1", "k1"));
+        assert!(matches!(res, Err(KvError::Unauthenticated(_))));
+        assert!(users
+            .check(&session, &CommandRequest::new_auth("alice", "secret"))
+            .is_ok());
+
+        let res = users.authenticate(&session, "alice", "wrong");
+        assert!(matches!(res, Err(KvError::Unauthenticated(_))));
+        let res = users.authenticate(&session, "bob", "secret");
+        assert!(matches!(res, Err(KvError::Unauthenticated(_))));
+        assert!(session.user().is_none());
+    }
+
+    #[test]
+    fn authenticated_session_should_follow_user_rules() {
+        let users = users();
+        let session = Session::default();
+        users.authenticate(&session, "alice", "secret").unwrap();
+        assert_eq!(session.user().unwrap().name(), "alice");
+
+        let check = |cmd: CommandRequest| users.check(&session, &cmd);
+        assert!(check(CommandRequest::new_hgetall("t2")).is_ok());
+        assert!(check(CommandRequest::new_hset("t1", "k1", "v1".into())).is_ok());
+        assert!(check(CommandRequest::new_publish("news.tech", vec!["v1".into()])).is_ok());
+        assert!(check(CommandRequest::new_subscribe("news.tech")).is_ok());
+        assert!(check(CommandRequest::new_ping()).is_ok());
+
+        for cmd in [
+            CommandRequest::new_hget("x1", "k1"),
+            CommandRequest::new_hset("t2", "k1", "v1".into()),
+            CommandRequest::new_publish("lobby", vec!["v1".into()]),
+            CommandRequest::new_psubscribe("*"),
+        ] {
+            assert!(matches!(check(cmd), Err(KvError::PermissionDenied(_))));
+        }
+    }
+}
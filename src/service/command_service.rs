@@ -105,10 +105,23 @@ impl CommandService for Hmexist {
     }
 }
 
+impl CommandService for Ping {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        Value::from("PONG").into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn ping_should_return_pong() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_ping(), &store);
+        assert_res_ok(&res, &["PONG".into()], &[]);
+    }
+
     #[test]
     fn hget_should_work() {
         let store = MemTable::new();
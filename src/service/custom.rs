@@ -0,0 +1,278 @@
+use crate::{Broadcaster, CommandResponse, Custom, KvError, Kvpair, Storage, Topic, Value};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// 自定义命令的处理函数，参数依次是命令的参数、存储以及用于发布消息的 topic
+pub type CustomHandler<Store> = Box<
+    dyn Fn(Vec<Value>, &CustomStore<Store>, &CustomTopic) -> Result<CommandResponse, KvError>
+        + Send
+        + Sync,
+>;
+
+/// 按照名字注册的自定义命令
+pub struct Customs<Store> {
+    handlers: HashMap<String, CustomHandler<Store>>,
+    /// 自定义命令之间串行执行，处理函数中先读后写不会被其它自定义命令打断。
+    /// 处理函数是同步的，由 Service 放到 blocking 线程中执行，等待锁不会阻塞 async 的 worker
+    lock: Mutex<()>,
+}
+
+/// 自定义命令看到的存储：table 会加上 session 的 namespace 前缀，访问的 table 需要 ACL 允许，
+/// 写入过的 key 在命令执行后通知客户端缓存失效
+pub struct CustomStore<'a, Store> {
+    store: &'a Store,
+    prefix: Option<&'a str>,
+    check: &'a dyn Fn(&str) -> Result<(), KvError>,
+    /// 写入过的 table 和 key，table 带有 namespace 前缀
+    written: RefCell<HashMap<String, Vec<String>>>,
+}
+
+/// 自定义命令看到的 pub/sub：topic 会加上 session 的 topic 前缀，发布的 topic 需要 ACL 允许
+pub struct CustomTopic<'a> {
+    broadcaster: &'a Arc<Broadcaster>,
+    prefix: &'a str,
+    check: &'a dyn Fn(&str) -> Result<(), KvError>,
+}
+
+impl<Store> Default for Customs<Store> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl<Store: Storage> Customs<Store> {
+    /// 注册一个自定义命令，同名的命令会被覆盖
+    pub fn insert(&mut self, name: impl Into<String>, handler: CustomHandler<Store>) {
+        self.handlers.insert(name.into(), handler);
+    }
+
+    /// 执行自定义命令，没有注册的命令返回 UnsupportedCommand。
+    /// 自定义命令之间串行执行，但是普通的读写命令不受影响，可能看到处理函数写入的中间状态
+    pub fn execute(
+        &self,
+        cmd: Custom,
+        store: &CustomStore<Store>,
+        topic: &CustomTopic,
+    ) -> CommandResponse {
+        match self.handlers.get(&cmd.name) {
+            Some(handler) => {
+                let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+                handler(cmd.args, store, topic).unwrap_or_else(|e| e.into())
+            }
+            None => KvError::UnsupportedCommand(format!("custom command {}", cmd.name)).into(),
+        }
+    }
+}
+
+impl<'a, Store: Storage> CustomStore<'a, Store> {
+    /// 创建存储的视图，check 在每次访问 table 之前检查权限，table 不带 namespace 前缀
+    pub fn new(
+        store: &'a Store,
+        prefix: Option<&'a str>,
+        check: &'a dyn Fn(&str) -> Result<(), KvError>,
+    ) -> Self {
+        Self {
+            store,
+            prefix,
+            check,
+            written: Default::default(),
+        }
+    }
+
+    pub fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(&self.table(table)?, key)
+    }
+
+    pub fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let table = self.table(table)?;
+        self.write(&table, &key);
+        self.store.set(&table, key, value)
+    }
+
+    pub fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let table = self.table(table)?;
+        self.write(&table, key);
+        self.store.del(&table, key)
+    }
+
+    pub fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(&self.table(table)?, key)
+    }
+
+    pub fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(&self.table(table)?)
+    }
+
+    /// 取出写入过的 table 和 key
+    pub fn take_written(&self) -> HashMap<String, Vec<String>> {
+        self.written.take()
+    }
+
+    /// 检查权限并加上 namespace 前缀
+    fn table<'t>(&self, table: &'t str) -> Result<Cow<'t, str>, KvError> {
+        (self.check)(table)?;
+        Ok(match self.prefix {
+            Some(prefix) => format!("{prefix}{table}").into(),
+            None if table.contains(':') => {
+                return Err(KvError::PermissionDenied(format!(
+                    "table {table} is in a namespace"
+                )))
+            }
+            None => table.into(),
+        })
+    }
+
+    fn write(&self, table: &str, key: &str) {
+        let mut written = self.written.borrow_mut();
+        written.entry(table.into()).or_default().push(key.into());
+    }
+}
+
+impl<'a> CustomTopic<'a> {
+    /// 创建 pub/sub 的视图，check 在每次发布之前检查权限，topic 不带前缀
+    pub fn new(
+        broadcaster: &'a Arc<Broadcaster>,
+        prefix: &'a str,
+        check: &'a dyn Fn(&str) -> Result<(), KvError>,
+    ) -> Self {
+        Self {
+            broadcaster,
+            prefix,
+            check,
+        }
+    }
+
+    /// 向 topic 发布 values，订阅者收到的内容与 Publish 相同
+    pub fn publish(&self, topic: &str, values: Vec<Value>) -> Result<(), KvError> {
+        (self.check)(topic)?;
+        let topic = format!("{}{topic}", self.prefix);
+        Arc::clone(self.broadcaster).publish(topic, Arc::new(values.into()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, MemTable};
+    use std::thread;
+
+    /// 扣减库存，数量不足时返回错误
+    fn reserve<Store: Storage>(
+        args: Vec<Value>,
+        store: &CustomStore<Store>,
+        _topic: &CustomTopic,
+    ) -> Result<CommandResponse, KvError> {
+        let (item, count) = match &args[..] {
+            [item, count] => (String::try_from(item)?, i64::try_from(count)?),
+            _ => return Err(KvError::InvalidCommand("reserve <item> <count>".into())),
+        };
+        let stock = match store.get("stock", &item)? {
+            Some(v) => i64::try_from(v)?,
+            None => 0,
+        };
+        if stock < count {
+            return Err(KvError::InvalidCommand(format!("{item} is out of stock")));
+        }
+        store.set("stock", item, (stock - count).into())?;
+        Ok(Value::from(stock - count).into())
+    }
+
+    fn customs() -> Customs<MemTable> {
+        let mut customs = Customs::default();
+        customs.insert("reserve", Box::new(reserve));
+        customs
+    }
+
+    fn cmd(name: &str, count: i64) -> Custom {
+        Custom {
+            name: name.into(),
+            args: vec!["apple".into(), count.into()],
+        }
+    }
+
+    #[test]
+    fn custom_command_should_work() {
+        let store = MemTable::new();
+        let broadcaster = Arc::new(Broadcaster::default());
+        store.set("stock", "apple".into(), 3.into()).unwrap();
+        let customs = customs();
+        let allow = |_: &str| Ok(());
+        let view = CustomStore::new(&store, None, &allow);
+        let topic = CustomTopic::new(&broadcaster, "", &allow);
+
+        let res = customs.execute(cmd("reserve", 2), &view, &topic);
+        assert_res_ok(&res, &[1.into()], &[]);
+        let res = customs.execute(cmd("reserve", 2), &view, &topic);
+        assert_res_error(&res, 400, "out of stock");
+        let res = customs.execute(cmd("refund", 2), &view, &topic);
+        assert_res_error(&res, 501, "custom command refund");
+
+        let written = view.take_written();
+        assert_eq!(written["stock"], vec!["apple".to_string()]);
+    }
+
+    #[test]
+    fn custom_store_should_apply_namespace_and_acl() {
+        let store = MemTable::new();
+        store.set("a:stock", "apple".into(), 1.into()).unwrap();
+        store.set("stock", "apple".into(), 5.into()).unwrap();
+        let broadcaster = Arc::new(Broadcaster::default());
+        let customs = customs();
+
+        // 只能看到 namespace 中的数据
+        let allow = |_: &str| Ok(());
+        let view = CustomStore::new(&store, Some("a:"), &allow);
+        let topic = CustomTopic::new(&broadcaster, "a:", &allow);
+        let res = customs.execute(cmd("reserve", 1), &view, &topic);
+        assert_res_ok(&res, &[0.into()], &[]);
+        assert_eq!(store.get("stock", "apple").unwrap(), Some(5.into()));
+        assert_eq!(view.take_written()["a:stock"], vec!["apple".to_string()]);
+
+        let deny = |table: &str| Err(KvError::PermissionDenied(table.into()));
+        let view = CustomStore::new(&store, None, &deny);
+        let res = customs.execute(cmd("reserve", 1), &view, &topic);
+        assert_res_error(&res, 403, "stock");
+        assert!(view.take_written().is_empty());
+        let topic = CustomTopic::new(&broadcaster, "", &deny);
+        let res = topic.publish("lobby", vec!["hello".into()]);
+        assert!(matches!(res, Err(KvError::PermissionDenied(msg)) if msg == "lobby"));
+    }
+
+    #[test]
+    fn custom_commands_should_not_interleave() {
+        let store = MemTable::new();
+        store.set("stock", "apple".into(), 100.into()).unwrap();
+        let broadcaster = Arc::new(Broadcaster::default());
+        let customs = customs();
+
+        let reserved: i64 = thread::scope(|s| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        let allow = |_: &str| Ok(());
+                        let view = CustomStore::new(&store, None, &allow);
+                        let topic = CustomTopic::new(&broadcaster, "", &allow);
+                        (0..20)
+                            .filter(|_| {
+                                let res = customs.execute(cmd("reserve", 1), &view, &topic);
+                                res.status == 200
+                            })
+                            .count() as i64
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).sum()
+        });
+        // 不会超卖
+        assert_eq!(reserved, 100);
+        assert_eq!(store.get("stock", "apple").unwrap(), Some(0.into()));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{CommandRequest, CommandResponse, KvError, Session};
+use futures::future::{self, BoxFuture};
+use tracing::warn;
+
+/// 服务端处理请求的中间件。每个回调都能拿到请求所在的 session，
+/// 从中可以得到客户端的标识、地址、证书身份以及认证的用户。
+/// 回调返回 Err 时跳过之后的步骤，直接把 Err 中的响应发给客户端
+pub trait Hook: Send + Sync + 'static {
+    /// 收到请求之后、执行之前调用
+    fn on_received<'a>(
+        &'a self,
+        _session: &'a Session,
+        _cmd: &'a CommandRequest,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    /// 请求执行完毕之后调用，订阅之类返回响应流的请求不会调用
+    fn on_executed<'a>(
+        &'a self,
+        _session: &'a Session,
+        _res: &'a CommandResponse,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    /// 发送响应之前调用，可以修改响应，调用的范围和 on_executed 相同
+    fn on_before_send<'a>(
+        &'a self,
+        _session: &'a Session,
+        _res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    /// 响应写入 stream 之后调用，订阅推送的每一个响应都会调用
+    fn on_after_send<'a>(
+        &'a self,
+        _session: &'a Session,
+        _res: &'a CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+}
+
+/// 按照注册的顺序调用的 Hook
+#[derive(Default)]
+pub(super) struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl Hooks {
+    pub fn push(&mut self, hook: impl Hook) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub async fn received(
+        &self,
+        session: &Session,
+        cmd: &CommandRequest,
+    ) -> Result<(), CommandResponse> {
+        for hook in &self.hooks {
+            hook.on_received(session, cmd).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn executed(
+        &self,
+        session: &Session,
+        res: &CommandResponse,
+    ) -> Result<(), CommandResponse> {
+        for hook in &self.hooks {
+            hook.on_executed(session, res).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn before_send(
+        &self,
+        session: &Session,
+        res: &mut CommandResponse,
+    ) -> Result<(), CommandResponse> {
+        for hook in &self.hooks {
+            hook.on_before_send(session, res).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn after_send(&self, session: &Session, res: &CommandResponse) {
+        for hook in &self.hooks {
+            hook.on_after_send(session, res).await;
+        }
+    }
+}
+
+/// 把同步的回调函数包装成 Hook
+pub(super) struct Received<F>(pub F);
+pub(super) struct Executed<F>(pub F);
+pub(super) struct BeforeSend<F>(pub F);
+pub(super) struct AfterSend<F>(pub F);
+
+impl<F> Hook for Received<F>
+where
+    F: Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync + 'static,
+{
+    fn on_received<'a>(
+        &'a self,
+        _session: &'a Session,
+        cmd: &'a CommandRequest,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready((self.0)(cmd).map_err(Into::into)))
+    }
+}
+
+impl<F> Hook for Executed<F>
+where
+    F: Fn(&CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+{
+    fn on_executed<'a>(
+        &'a self,
+        _session: &'a Session,
+        res: &'a CommandResponse,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready((self.0)(res).map_err(Into::into)))
+    }
+}
+
+impl<F> Hook for BeforeSend<F>
+where
+    F: Fn(&mut CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+{
+    fn on_before_send<'a>(
+        &'a self,
+        _session: &'a Session,
+        res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+        Box::pin(future::ready((self.0)(res).map_err(Into::into)))
+    }
+}
+
+impl<F> Hook for AfterSend<F>
+where
+    F: Fn() -> Result<(), KvError> + Send + Sync + 'static,
+{
+    fn on_after_send<'a>(
+        &'a self,
+        _session: &'a Session,
+        _res: &'a CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        // 响应已经发出，出错时只能记录下来
+        if let Err(e) = (self.0)() {
+            warn!("After send hook failed: {}", e);
+        }
+        Box::pin(future::ready(()))
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{CommandRequest, KvError, Service, Session, Storage, StreamingResponse};
+use futures::{future::BoxFuture, stream, StreamExt};
+use std::task::{Context, Poll};
+use tower::{
+    load_shed::error::Overloaded, timeout::error::Elapsed, util::BoxCloneService, BoxError,
+};
+
+/// 装箱之后的 tower service，ProstServerStream 用它执行请求
+pub type BoxCommandService = BoxCloneService<CommandRequest, StreamingResponse, KvError>;
+
+/// 在指定的 session 中执行请求的 tower service
+pub struct SessionService<Store> {
+    service: Service<Store>,
+    session: Session,
+}
+
+impl<Store> Clone for SessionService<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            session: self.session.clone(),
+        }
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 得到在 session 中执行请求的 tower service
+    pub fn with_session(&self, session: Session) -> SessionService<Store> {
+        SessionService {
+            service: self.clone(),
+            session,
+        }
+    }
+}
+
+/// 在一个没有认证过的 session 中执行请求
+impl<Store: Storage> tower::Service<CommandRequest> for Service<Store> {
+    type Response = StreamingResponse;
+    type Error = KvError;
+    type Future = BoxFuture<'static, Result<StreamingResponse, KvError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), KvError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, cmd: CommandRequest) -> Self::Future {
+        first_response(self.execute(cmd))
+    }
+}
+
+impl<Store: Storage> tower::Service<CommandRequest> for SessionService<Store> {
+    type Response = StreamingResponse;
+    type Error = KvError;
+    type Future = BoxFuture<'static, Result<StreamingResponse, KvError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), KvError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, cmd: CommandRequest) -> Self::Future {
+        first_response(self.service.execute_in(&self.session, cmd))
+    }
+}
+
+/// 等到第一个响应之后 future 才完成，这样超时、并发限制之类的中间件能够覆盖请求的执行，
+/// 订阅之类的请求在第一个响应之后继续推送
+fn first_response(
+    mut res: StreamingResponse,
+) -> BoxFuture<'static, Result<StreamingResponse, KvError>> {
+    Box::pin(async move {
+        let first = res.next().await;
+        let res: StreamingResponse = Box::pin(stream::iter(first).chain(res));
+        Ok(res)
+    })
+}
+
+/// 把 tower 中间件返回的错误转换成 KvError
+pub fn from_box_error(e: BoxError) -> KvError {
+    match e.downcast::<KvError>() {
+        Ok(e) => *e,
+        Err(e) if e.is::<Elapsed>() => KvError::Timeout("request".into()),
+        Err(e) if e.is::<Overloaded>() => KvError::Overloaded,
+        Err(e) => KvError::Internal(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use std::time::Duration;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    #[tokio::test]
+    async fn service_should_work_with_tower_layers() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let svc = ServiceBuilder::new()
+            .timeout(Duration::from_secs(1))
+            .concurrency_limit(4)
+            .service(service.clone());
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = svc.clone().oneshot(cmd).await.unwrap();
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        assert!(res.next().await.is_none());
+
+        let session = Session::default().with_namespace("a");
+        let res = service
+            .with_session(session)
+            .oneshot(CommandRequest::new_hget("t1", "k1"));
+        let data = res.await.unwrap().next().await.unwrap();
+        assert_eq!(data.status, 404);
+    }
+
+    #[test]
+    fn tower_errors_should_be_converted() {
+        let e = from_box_error(Box::new(KvError::RateLimited("c1".into())));
+        assert!(matches!(e, KvError::RateLimited(_)));
+        assert!(matches!(
+            from_box_error(Box::new(Elapsed::new())),
+            KvError::Timeout(_)
+        ));
+        assert!(matches!(
+            from_box_error(Box::new(Overloaded::new())),
+            KvError::Overloaded
+        ));
+        assert!(matches!(
+            from_box_error("boom".into()),
+            KvError::Internal(_)
+        ));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{CommandRequest, KvError, LimitConfig, RateConfig};
+use dashmap::DashMap;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// 超过这个时间没有更新的延迟统计不再作为拒绝请求的依据
+const LATENCY_WINDOW: Duration = Duration::from_secs(1);
+
+/// 令牌桶数量超过该值时清理长时间没有使用的桶
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// 没有配置时 Auth 使用的限流，每个客户端每秒一次，允许连续尝试 5 次
+const DEFAULT_AUTH_RATE: RateConfig = RateConfig { rate: 1, burst: 5 };
+
+/// 命令的分类，不同的分类使用不同的限流配置
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum CommandClass {
+    Read,
+    Write,
+    PubSub,
+    /// 认证，单独限流以防止猜测密码
+    Auth,
+    /// ping、cancel 等控制命令，不受限流和过载保护的影响
+    Control,
+}
+
+impl CommandClass {
+    pub fn of(cmd: &CommandRequest) -> Self {
+        match cmd.name() {
+            "hget" | "hmget" | "hgetall" | "hexist" | "hmexist" | "download" => Self::Read,
+            "hset" | "hmset" | "hdel" | "hmdel" | "upload" | "custom" => Self::Write,
+            "subscribe" | "unsubscribe" | "publish" | "psubscribe" | "punsubscribe" | "track" => {
+                Self::PubSub
+            }
+            "auth" => Self::Auth,
+            _ => Self::Control,
+        }
+    }
+}
+
+/// 令牌桶，按照固定的速率补充令牌，每个请求消耗一个令牌
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: &RateConfig) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self, rate: &RateConfig) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.rate as f64).min(rate.burst as f64);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 服务端的负载情况
+struct Load {
+    start: Instant,
+    /// 已经开始执行但还没有返回第一个响应的请求数
+    inflight: AtomicUsize,
+    /// 请求延迟的指数加权平均值（微秒）
+    latency: AtomicU64,
+    /// 最后一次更新 latency 时距离 start 的毫秒数
+    updated_at: AtomicU64,
+}
+
+impl Load {
+    fn record(&self, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+                Some(avg - avg / 8 + sample / 8)
+            });
+        let now = self.start.elapsed().as_millis() as u64;
+        self.updated_at.store(now, Ordering::Relaxed);
+    }
+
+    /// 最近的平均延迟，长时间没有新的请求完成时返回 None
+    fn latency(&self) -> Option<Duration> {
+        let updated_at = Duration::from_millis(self.updated_at.load(Ordering::Relaxed));
+        if self.start.elapsed().saturating_sub(updated_at) > LATENCY_WINDOW {
+            return None;
+        }
+        Some(Duration::from_micros(self.latency.load(Ordering::Relaxed)))
+    }
+}
+
+/// 按照客户端和命令分类进行限流，并在服务端过载时拒绝新的请求
+pub struct Limiter {
+    config: LimitConfig,
+    buckets: DashMap<(String, CommandClass), TokenBucket>,
+    load: Arc<Load>,
+}
+
+/// 请求得到第一个响应之前持有，用于统计排队中的请求数和延迟
+pub struct Pending {
+    load: Arc<Load>,
+    start: Instant,
+}
+
+impl Pending {
+    /// 请求得到了第一个响应
+    pub fn finish(self) {
+        self.load.record(self.start.elapsed());
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.load.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            buckets: DashMap::new(),
+            load: Arc::new(Load {
+                start: Instant::now(),
+                inflight: AtomicUsize::new(0),
+                latency: AtomicU64::new(0),
+                updated_at: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// 检查 client 能否执行 cmd，被限流时返回 KvError::RateLimited，过载时返回 KvError::Overloaded
+    pub fn admit(&self, client: &str, cmd: &CommandRequest) -> Result<Pending, KvError> {
+        let class = CommandClass::of(cmd);
+        if class != CommandClass::Control {
+            self.check_load()?;
+            self.check_rate(client, class)?;
+        }
+
+        self.load.inflight.fetch_add(1, Ordering::Relaxed);
+        Ok(Pending {
+            load: self.load.clone(),
+            start: Instant::now(),
+        })
+    }
+
+    fn check_load(&self) -> Result<(), KvError> {
+        let config = &self.config;
+        let inflight = self.load.inflight.load(Ordering::Relaxed);
+        if config.max_inflight > 0 && inflight >= config.max_inflight {
+            warn!("Too many requests in flight: {}", inflight);
+            return Err(KvError::Overloaded);
+        }
+        if config.max_latency > 0 {
+            if let Some(latency) = self.load.latency() {
+                if latency > Duration::from_millis(config.max_latency) {
+                    warn!("Request latency is too high: {:?}", latency);
+                    return Err(KvError::Overloaded);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rate(&self, client: &str, class: CommandClass) -> Result<(), KvError> {
+        let rate = match self.rate(class) {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+
+        if self.buckets.len() > MAX_IDLE_BUCKETS {
+            self.purge();
+        }
+        let mut bucket = self
+            .buckets
+            .entry((client.to_owned(), class))
+            .or_insert_with(|| TokenBucket::new(rate));
+        if bucket.try_acquire(rate) {
+            Ok(())
+        } else {
+            debug!("Client {} exceeds the {:?} rate limit", client, class);
+            Err(KvError::RateLimited(format!("{client} ({class:?})")))
+        }
+    }
+
+    /// 命令分类使用的限流配置，None 表示不限流
+    fn rate(&self, class: CommandClass) -> Option<&RateConfig> {
+        let config = &self.config;
+        match class {
+            CommandClass::Read => config.read.as_ref(),
+            CommandClass::Write => config.write.as_ref(),
+            CommandClass::PubSub => config.pubsub.as_ref(),
+            CommandClass::Auth => Some(config.auth.as_ref().unwrap_or(&DEFAULT_AUTH_RATE)),
+            CommandClass::Control => None,
+        }
+    }
+
+    /// 清理已经补满的桶，之后再次使用时会重新创建
+    fn purge(&self) {
+        self.buckets
+            .retain(|(_, class), bucket| match self.rate(*class) {
+                Some(rate) if rate.rate > 0 => {
+                    let refill = (rate.burst as f64 - bucket.tokens) / rate.rate as f64;
+                    bucket.updated_at.elapsed().as_secs_f64() < refill
+                }
+                _ => true,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn limit_config() -> LimitConfig {
+        LimitConfig {
+            write: Some(RateConfig { rate: 10, burst: 2 }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rate_limit_should_apply_per_client_and_class() {
+        let limiter = Limiter::new(limit_config());
+        let hset = CommandRequest::new_hset("t1", "k1", Value::default());
+        let hget = CommandRequest::new_hget("t1", "k1");
+
+        assert!(limiter.admit("alice", &hset).is_ok());
+        assert!(limiter.admit("alice", &hset).is_ok());
+        let res = limiter.admit("alice", &hset);
+        assert!(matches!(res, Err(KvError::RateLimited(_))));
+
+        // 其它客户端和没有配置限流的命令不受影响
+        assert!(limiter.admit("bob", &hset).is_ok());
+        assert!(limiter.admit("alice", &hget).is_ok());
+
+        // 令牌会随着时间补充
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.admit("alice", &hset).is_ok());
+    }
+
+    #[test]
+    fn auth_should_be_rate_limited_by_default() {
+        let limiter = Limiter::new(LimitConfig::default());
+        let auth = CommandRequest::new_auth("alice", "secret");
+
+        for _ in 0..DEFAULT_AUTH_RATE.burst {
+            assert!(limiter.admit("10.0.0.1", &auth).is_ok());
+        }
+        let res = limiter.admit("10.0.0.1", &auth);
+        assert!(matches!(res, Err(KvError::RateLimited(_))));
+        assert!(limiter.admit("10.0.0.2", &auth).is_ok());
+    }
+
+    #[test]
+    fn too_many_inflight_requests_should_be_rejected() {
+        let limiter = Limiter::new(LimitConfig {
+            max_inflight: 1,
+            ..Default::default()
+        });
+        let hget = CommandRequest::new_hget("t1", "k1");
+
+        let pending = limiter.admit("alice", &hget).unwrap();
+        let res = limiter.admit("bob", &hget);
+        assert!(matches!(res, Err(KvError::Overloaded)));
+        // 控制命令不会被拒绝
+        assert!(limiter.admit("bob", &CommandRequest::new_ping()).is_ok());
+
+        pending.finish();
+        assert!(limiter.admit("bob", &hget).is_ok());
+    }
+
+    #[test]
+    fn high_latency_should_shed_load() {
+        let limiter = Limiter::new(LimitConfig {
+            max_latency: 10,
+            ..Default::default()
+        });
+        let hget = CommandRequest::new_hget("t1", "k1");
+
+        for _ in 0..32 {
+            limiter.load.record(Duration::from_millis(50));
+        }
+        let res = limiter.admit("alice", &hget);
+        assert!(matches!(res, Err(KvError::Overloaded)));
+    }
+}
@@ -1,15 +1,36 @@
 use crate::command_request::*;
 use crate::*;
-use futures::stream;
-use std::sync::Arc;
+use futures::{stream, StreamExt};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    task,
+    time::{self, Instant},
+};
 use tracing::{debug, instrument};
+mod acl;
+mod auth;
 mod command_service;
+mod custom;
+mod hook;
+mod layer;
+mod limit;
+mod session;
 mod topic;
 mod topic_service;
+mod transfer;
 pub use self::{
+    acl::Acl,
+    auth::{hash_password, User, Users},
+    custom::{CustomHandler, CustomStore, CustomTopic, Customs},
+    hook::Hook,
+    layer::{from_box_error, BoxCommandService, SessionService},
+    limit::{CommandClass, Limiter, Pending},
+    session::{validate_namespace, Session},
     topic::{Broadcaster, Topic},
     topic_service::{StreamingResponse, TopicService},
+    transfer::{Uploads, CHUNK_SIZE, MAX_VALUE_SIZE},
 };
+use hook::{AfterSend, BeforeSend, Executed, Hooks, Received};
 
 /// 对 Command 的处理进行抽象
 pub trait CommandService {
@@ -33,43 +54,196 @@ impl<Store> Clone for Service<Store> {
     }
 }
 
-pub type ReceivedFunc = fn(&CommandRequest) -> Result<(), KvError>;
-pub type ResponseFunc = fn(&CommandResponse) -> Result<(), KvError>;
-pub type BeforeSendFunc = fn(&mut CommandResponse) -> Result<(), KvError>;
-pub type AfterSendFunc = fn() -> Result<(), KvError>;
-
 /// ServiceInner：Service 内部数据结构，这也是 Rust 的一个惯例，把需要在多线程下 clone 的主体和其内部结构分开，这样代码逻辑更加清晰
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<ReceivedFunc>,
-    on_executed: Vec<ResponseFunc>,
-    on_before_send: Vec<BeforeSendFunc>,
-    on_after_send: Vec<AfterSendFunc>,
+    uploads: Uploads,
+    limiter: Limiter,
+    acl: Acl,
+    users: Users,
+    customs: Customs<Store>,
+    hooks: Hooks,
+}
+
+/// run 的结果，普通的请求返回一个响应，订阅、下载等请求返回响应流
+enum Output {
+    Unary(CommandResponse),
+    Streaming(StreamingResponse),
 }
 
 impl<Store: Storage> Service<Store> {
-    #[instrument(name = "service_execute", skip_all)]
+    /// 在一个没有认证过的 session 中执行请求
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_in(&Session::default(), cmd)
+    }
+
+    /// 在 session 中执行请求，开启认证后，session 认证之前只能执行 Auth
+    #[instrument(name = "service_execute", skip_all)]
+    pub fn execute_in(&self, session: &Session, cmd: CommandRequest) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
-        if let Err(e) = self.inner.on_received.notify(&cmd) {
-            return Box::pin(stream::once(async { Arc::new(e) }));
+        if let Some(RequestData::Auth(ref auth)) = cmd.request_data {
+            let (service, session, auth) = (self.clone(), session.clone(), auth.clone());
+            let res = async move {
+                if let Err(res) = service.inner.hooks.received(&session, &cmd).await {
+                    return Arc::new(res);
+                }
+                // 校验密码需要大量计算，放到 blocking 线程中执行，避免阻塞其它连接
+                let res = task::spawn_blocking(move || service.authenticate(&session, &auth));
+                let res: CommandResponse = match res.await {
+                    Ok(Ok(())) => Value::from("OK").into(),
+                    Ok(Err(e)) => e.into(),
+                    Err(e) => KvError::Internal(e.to_string()).into(),
+                };
+                Arc::new(res)
+            };
+            return Box::pin(stream::once(res));
+        }
+        if let Err(e) = self.inner.users.check(session, &cmd) {
+            let res = e.into();
+            return Box::pin(stream::once(async { Arc::new(res) }));
         }
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+        let timeout = cmd.timeout();
+        let name = cmd.name();
+        let (service, session) = (self.clone(), session.clone());
+        let res = stream::once(async move { service.process(session, cmd).await }).flatten();
+        match timeout {
+            Some(timeout) => with_deadline(Box::pin(res), name, timeout),
+            None => Box::pin(res),
+        }
+    }
 
-        if res == CommandResponse::default() {
-            dispatch_stream(cmd, Arc::clone(&self.brocaster))
-        } else {
-            debug!("Executed response: {:?}", res);
-            if let Err(e) = self.inner.on_executed.notify(&res) {
-                return Box::pin(stream::once(async { Arc::new(e) }));
+    /// 依次调用各个 Hook 并执行请求
+    async fn process(self, session: Session, cmd: CommandRequest) -> StreamingResponse {
+        let hooks = &self.inner.hooks;
+        if let Err(res) = hooks.received(&session, &cmd).await {
+            return single(res);
+        }
+        let is_track = cmd.name() == "track";
+        let cmd = match (session.prefix(), cmd.table()) {
+            (Some(prefix), _) if !is_track => cmd.with_table_prefix(prefix),
+            // 没有 namespace 的 session 不能通过 "a:t1" 这样的名字访问 namespace 中的 table
+            (None, Some(table)) if table.contains(':') => {
+                let e = KvError::PermissionDenied(format!("table {table} is in a namespace"));
+                return single(e.into());
             }
-            if let Err(e) = self.inner.on_before_send.notify(&mut res) {
-                return Box::pin(stream::once(async { Arc::new(e) }));
+            _ => cmd,
+        };
+        let cmd = cmd.with_topic_prefix(session.topic_prefix());
+        // 自定义命令持有全局的锁并执行任意的同步代码，总是放到 blocking 线程中执行
+        let output = match cmd.timeout().is_some() || cmd.name() == "custom" {
+            false => self.run(&session, cmd),
+            true => {
+                // 存储的调用是同步的，放到 blocking 线程中执行，这样超时后就不必等待它返回。
+                // 超时只是不再等待，已经开始的调用会在 blocking 线程中继续执行完，其中的写入依然生效
+                let (service, session) = (self.clone(), session.clone());
+                match task::spawn_blocking(move || service.run(&session, cmd)).await {
+                    Ok(output) => output,
+                    Err(e) => return single(KvError::Internal(e.to_string()).into()),
+                }
             }
-            if !self.inner.on_after_send.is_empty() {
-                debug!("Modified response: {:?}", res);
+        };
+        match output {
+            Output::Streaming(res) => match session.prefix() {
+                Some(prefix) if is_track => strip_prefix(res, prefix),
+                _ => res,
+            },
+            Output::Unary(mut res) => {
+                debug!("Executed response: {:?}", res);
+                if let Err(res) = hooks.executed(&session, &res).await {
+                    return single(res);
+                }
+                if let Err(res) = hooks.before_send(&session, &mut res).await {
+                    return single(res);
+                }
+                single(res)
             }
-            Box::pin(stream::once(async { Arc::new(res) }))
+        }
+    }
+
+    /// 响应写入 stream 之后调用，通知各个 Hook
+    pub async fn after_send(&self, session: &Session, res: &CommandResponse) {
+        self.inner.hooks.after_send(session, res).await
+    }
+
+    /// 执行请求之前检查 client 是否被限流，以及服务端是否过载
+    pub fn admit(&self, client: &str, cmd: &CommandRequest) -> Result<Pending, KvError> {
+        self.inner.limiter.admit(client, cmd)
+    }
+
+    /// 检查客户端是否有权限执行请求，identity 为 None 表示客户端没有提供证书
+    pub fn authorize(
+        &self,
+        identity: Option<&PeerIdentity>,
+        cmd: &CommandRequest,
+    ) -> Result<(), KvError> {
+        self.inner.acl.check(identity, cmd)
+    }
+
+    /// 校验 Auth 中的用户名和密码，成功后 session 以该用户的身份执行之后的请求
+    pub fn authenticate(&self, session: &Session, auth: &Auth) -> Result<(), KvError> {
+        self.inner
+            .users
+            .authenticate(session, &auth.username, &auth.password)
+    }
+
+    /// 服务器关闭时调用，通知所有的订阅者并结束订阅
+    pub fn shutdown(&self) {
+        self.brocaster.shutdown();
+    }
+
+    /// 把存储中尚未持久化的数据写入磁盘
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.store.flush()
+    }
+
+    /// 执行命令，返回的响应还没有经过 Hook 的处理
+    fn run(&self, session: &Session, cmd: CommandRequest) -> Output {
+        let store = &self.inner.store;
+        let res = match cmd.request_data {
+            Some(RequestData::Upload(ref chunk)) => {
+                self.inner.uploads.execute(session, chunk.clone(), store)
+            }
+            Some(RequestData::Download(ref v)) => {
+                return Output::Streaming(v.clone().execute(store))
+            }
+            Some(RequestData::Custom(ref v)) => {
+                let acl = &self.inner.acl;
+                let check = |table: &str| acl.check_table(session.identity(), &cmd, table);
+                let view = CustomStore::new(store, session.prefix(), &check);
+                let check = |topic: &str| acl.check_topic(session.identity(), &cmd, topic);
+                let topic = CustomTopic::new(&self.brocaster, session.topic_prefix(), &check);
+                let res = self.inner.customs.execute(v.clone(), &view, &topic);
+                // 处理函数出错之前的写入依然生效，同样需要通知
+                for (table, keys) in view.take_written() {
+                    let keys: Vec<_> = keys.iter().map(|k| k.as_str()).collect();
+                    Arc::clone(&self.brocaster).invalidate(&table, &keys);
+                }
+                return Output::Unary(res);
+            }
+            _ => dispatch(cmd.clone(), store),
+        };
+
+        if res == CommandResponse::default() {
+            Output::Streaming(dispatch_stream(cmd, Arc::clone(&self.brocaster)))
+        } else {
+            self.track(&cmd);
+            Output::Unary(res)
+        }
+    }
+}
+
+impl<Store> Service<Store> {
+    /// 记录带有 tracking 的读请求读取的 key，写请求执行后通知读取过这些 key 的客户端
+    fn track(&self, cmd: &CommandRequest) {
+        let (table, keys) = match cmd.keys() {
+            Some(v) => v,
+            None => return,
+        };
+        let topic = Arc::clone(&self.brocaster);
+        match CommandClass::of(cmd) {
+            CommandClass::Read if cmd.tracking != 0 => topic.watch_keys(cmd.tracking, table, &keys),
+            CommandClass::Write => topic.invalidate(table, &keys),
+            _ => {}
         }
     }
 }
@@ -78,74 +252,88 @@ impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
-            on_received: Vec::new(),
-            on_executed: Vec::new(),
-            on_before_send: Vec::new(),
-            on_after_send: Vec::new(),
+            uploads: Uploads::default(),
+            limiter: Limiter::new(LimitConfig::default()),
+            acl: Acl::default(),
+            users: Users::default(),
+            customs: Customs::default(),
+            hooks: Hooks::default(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest) -> Result<(), KvError>) -> Self {
-        self.on_received.push(f);
+    /// 设置限流和过载保护
+    pub fn with_limit(mut self, config: LimitConfig) -> Self {
+        self.limiter = Limiter::new(config);
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse) -> Result<(), KvError>) -> Self {
-        self.on_executed.push(f);
+    /// 设置访问控制
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse) -> Result<(), KvError>) -> Self {
-        self.on_before_send.push(f);
+    /// 设置用户数据库，有用户时需要先认证才能执行其它请求
+    pub fn with_users(mut self, users: Users) -> Self {
+        self.users = users;
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn() -> Result<(), KvError>) -> Self {
-        self.on_after_send.push(f);
+    /// 注册一个名为 name 的自定义命令，客户端通过 CommandRequest::new_custom 调用。
+    /// handler 通过 CustomStore 访问 session 的 namespace 中 ACL 允许的 table，
+    /// 写入的 key 会触发客户端缓存的失效通知；通过 CustomTopic 向 namespace 中 ACL 允许的 topic 发布消息
+    pub fn with_command<F>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Vec<Value>, &CustomStore<Store>, &CustomTopic) -> Result<CommandResponse, KvError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.customs.insert(name, Box::new(handler));
         self
     }
-}
 
-impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
-    fn from(inner: ServiceInner<Store>) -> Self {
-        Self {
-            inner: Arc::new(inner),
-            brocaster: Default::default(),
-        }
+    /// 注册一个 Hook，各个 Hook 按照注册的顺序调用
+    pub fn with_hook(mut self, hook: impl Hook) -> Self {
+        self.hooks.push(hook);
+        self
     }
-}
 
-/// 不可变事件通知
-pub trait Notify<Arg> {
-    fn notify(&self, arg: &Arg) -> Result<(), CommandResponse>;
-}
+    pub fn fn_received(
+        self,
+        f: impl Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.with_hook(Received(f))
+    }
 
-/// 可变事件通知
-pub trait NotifyMut<Arg> {
-    fn notify(&self, arg: &mut Arg) -> Result<(), CommandResponse>;
-}
+    pub fn fn_executed(
+        self,
+        f: impl Fn(&CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.with_hook(Executed(f))
+    }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg) -> Result<(), KvError>> {
-    #[inline]
-    fn notify(&self, arg: &Arg) -> Result<(), CommandResponse> {
-        for f in self {
-            if let Err(e) = f(arg) {
-                return Err(e.into());
-            }
-        }
-        Ok(())
+    pub fn fn_before_send(
+        self,
+        f: impl Fn(&mut CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.with_hook(BeforeSend(f))
+    }
+
+    pub fn fn_after_send(
+        self,
+        f: impl Fn() -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.with_hook(AfterSend(f))
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg) -> Result<(), KvError>> {
-    #[inline]
-    fn notify(&self, arg: &mut Arg) -> Result<(), CommandResponse> {
-        for f in self {
-            if let Err(e) = f(arg) {
-                return Err(e.into());
-            }
+impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
+    fn from(inner: ServiceInner<Store>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            brocaster: Default::default(),
         }
-        Ok(())
     }
 }
 
@@ -160,6 +348,7 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(v)) => v.execute(store),
         Some(RequestData::Hexist(v)) => v.execute(store),
         Some(RequestData::Hmexist(v)) => v.execute(store),
+        Some(RequestData::Ping(v)) => v.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         // _ => Value::default().into(), Value 的默认值通过 into 转换得来的并不等同于 CommandResponse::default() 值
         _ => CommandResponse::default(),
@@ -174,14 +363,83 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
         Some(RequestData::Psubscribe(param)) => param.execute(topic),
         Some(RequestData::Unsubscribe(param)) => param.execute(topic),
         Some(RequestData::Punsubscribe(param)) => param.execute(topic),
-        _ => unreachable!(),
+        Some(RequestData::Track(param)) => param.execute(topic),
+        _ => {
+            let res = KvError::UnsupportedCommand(cmd.name().into()).into();
+            Box::pin(stream::once(async { Arc::new(res) }))
+        }
     }
 }
 
+/// 只包含一个响应的响应流
+fn single(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(res) }))
+}
+
+/// 去掉失效通知中 table 的 namespace 前缀，客户端看到的 table 和它请求时使用的一致
+fn strip_prefix(res: StreamingResponse, prefix: &str) -> StreamingResponse {
+    let prefix = prefix.to_owned();
+    Box::pin(res.map(move |data| {
+        let table = match data.values.first().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::String(table)) => table.strip_prefix(prefix.as_str()),
+            _ => None,
+        };
+        match table {
+            Some(table) => {
+                let mut res = (*data).clone();
+                res.values[0] = table.into();
+                Arc::new(res)
+            }
+            None => data,
+        }
+    }))
+}
+
+/// 超过 timeout 后结束响应流，并在最后返回一个超时的响应
+fn with_deadline(res: StreamingResponse, name: &str, timeout: Duration) -> StreamingResponse {
+    let deadline = Instant::now() + timeout;
+    let name = name.to_owned();
+    Box::pin(stream::unfold(Some(res), move |state| {
+        let name = name.clone();
+        async move {
+            let mut res = state?;
+            match time::timeout_at(deadline, res.next()).await {
+                Ok(Some(data)) => Some((data, Some(res))),
+                Ok(None) => None,
+                Err(_) => {
+                    debug!("Request {} timed out", name);
+                    let res = KvError::Timeout(name).into();
+                    Some((Arc::new(res), None))
+                }
+            }
+        }
+    }))
+}
+
+// 如果要在 command_service.rs 中调用 assert_res_ok，则 pub 是必要的
+#[cfg(test)]
+pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    let mut sorted_pairs = res.kvpairs.clone();
+    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(sorted_pairs, pairs);
+}
+
+#[cfg(test)]
+pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.kvpairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{MemTable, Value};
+    use futures::future::BoxFuture;
     use http::StatusCode;
     use tokio_stream::StreamExt;
     use tracing::info;
@@ -205,6 +463,205 @@ mod tests {
         assert_res_ok(&data, &["v1".into()], &[]);
     }
 
+    #[tokio::test]
+    async fn request_with_timeout_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let timeout = Duration::from_millis(100);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_timeout(timeout);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1").with_timeout(timeout);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn subscription_should_end_at_deadline() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmd = CommandRequest::new_subscribe("lobby").with_timeout(Duration::from_millis(50));
+        let mut res = service.execute(cmd);
+
+        let data = res.next().await.unwrap();
+        assert_eq!(data.status, StatusCode::OK.as_u16() as u32);
+        let data = res.next().await.unwrap();
+        assert_res_error(
+            &data,
+            StatusCode::REQUEST_TIMEOUT.as_u16() as _,
+            "timed out",
+        );
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_be_isolated() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let a = Session::default().with_namespace("a");
+        let b = Session::default().with_namespace("b");
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = service.execute_in(&a, cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let data = service.execute_in(&a, cmd.clone()).next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+        let data = service.execute_in(&b, cmd.clone()).next().await.unwrap();
+        assert_eq!(data.status, StatusCode::NOT_FOUND.as_u16() as u32);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_eq!(data.status, StatusCode::NOT_FOUND.as_u16() as u32);
+
+        // 没有 namespace 的 session 不能直接访问 namespace 中的 table
+        let cmd = CommandRequest::new_hget("a:t1", "k1");
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 403, "namespace");
+    }
+
+    #[tokio::test]
+    async fn pubsub_should_be_isolated_by_namespace() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let a = Session::default().with_namespace("a");
+
+        let mut sub = service.execute_in(&a, CommandRequest::new_subscribe("lobby"));
+        let mut psub = service.execute(CommandRequest::new_psubscribe("*"));
+        sub.next().await.unwrap();
+        psub.next().await.unwrap();
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        service.execute_in(&a, cmd).next().await.unwrap();
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["hello".into()], &[]);
+        // 没有 namespace 的订阅看不到 namespace 中的消息
+        let res = time::timeout(Duration::from_millis(50), psub.next()).await;
+        assert!(res.is_err());
+
+        let cmd = CommandRequest::new_publish("a:lobby", vec!["world".into()]);
+        service.execute(cmd).next().await.unwrap();
+        let data = psub.next().await.unwrap();
+        assert_res_ok(&data, &["world".into()], &[]);
+        let res = time::timeout(Duration::from_millis(50), sub.next()).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_command_should_be_registered() {
+        fn incr(
+            args: Vec<Value>,
+            store: &CustomStore<MemTable>,
+            _topic: &CustomTopic,
+        ) -> Result<CommandResponse, KvError> {
+            let key = match args.first() {
+                Some(key) => String::try_from(key)?,
+                None => return Err(KvError::InvalidCommand("incr <key>".into())),
+            };
+            let count = match store.get("counter", &key)? {
+                Some(v) => i64::try_from(v)? + 1,
+                None => 1,
+            };
+            store.set("counter", key, count.into())?;
+            Ok(Value::from(count).into())
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_command("incr", incr)
+            .into();
+        for i in 1..=2i64 {
+            let cmd = CommandRequest::new_custom("incr", vec!["k1".into()]);
+            let data = service.execute(cmd).next().await.unwrap();
+            assert_res_ok(&data, &[i.into()], &[]);
+        }
+        let cmd = CommandRequest::new_custom("decr", vec!["k1".into()]);
+        let data = service.execute(cmd).next().await.unwrap();
+        assert_res_error(
+            &data,
+            StatusCode::NOT_IMPLEMENTED.as_u16() as _,
+            "custom command decr",
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_command_should_respect_namespace_and_invalidate() {
+        fn reset(
+            args: Vec<Value>,
+            store: &CustomStore<MemTable>,
+            topic: &CustomTopic,
+        ) -> Result<CommandResponse, KvError> {
+            for key in &args {
+                store.set("t1", String::try_from(key)?, 0.into())?;
+            }
+            topic.publish("resets", args)?;
+            Ok(Value::from("OK").into())
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_command("reset", reset)
+            .into();
+        let session = Session::default().with_namespace("a");
+        let mut track = service.execute_in(&session, CommandRequest::new_track());
+        let id: i64 = track.next().await.unwrap().as_ref().try_into().unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1").with_tracking(id as _);
+        service.execute_in(&session, cmd).next().await.unwrap();
+        let mut sub = service.execute_in(&session, CommandRequest::new_subscribe("resets"));
+        let mut other = service.execute(CommandRequest::new_subscribe("resets"));
+        sub.next().await.unwrap();
+        other.next().await.unwrap();
+
+        let cmd = CommandRequest::new_custom("reset", vec!["k1".into()]);
+        let res = service.execute_in(&session, cmd).next().await.unwrap();
+        assert_res_ok(&res, &["OK".into()], &[]);
+        // 失效通知中的 table 不带 namespace 前缀
+        let res = track.next().await.unwrap();
+        assert_res_ok(&res, &["t1".into(), "k1".into()], &[]);
+        // 发布的消息同样只在 namespace 中可见
+        let res = sub.next().await.unwrap();
+        assert_res_ok(&res, &["k1".into()], &[]);
+        let res = time::timeout(Duration::from_millis(50), other.next()).await;
+        assert!(res.is_err());
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service
+            .execute_in(&session, cmd.clone())
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &[0.into()], &[]);
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+    }
+
+    #[tokio::test]
+    async fn custom_command_should_not_block_async_workers() {
+        /// 等待其它请求写入 go 之后才返回
+        fn wait(
+            _args: Vec<Value>,
+            store: &CustomStore<MemTable>,
+            _topic: &CustomTopic,
+        ) -> Result<CommandResponse, KvError> {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while !store.contains("flags", "go")? {
+                if std::time::Instant::now() > deadline {
+                    return Err(KvError::Internal("flag is never set".into()));
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok(Value::from("OK").into())
+        }
+
+        // 单线程的 runtime 中，处理函数如果在 async 的 worker 上执行，hset 就永远没有机会执行
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_command("wait", wait)
+            .into();
+        let cmd = CommandRequest::new_custom("wait", vec![]);
+        let mut res = service.execute(cmd);
+        let waiting = tokio::spawn(async move { res.next().await.unwrap() });
+        // 让自定义命令先开始执行
+        task::yield_now().await;
+        let cmd = CommandRequest::new_hset("flags", "go", true.into());
+        service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&waiting.await.unwrap(), &["OK".into()], &[]);
+    }
+
     #[tokio::test]
     async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) -> Result<(), KvError> {
@@ -242,6 +699,48 @@ mod tests {
         assert_eq!(data.values, vec![Value::default()]);
     }
 
+    #[tokio::test]
+    async fn hook_should_short_circuit_with_custom_response() {
+        /// 只允许 admin 客户端读取 secret 表，其它客户端读到的都是空值
+        struct Mask;
+
+        impl Hook for Mask {
+            fn on_received<'a>(
+                &'a self,
+                session: &'a Session,
+                cmd: &'a CommandRequest,
+            ) -> BoxFuture<'a, Result<(), CommandResponse>> {
+                Box::pin(async move {
+                    tokio::task::yield_now().await;
+                    match (session.client(), cmd.table()) {
+                        ("admin", _) => Ok(()),
+                        (_, Some("secret")) => Err(Value::default().into()),
+                        _ => Ok(()),
+                    }
+                })
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_hook(Mask)
+            .into();
+        let admin = Session::default().with_client("admin");
+        let guest = Session::default().with_client("guest");
+
+        let cmd = CommandRequest::new_hset("secret", "k1", "v1".into());
+        service.execute_in(&admin, cmd).next().await.unwrap();
+
+        let cmd = CommandRequest::new_hget("secret", "k1");
+        let data = service
+            .execute_in(&admin, cmd.clone())
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
+        let data = service.execute_in(&guest, cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+    }
+
     #[tokio::test]
     async fn test_pipeline_should_return_early_when_something_went_wrong() {
         fn b(cmd: &CommandRequest) -> Result<(), KvError> {
@@ -319,22 +818,3 @@ mod tests {
         );
     }
 }
-
-// 如果要在 command_service.rs 中调用 assert_res_ok，则 pub 是必要的
-#[cfg(test)]
-pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    let mut sorted_pairs = res.kvpairs.clone();
-    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(sorted_pairs, pairs);
-}
-
-#[cfg(test)]
-pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.kvpairs, &[]);
-}
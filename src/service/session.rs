@@ -0,0 +1,103 @@
+use super::transfer::SessionUploads;
+use crate::{KvError, PeerIdentity, User};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// 一个 stream 的上下文：对端的信息、认证状态和 namespace，clone 之后共享同一个认证状态
+#[derive(Clone, Default)]
+pub struct Session {
+    user: Arc<Mutex<Option<Arc<User>>>>,
+    /// namespace 对应的 table 前缀，None 表示不隔离
+    prefix: Option<Arc<str>>,
+    peer: Arc<Peer>,
+    /// session 中尚未完成的分块上传，session 结束时释放
+    uploads: Arc<SessionUploads>,
+}
+
+/// 没有 namespace 的 session 中 topic 的前缀。namespace 中不能有 ':'，因此不会和任何 namespace 的 topic 冲突
+const DEFAULT_TOPIC_PREFIX: &str = ":";
+
+/// 建立 stream 时确定的对端信息
+#[derive(Clone, Default)]
+struct Peer {
+    client: String,
+    identity: Option<Arc<PeerIdentity>>,
+    addr: Option<SocketAddr>,
+}
+
+/// 检查 namespace 的名字，namespace 和 table 之间用 ':' 分隔，因此名字中不能有 ':'
+pub fn validate_namespace(namespace: &str) -> Result<(), KvError> {
+    if namespace.is_empty() || namespace.contains(':') {
+        return Err(KvError::Internal(format!(
+            "Invalid namespace {namespace:?}: must be non-empty and cannot contain ':'"
+        )));
+    }
+    Ok(())
+}
+
+impl Session {
+    /// 设置 session 的 namespace，session 中的请求只能访问 namespace 下的 table 和 topic。
+    /// namespace 不能为空，也不能包含 ':'，加载配置时由 validate_namespace 检查
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.prefix = Some(format!("{namespace}:").into());
+        self
+    }
+
+    /// 设置客户端的标识，比如证书的 CN 或者 IP 地址
+    pub fn with_client(mut self, client: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.peer).client = client.into();
+        self
+    }
+
+    /// 设置客户端证书中的身份
+    pub fn with_identity(mut self, identity: Option<Arc<PeerIdentity>>) -> Self {
+        Arc::make_mut(&mut self.peer).identity = identity;
+        self
+    }
+
+    /// 设置客户端的地址
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        Arc::make_mut(&mut self.peer).addr = Some(addr);
+        self
+    }
+
+    /// 当前认证的用户，尚未认证时返回 None
+    pub fn user(&self) -> Option<Arc<User>> {
+        self.user.lock().unwrap().clone()
+    }
+
+    /// namespace 对应的 table 前缀
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// topic 的前缀，不同 namespace 之间的 pub/sub 互相隔离，没有 namespace 的 session 之间共享
+    pub fn topic_prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(DEFAULT_TOPIC_PREFIX)
+    }
+
+    /// 客户端的标识，没有设置时为空字符串
+    pub fn client(&self) -> &str {
+        &self.peer.client
+    }
+
+    /// 客户端证书中的身份，客户端没有提供证书时为 None
+    pub fn identity(&self) -> Option<&PeerIdentity> {
+        self.peer.identity.as_deref()
+    }
+
+    /// 客户端的地址，不是通过网络连接的 session 为 None
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.peer.addr
+    }
+
+    pub(super) fn uploads(&self) -> &Arc<SessionUploads> {
+        &self.uploads
+    }
+
+    pub(super) fn set_user(&self, user: Arc<User>) {
+        *self.user.lock().unwrap() = Some(user);
+    }
+}
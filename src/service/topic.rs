@@ -1,9 +1,12 @@
 use crate::{CommandResponse, KvError, Value};
 use dashmap::{DashMap, DashSet};
 use glob::Pattern;
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
 };
 
 use tokio::sync::mpsc;
@@ -12,6 +15,9 @@ use tracing::{debug, info, instrument, warn};
 /// topic 里最大存放数据
 const BROCASTER_CAPACITY: usize = 128;
 
+/// 被跟踪的 key 超过该数量时，清理已经失效的 Track 订阅留下的记录
+const MAX_TRACKED_KEYS: usize = 100_000;
+
 /// 下一个 subscription id
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
@@ -34,6 +40,15 @@ pub trait Topic: Send + Sync + 'static {
 
     /// 退订某个模式
     fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError>;
+
+    /// 开启 key 的失效通知，返回的订阅 id 用于在读请求中标记需要跟踪的 key
+    fn track(self) -> mpsc::Receiver<Arc<CommandResponse>>;
+
+    /// 记录 Track 订阅读取过的 key
+    fn watch_keys(self, id: u32, table: &str, keys: &[&str]);
+
+    /// key 被修改时通知读取过它的 Track 订阅，每次读取只会收到一次通知
+    fn invalidate(self, table: &str, keys: &[&str]);
 }
 
 /// 用于主题发布和订阅的数据结构
@@ -45,9 +60,40 @@ pub struct Broadcaster {
     subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
     /// 所有的模式订阅列表
     patterns: DashMap<Pattern, DashSet<u32>>,
+    /// 被 Track 订阅读取过的 (table, key)，以及读取过它的订阅
+    tracking: DashMap<(String, String), DashSet<u32>>,
+    /// 服务器正在关闭，不再接受新的订阅
+    closed: AtomicBool,
 }
 
 impl Broadcaster {
+    /// 服务器关闭时通知所有的订阅者，并结束所有的订阅
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::Release);
+        let res = Arc::new(KvError::ShuttingDown.into());
+        for entry in self.subscriptions.iter() {
+            if let Err(e) = entry.value().try_send(Arc::clone(&res)) {
+                warn!("Failed to notify subscription {}: {:?}", entry.key(), e);
+            }
+        }
+        // drop 掉所有的 sender，订阅者的 stream 会随之结束
+        self.subscriptions.clear();
+        self.topics.clear();
+        self.patterns.clear();
+        self.tracking.clear();
+        info!("All subscriptions are closed");
+    }
+
+    /// 保存订阅的 sender，服务器已经关闭时直接丢弃，订阅者收到 id 之后 stream 就会结束
+    fn add_subscription(&self, id: u32, tx: mpsc::Sender<Arc<CommandResponse>>) {
+        if self.closed.load(Ordering::Acquire) {
+            warn!("Server is shutting down, subscription {} is dropped", id);
+            return;
+        }
+        self.subscriptions.insert(id, tx);
+        debug!("Subscription {} is added", id);
+    }
+
     fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
         if let Some(v) = self.topics.get_mut(&name) {
             v.remove(&id);
@@ -86,6 +132,14 @@ impl Broadcaster {
         self.subscriptions.remove(&id).map(|(id, _)| id)
     }
 
+    /// 清理已经结束的 Track 订阅读取过的 key
+    fn purge_tracking(&self) {
+        self.tracking.retain(|_, ids| {
+            ids.retain(|id| self.subscriptions.contains_key(id));
+            !ids.is_empty()
+        });
+    }
+
     fn purge_patterns(&self) {
         for element in self.patterns.iter() {
             for id in element.value().iter() {
@@ -122,8 +176,7 @@ impl Topic for Arc<Broadcaster> {
         });
 
         // 把 tx 存储到 subscription table 中
-        self.subscriptions.insert(id, tx);
-        debug!("Subscription {} is added", id);
+        self.add_subscription(id, tx);
 
         rx
     }
@@ -153,8 +206,7 @@ impl Topic for Arc<Broadcaster> {
         });
 
         // 把 tx 存储到 subscription table 中
-        self.subscriptions.insert(id, tx);
-        debug!("Subscription {} is added", id);
+        self.add_subscription(id, tx);
 
         rx
     }
@@ -180,6 +232,62 @@ impl Topic for Arc<Broadcaster> {
         res
     }
 
+    #[instrument(name = "topic_track", skip_all)]
+    fn track(self) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let id = get_next_subscription_id();
+        let (tx, rx) = mpsc::channel(BROCASTER_CAPACITY);
+        let v: Value = (id as i64).into();
+        // channel 是新建的，一定有空间放下订阅 id
+        let _ = tx.try_send(Arc::new(v.into()));
+        self.add_subscription(id, tx);
+        rx
+    }
+
+    fn watch_keys(self, id: u32, table: &str, keys: &[&str]) {
+        if !self.subscriptions.contains_key(&id) {
+            debug!("Tracking {} does not exist", id);
+            return;
+        }
+        if self.tracking.len() > MAX_TRACKED_KEYS {
+            self.purge_tracking();
+        }
+        for key in keys {
+            self.tracking
+                .entry((table.to_owned(), (*key).to_owned()))
+                .or_default()
+                .insert(id);
+        }
+    }
+
+    #[instrument(name = "topic_invalidate", skip_all)]
+    fn invalidate(self, table: &str, keys: &[&str]) {
+        // 按照订阅汇总被修改的 key，每个订阅只发送一次通知
+        let mut notifications: HashMap<u32, Vec<Value>> = HashMap::new();
+        for key in keys {
+            let entry = (table.to_owned(), (*key).to_owned());
+            if let Some((_, ids)) = self.tracking.remove(&entry) {
+                for id in ids {
+                    notifications
+                        .entry(id)
+                        .or_insert_with(|| vec![table.into()])
+                        .push((*key).into());
+                }
+            }
+        }
+
+        for (id, values) in notifications {
+            let tx = match self.subscriptions.get(&id) {
+                Some(tx) => tx.clone(),
+                None => continue,
+            };
+            // 通知丢失后客户端的缓存就不再可信，因此直接结束这个订阅，客户端会清空缓存
+            if let Err(e) = tx.try_send(Arc::new(values.into())) {
+                warn!("Failed to invalidate keys for {}: {:?}", id, e);
+                self.subscriptions.remove(&id);
+            }
+        }
+    }
+
     #[instrument(name = "topic_publish", skip_all)]
     fn publish(self, name: String, value: Arc<CommandResponse>) {
         // 使用 tokio 来包装，避免阻塞
@@ -245,6 +353,23 @@ mod tests {
     use crate::assert_res_ok;
     use std::convert::TryInto;
 
+    #[tokio::test]
+    async fn shutdown_should_close_subscriptions() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into());
+        let _id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        b.shutdown();
+        let res = stream.recv().await.unwrap();
+        assert_eq!(res.status, 503);
+        assert!(stream.recv().await.is_none());
+
+        // 关闭之后的订阅在收到 id 后就会结束
+        let mut stream = b.clone().subscribe("lobby".into());
+        assert!(stream.recv().await.is_some());
+        assert!(stream.recv().await.is_none());
+    }
+
     #[tokio::test]
     async fn pub_sub_should_work() {
         let b = Arc::new(Broadcaster::default());
@@ -263,7 +388,7 @@ mod tests {
         let res2 = stream2.recv().await.unwrap();
 
         assert_eq!(res1, res2);
-        assert_res_ok(&res1, &[v.clone()], &[]);
+        assert_res_ok(&res1, std::slice::from_ref(&v), &[]);
 
         let result = b.clone().unsubscribe(topic.clone(), id1 as _).unwrap();
         assert_eq!(result, id1 as u32);
@@ -274,7 +399,7 @@ mod tests {
         let result = stream1.recv().await;
         assert!(result.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_res_ok(&res2, &[v.clone()], &[]);
+        assert_res_ok(&res2, std::slice::from_ref(&v), &[]);
     }
 
     #[tokio::test]
@@ -311,12 +436,12 @@ mod tests {
         let result2 = stream2.recv().await.unwrap();
         let result3 = stream3.recv().await.unwrap();
         let result4 = stream4.recv().await.unwrap();
-        assert_res_ok(&result1, &[v1.clone()], &[]);
-        assert_res_ok(&result2, &[v2.clone()], &[]);
-        assert_res_ok(&result3, &[v3.clone()], &[]);
-        assert_res_ok(&result4, &[v1.clone()], &[]);
+        assert_res_ok(&result1, std::slice::from_ref(&v1), &[]);
+        assert_res_ok(&result2, std::slice::from_ref(&v2), &[]);
+        assert_res_ok(&result3, std::slice::from_ref(&v3), &[]);
+        assert_res_ok(&result4, std::slice::from_ref(&v1), &[]);
         let result4 = stream4.recv().await.unwrap();
-        assert_res_ok(&result4, &[v2.clone()], &[]);
+        assert_res_ok(&result4, std::slice::from_ref(&v2), &[]);
 
         b.clone()
             .punsubscribe("chat.*".to_string(), id4 as _)
@@ -327,6 +452,25 @@ mod tests {
         let result = stream4.recv().await;
         assert!(result.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_res_ok(&res2, &[v2.clone()], &[]);
+        assert_res_ok(&res2, std::slice::from_ref(&v2), &[]);
+    }
+
+    #[tokio::test]
+    async fn tracked_keys_should_be_invalidated_once() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().track();
+        let id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        b.clone().watch_keys(id as _, "t1", &["k1", "k2"]);
+        b.clone().invalidate("t1", &["k1", "k3"]);
+        let res = stream.recv().await.unwrap();
+        assert_res_ok(&res, &["t1".into(), "k1".into()], &[]);
+
+        // 没有再次读取的 key 不会重复通知
+        b.clone().invalidate("t1", &["k1", "k2"]);
+        let res = stream.recv().await.unwrap();
+        assert_res_ok(&res, &["t1".into(), "k2".into()], &[]);
+        b.clone().invalidate("t1", &["k1", "k2"]);
+        assert!(stream.try_recv().is_err());
     }
 }
@@ -1,8 +1,11 @@
-use futures::{stream, Stream};
+use futures::{stream, Stream, StreamExt};
 use std::{pin::Pin, sync::Arc};
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::{CommandResponse, PSubscribe, PUnsubscribe, Publish, Subscribe, Topic, Unsubscribe};
+use crate::{
+    CommandResponse, KvError, PSubscribe, PUnsubscribe, Publish, Subscribe, Topic, Track,
+    Unsubscribe,
+};
 
 /// 使用 tokio-stream 的 stream wrapper 来把一个 mpsc::Receiver 转换
 /// 成 Receiver Stream，这样就可以不断调用 next 来获得下一个值
@@ -51,6 +54,18 @@ impl TopicService for PUnsubscribe {
     }
 }
 
+impl TopicService for Track {
+    /// 订阅被服务端结束时（比如通知发送失败）最后返回一个错误，客户端收到后需要清空缓存
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let rx = topic.track();
+        let end = stream::once(async {
+            let res = KvError::Internal("Tracking is interrupted".into()).into();
+            Arc::new(res)
+        });
+        Box::pin(ReceiverStream::new(rx).chain(end))
+    }
+}
+
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
         topic.publish(self.topic, Arc::new(self.value.into()));
@@ -97,7 +112,7 @@ mod tests {
 
         // publish 时会将断掉的连接删除，记者再 unsubscription 就会失效，所以会被删除
         let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
-        dispatch_stream(cmd, topic.clone());
+        let _ = dispatch_stream(cmd, topic.clone());
         time::sleep(Duration::from_millis(10)).await;
 
         // 如果再次尝试删除，应该返回 KvError
@@ -139,4 +154,13 @@ mod tests {
         let data = res.next().await.unwrap();
         assert_res_error(&data, 404, "Not found: subscription 6543");
     }
+
+    #[tokio::test]
+    async fn dispatch_non_streaming_command_should_error() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let mut res = dispatch_stream(cmd, topic);
+        let data = res.next().await.unwrap();
+        assert_res_error(&data, 501, "Unsupported command: hget");
+    }
 }
@@ -0,0 +1,449 @@
+use crate::{value, CommandResponse, Download, KvError, Session, Storage, Upload, Value};
+use bytes::{Bytes, BytesMut};
+use futures::stream;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Handle, time};
+use tracing::{debug, info};
+
+use super::StreamingResponse;
+
+/// 每个分块的大小，需要给 frame 中的其它字段留出空间
+pub const CHUNK_SIZE: usize = 512 * 1024;
+
+/// 分块上传的 value 的最大长度
+pub const MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+/// 所有 session 中同时进行的上传的最大数量
+pub const MAX_UPLOADS: usize = 64;
+
+/// 所有进行中的上传缓存的数据总量的上限
+pub const MAX_UPLOAD_BYTES: usize = 256 * 1024 * 1024;
+
+/// 超过这个时间没有收到新分块的上传会被丢弃
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 正在进行中的上传
+struct PartialUpload {
+    table: String,
+    key: String,
+    buf: BytesMut,
+    updated_at: Instant,
+    /// 这个上传占用的配额，上传完成或者被丢弃时归还
+    quota: Quota,
+}
+
+/// 处理分块上传，收到最后一个分块后把完整的 value 写入存储。
+/// 上传属于发起它的 session，其它 session 无法继续或者完成它，session 结束时尚未完成的上传随之释放
+pub struct Uploads {
+    usage: Arc<Usage>,
+    /// 有过上传的 session，定时清理其中被放弃的上传
+    sessions: Arc<Mutex<Vec<Weak<SessionUploads>>>>,
+    sweeping: AtomicBool,
+}
+
+/// 一个 session 中尚未完成的上传
+#[derive(Default)]
+pub struct SessionUploads {
+    next_id: AtomicU32,
+    uploads: Mutex<HashMap<u32, PartialUpload>>,
+    registered: AtomicBool,
+}
+
+/// 所有 session 共享的上传数量和缓存数据量
+struct Usage {
+    uploads: AtomicUsize,
+    bytes: AtomicUsize,
+    max_uploads: usize,
+    max_bytes: usize,
+}
+
+/// 一个上传占用的配额，drop 时归还
+struct Quota {
+    usage: Arc<Usage>,
+    bytes: usize,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self::new(MAX_UPLOADS, MAX_UPLOAD_BYTES)
+    }
+}
+
+impl Uploads {
+    /// 限制同时进行的上传数量以及它们缓存的数据总量
+    pub fn new(max_uploads: usize, max_bytes: usize) -> Self {
+        Self {
+            usage: Arc::new(Usage {
+                uploads: AtomicUsize::new(0),
+                bytes: AtomicUsize::new(0),
+                max_uploads,
+                max_bytes,
+            }),
+            sessions: Default::default(),
+            sweeping: AtomicBool::new(false),
+        }
+    }
+
+    /// 在 session 中处理一个分块，返回的响应中带有上传 id
+    pub fn execute(
+        &self,
+        session: &Session,
+        chunk: Upload,
+        store: &impl Storage,
+    ) -> CommandResponse {
+        match self.append(session.uploads(), chunk, store) {
+            Ok(id) => Value::from(id as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 进行中的上传的数量
+    pub fn len(&self) -> usize {
+        self.usage.uploads.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn append(
+        &self,
+        session: &Arc<SessionUploads>,
+        chunk: Upload,
+        store: &impl Storage,
+    ) -> Result<u32, KvError> {
+        let mut uploads = session.uploads.lock().unwrap();
+        purge(&mut uploads);
+        let id = match chunk.upload_id {
+            0 => {
+                let id = self.start(session, &mut uploads, &chunk)?;
+                self.register(session);
+                id
+            }
+            id => id,
+        };
+
+        let upload = uploads
+            .get_mut(&id)
+            .ok_or_else(|| KvError::NotFound(format!("upload {id}")))?;
+        if upload.table != chunk.table || upload.key != chunk.key {
+            return Err(KvError::InvalidCommand(format!(
+                "upload {} belongs to {}:{}",
+                id, upload.table, upload.key
+            )));
+        }
+        if upload.buf.len() as u64 != chunk.offset {
+            return Err(KvError::InvalidCommand(format!(
+                "upload {} expects offset {}, got {}",
+                id,
+                upload.buf.len(),
+                chunk.offset
+            )));
+        }
+        if upload.buf.len() + chunk.data.len() > MAX_VALUE_SIZE {
+            uploads.remove(&id);
+            return Err(KvError::InvalidCommand(format!(
+                "value is larger than {MAX_VALUE_SIZE} bytes"
+            )));
+        }
+        if let Err(e) = upload.quota.grow(chunk.data.len()) {
+            uploads.remove(&id);
+            return Err(e);
+        }
+
+        upload.buf.extend_from_slice(&chunk.data);
+        upload.updated_at = Instant::now();
+
+        if chunk.finish {
+            let upload = uploads.remove(&id).unwrap();
+            drop(uploads);
+            info!(
+                "Upload {} finished: {}:{} ({} bytes)",
+                id,
+                upload.table,
+                upload.key,
+                upload.buf.len()
+            );
+            store.set(&upload.table, upload.key, upload.buf.freeze().into())?;
+        }
+        Ok(id)
+    }
+
+    /// 开始一个新的上传，id 只在 session 内有效
+    fn start(
+        &self,
+        session: &SessionUploads,
+        uploads: &mut HashMap<u32, PartialUpload>,
+        chunk: &Upload,
+    ) -> Result<u32, KvError> {
+        if chunk.offset != 0 {
+            return Err(KvError::InvalidCommand(
+                "first chunk must start at offset 0".into(),
+            ));
+        }
+        let quota = Quota::new(&self.usage)?;
+
+        // 0 表示新的上传，因此跳过
+        let id = loop {
+            let id = session.next_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                break id;
+            }
+        };
+        uploads.insert(
+            id,
+            PartialUpload {
+                table: chunk.table.clone(),
+                key: chunk.key.clone(),
+                buf: BytesMut::new(),
+                updated_at: Instant::now(),
+                quota,
+            },
+        );
+        Ok(id)
+    }
+
+    /// 记录有上传的 session，在第一次有上传时启动定时清理被放弃的上传的任务
+    fn register(&self, session: &Arc<SessionUploads>) {
+        if !session.registered.swap(true, Ordering::Relaxed) {
+            self.sessions.lock().unwrap().push(Arc::downgrade(session));
+        }
+        if self.sweeping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(sweep(Arc::downgrade(&self.sessions)));
+            }
+            // 不在 tokio runtime 中时，只在处理分块时清理
+            Err(_) => self.sweeping.store(false, Ordering::Relaxed),
+        }
+    }
+}
+
+/// 定时清理所有 session 中被放弃的上传，Uploads 被 drop 后退出
+async fn sweep(sessions: Weak<Mutex<Vec<Weak<SessionUploads>>>>) {
+    let mut interval = time::interval(UPLOAD_IDLE_TIMEOUT / 2);
+    loop {
+        interval.tick().await;
+        let sessions = match sessions.upgrade() {
+            Some(sessions) => sessions,
+            None => return,
+        };
+        // 已经结束的 session 中的上传在 session drop 时就已经释放了
+        sessions
+            .lock()
+            .unwrap()
+            .retain(|session| match session.upgrade() {
+                Some(session) => {
+                    purge(&mut session.uploads.lock().unwrap());
+                    true
+                }
+                None => false,
+            });
+    }
+}
+
+/// 丢弃长时间没有收到新分块的上传
+fn purge(uploads: &mut HashMap<u32, PartialUpload>) {
+    uploads.retain(|id, upload| {
+        let alive = upload.updated_at.elapsed() < UPLOAD_IDLE_TIMEOUT;
+        if !alive {
+            debug!("Upload {} is abandoned", id);
+        }
+        alive
+    });
+}
+
+impl Quota {
+    /// 占用一个上传的名额，上传数量达到上限时返回 Overloaded
+    fn new(usage: &Arc<Usage>) -> Result<Self, KvError> {
+        usage
+            .uploads
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < usage.max_uploads).then_some(n + 1)
+            })
+            .map_err(|_| KvError::Overloaded)?;
+        Ok(Self {
+            usage: usage.clone(),
+            bytes: 0,
+        })
+    }
+
+    /// 为新的分块占用缓存的配额，超过上限时返回 Overloaded
+    fn grow(&mut self, len: usize) -> Result<(), KvError> {
+        let usage = &self.usage;
+        usage
+            .bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n + len <= usage.max_bytes).then_some(n + len)
+            })
+            .map_err(|_| KvError::Overloaded)?;
+        self.bytes += len;
+        Ok(())
+    }
+}
+
+impl Drop for Quota {
+    fn drop(&mut self) {
+        self.usage.uploads.fetch_sub(1, Ordering::AcqRel);
+        self.usage.bytes.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl Download {
+    /// 第一个响应返回 value 的总长度，之后每个响应返回一个分块
+    pub fn execute(self, store: &impl Storage) -> StreamingResponse {
+        let data = match store.get(&self.table, &self.key) {
+            Ok(Some(Value {
+                value: Some(value::Value::Binary(data)),
+            })) => data,
+            Ok(Some(v)) => {
+                let res = KvError::ConvertError(v.format(), "Binary").into();
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+            Ok(None) => {
+                let res = KvError::NotFound(format!("{}:{}", self.table, self.key)).into();
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+            Err(e) => return Box::pin(stream::once(async { Arc::new(e.into()) })),
+        };
+
+        let size: CommandResponse = Value::from(data.len() as i64).into();
+        let chunks = (0..data.len())
+            .step_by(CHUNK_SIZE)
+            .map(move |start| {
+                let end = (start + CHUNK_SIZE).min(data.len());
+                let chunk: Bytes = data.slice(start..end);
+                Arc::new(Value::from(chunk).into())
+            })
+            .collect::<Vec<_>>();
+        Box::pin(stream::iter(std::iter::once(Arc::new(size)).chain(chunks)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, MemTable};
+    use futures::StreamExt;
+
+    fn chunk(id: i64, offset: u64, data: &'static [u8], finish: bool) -> Upload {
+        Upload {
+            upload_id: id as _,
+            table: "t1".into(),
+            key: "k1".into(),
+            offset,
+            data: Bytes::from_static(data),
+            finish,
+        }
+    }
+
+    #[test]
+    fn upload_should_work() {
+        let store = MemTable::new();
+        let uploads = Uploads::default();
+        let session = Session::default();
+
+        let res = uploads.execute(&session, chunk(0, 0, b"hello ", false), &store);
+        let id: i64 = (&res).try_into().unwrap();
+        assert!(store.get("t1", "k1").unwrap().is_none());
+
+        // offset 不连续的分块会被拒绝
+        let res = uploads.execute(&session, chunk(id, 0, b"world", true), &store);
+        assert_res_error(&res, 400, "offset");
+
+        let res = uploads.execute(&session, chunk(id, 6, b"world", true), &store);
+        assert_res_ok(&res, &[id.into()], &[]);
+        let v = store.get("t1", "k1").unwrap().unwrap();
+        assert_eq!(v, Bytes::from_static(b"hello world").into());
+
+        // 完成的上传不能再继续使用
+        let res = uploads.execute(&session, chunk(id, 11, b"!", true), &store);
+        assert_res_error(&res, 404, "upload");
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn upload_should_belong_to_its_session() {
+        let store = MemTable::new();
+        let uploads = Uploads::default();
+        let (alice, bob) = (Session::default(), Session::default());
+
+        let res = uploads.execute(&alice, chunk(0, 0, b"hello ", false), &store);
+        let id: i64 = (&res).try_into().unwrap();
+
+        // 其它 session 无法继续或者完成这个上传
+        let res = uploads.execute(&bob, chunk(id, 6, b"world", true), &store);
+        assert_res_error(&res, 404, "upload");
+        assert!(store.get("t1", "k1").unwrap().is_none());
+
+        // session 结束时尚未完成的上传随之释放
+        assert_eq!(uploads.len(), 1);
+        drop(alice);
+        assert!(uploads.is_empty());
+    }
+
+    #[test]
+    fn uploads_should_be_limited() {
+        let store = MemTable::new();
+        let uploads = Uploads::new(2, 8);
+        let session = Session::default();
+
+        let res = uploads.execute(&session, chunk(0, 0, b"hello", false), &store);
+        let id: i64 = (&res).try_into().unwrap();
+        uploads.execute(&session, chunk(0, 0, b"", false), &store);
+        let res = uploads.execute(&session, chunk(0, 0, b"", false), &store);
+        assert_res_error(&res, 503, "overloaded");
+
+        // 超过缓存上限的上传会被丢弃，并归还占用的配额
+        let res = uploads.execute(&session, chunk(id, 5, b"world", false), &store);
+        assert_res_error(&res, 503, "overloaded");
+        assert_eq!(uploads.len(), 1);
+        let res = uploads.execute(&session, chunk(0, 0, b"world", true), &store);
+        assert_res_ok(&res, &[3.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn download_should_return_chunks() {
+        let store = MemTable::new();
+        let data = Bytes::from(vec![7u8; CHUNK_SIZE * 2 + 10]);
+        store.set("t1", "k1".into(), data.clone().into()).unwrap();
+
+        let cmd = Download {
+            table: "t1".into(),
+            key: "k1".into(),
+        };
+        let res: Vec<_> = cmd.execute(&store).collect().await;
+        assert_eq!(res.len(), 4);
+        assert_res_ok(&res[0], &[(data.len() as i64).into()], &[]);
+
+        let mut buf = BytesMut::new();
+        for res in &res[1..] {
+            match &res.values[0].value {
+                Some(value::Value::Binary(chunk)) => buf.extend_from_slice(chunk),
+                _ => panic!("expect binary chunk"),
+            }
+        }
+        assert_eq!(buf.freeze(), data);
+    }
+
+    #[tokio::test]
+    async fn download_missing_key_should_fail() {
+        let store = MemTable::new();
+        let cmd = Download {
+            table: "t1".into(),
+            key: "k1".into(),
+        };
+        let res: Vec<_> = cmd.execute(&store).collect().await;
+        assert_eq!(res.len(), 1);
+        assert_res_error(&res[0], 404, "Not found");
+    }
+}
@@ -24,6 +24,11 @@ pub trait Storage: Send + Sync + 'static {
 
     /// 遍历 HashTable，返回 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+
+    /// 把尚未持久化的数据写入磁盘，内存中的存储无需实现
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
 }
 
 /// 提供 Storage Iterator 来对 iter 进行抽象，这样 trait 的实现者
@@ -93,6 +98,18 @@ mod tests {
         test_get_iter_should_work(store);
     }
 
+    #[test]
+    fn sleddb_flush_should_persist_data() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
     fn test_basic_interfaces_should_work(store: impl Storage) {
         // 首次插入会返回 None
         let v = store.set("t1", "k1".into(), "v1".into()).unwrap();
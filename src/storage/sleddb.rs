@@ -68,6 +68,11 @@ impl Storage for SledDb {
         let iter = StorageIter::new(self.0.scan_prefix(prefix));
         Ok(Box::new(iter))
     }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.0.flush()?;
+        Ok(())
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
@@ -1,10 +1,12 @@
 use anyhow::Result;
+use futures::StreamExt;
 use simple_kv::{
-    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, ServerConfig,
+    start_client_with_config, start_plain_client_with_config, start_server_with_config,
+    start_server_with_shutdown, ClientConfig, CommandRequest, ServerConfig, ServerMode,
     StorageConfig,
 };
 use std::time::Duration;
-use tokio::time;
+use tokio::{sync::oneshot, time};
 
 #[tokio::test]
 async fn yamux_server_client_full_tests() -> Result<()> {
@@ -22,7 +24,7 @@ async fn yamux_server_client_full_tests() -> Result<()> {
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = addr.into();
 
-    let mut ctrl = start_client_with_config(&config).await.unwrap();
+    let ctrl = start_client_with_config(&config).await.unwrap();
     let mut stream = ctrl.open_stream().await?;
 
     // 生成一个 HSET 命令
@@ -38,3 +40,80 @@ async fn yamux_server_client_full_tests() -> Result<()> {
     assert_eq!(res.values, &["v1".into()]);
     Ok(())
 }
+
+#[tokio::test]
+async fn server_should_shutdown_gracefully() -> Result<()> {
+    let addr = "127.0.0.1:10087";
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.grace_period = 1;
+    config.storage = StorageConfig::MemTable;
+
+    // 启动 server，收到 oneshot 消息后关闭
+    let (shutdown, signal) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        start_server_with_shutdown(&config, async {
+            let _ = signal.await;
+        })
+        .await
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+
+    let ctrl = start_client_with_config(&config).await?;
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    let stream = ctrl.open_stream().await?;
+    let mut sub = stream
+        .execute_streaming(&CommandRequest::new_subscribe("lobby"))
+        .await?;
+
+    shutdown.send(()).unwrap();
+
+    // 订阅者会收到服务器关闭的通知
+    let res = time::timeout(Duration::from_secs(1), sub.next()).await?;
+    assert_eq!(res.unwrap()?.status, 503);
+
+    // 所有的 stream 结束后，server 正常退出
+    time::timeout(Duration::from_secs(2), server).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn auto_mode_should_serve_plain_and_yamux_clients() -> Result<()> {
+    let addr = "127.0.0.1:10088";
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    config.mode = ServerMode::Auto;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+
+    // 不使用 yamux 的客户端
+    let mut client = start_plain_client_with_config(&config).await?;
+    let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+    let res = client.execute(&cmd).await?;
+    assert_eq!(res.status, 200);
+
+    // yamux 客户端读到同样的数据
+    let ctrl = start_client_with_config(&config).await?;
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute(&CommandRequest::new_hget("t1", "k1"))
+        .await?;
+    assert_eq!(res.status, 200);
+    assert_eq!(res.values, &["v1".into()]);
+    Ok(())
+}